@@ -0,0 +1,715 @@
+// Audio header parsing for uploaded files.
+//
+// Only the container headers are read: the first HEAD_BYTES of the file (plus
+// the frame after an oversized ID3 tag, and the last page of an Ogg stream for
+// its duration), never the audio itself, so large chunked uploads cost the same
+// as small ones. A file declared as WAV, FLAC, MP3 or Ogg whose bytes turn out
+// to be something else is rejected; other content types are inspected on a
+// best-effort basis and never rejected.
+use crate::ApiError;
+use candid::{CandidType, Deserialize};
+
+pub const HEAD_BYTES: u64 = 64 * 1024;
+const OGG_TAIL_BYTES: u64 = 64 * 1024;
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Mp3,
+    Ogg,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AudioMetadata {
+    pub format: Option<AudioFormat>,
+    pub duration_ms: Option<u64>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u16>, // None for lossy formats
+    pub channels: Option<u16>,
+    pub bitrate_kbps: Option<u32>,
+    /// From ID3v2 or Vorbis comments, for prefilling the track's title and credits.
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// Reads bytes `start..end` of the file being inspected, clamped to its size.
+pub type Reader<'a> = &'a dyn Fn(u64, u64) -> Vec<u8>;
+
+fn declared_format(content_type: &str) -> Option<AudioFormat> {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    match mime.as_str() {
+        "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some(AudioFormat::Wav),
+        "audio/flac" | "audio/x-flac" => Some(AudioFormat::Flac),
+        "audio/mpeg" | "audio/mp3" => Some(AudioFormat::Mp3),
+        "audio/ogg" | "audio/opus" | "audio/vorbis" | "application/ogg" => Some(AudioFormat::Ogg),
+        _ => None,
+    }
+}
+
+/// Parses the file's headers. Errors only when the declared type is a known
+/// audio format the bytes don't match; returns `None` for unrecognised content.
+pub fn inspect(content_type: &str, size: u64, read: Reader) -> Result<Option<AudioMetadata>, ApiError> {
+    let head = read(0, size.min(HEAD_BYTES));
+    let parsed = if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WAVE") {
+        parse_wav(&head)
+    } else if head.starts_with(b"OggS") {
+        parse_ogg(&head, size, read)
+    } else {
+        // FLAC and MP3 may both start with an ID3v2 tag
+        let (tag, audio_start) = parse_id3(&head);
+        let body = if audio_start == 0 { head.clone() } else { read(audio_start.min(size), audio_start.saturating_add(HEAD_BYTES).min(size)) };
+        // Without a tag or a declared MP3 type, a frame sync must open the file;
+        // otherwise any binary file would pass for MP3 somewhere in its first bytes
+        let search = audio_start > 0 || declared_format(content_type) == Some(AudioFormat::Mp3);
+        let parsed = if body.starts_with(b"fLaC") {
+            parse_flac(&body)
+        } else {
+            parse_mp3(&body, size - audio_start.min(size), search)
+        };
+        parsed.map(|mut meta| {
+            meta.title = meta.title.or(tag.title);
+            meta.artist = meta.artist.or(tag.artist);
+            meta
+        })
+    };
+    if let Some(expected) = declared_format(content_type) {
+        if parsed.as_ref().and_then(|m| m.format.as_ref()) != Some(&expected) {
+            return Err(ApiError::validation(
+                "content_type",
+                &format!("file content is not valid {:?} audio", expected),
+            ));
+        }
+    }
+    Ok(parsed.map(|mut meta| {
+        if meta.bitrate_kbps.is_none() {
+            meta.bitrate_kbps = meta.duration_ms.filter(|d| *d > 0).map(|d| (size * 8 / d) as u32);
+        }
+        meta
+    }))
+}
+
+// Offsets come from untrusted headers, so every read is bounds-checked without
+// computing `at + n` first.
+fn bytes<const N: usize>(b: &[u8], at: usize) -> Option<[u8; N]> {
+    b.get(at..)?.get(..N)?.try_into().ok()
+}
+
+fn u16_le(b: &[u8], at: usize) -> Option<u16> {
+    bytes(b, at).map(u16::from_le_bytes)
+}
+
+fn u32_le(b: &[u8], at: usize) -> Option<u32> {
+    bytes(b, at).map(u32::from_le_bytes)
+}
+
+fn u32_be(b: &[u8], at: usize) -> Option<u32> {
+    bytes(b, at).map(u32::from_be_bytes)
+}
+
+fn syncsafe(b: &[u8], at: usize) -> Option<u32> {
+    let b: [u8; 4] = bytes(b, at)?;
+    Some(b.iter().fold(0u32, |acc, byte| (acc << 7) | (*byte as u32 & 0x7f)))
+}
+
+// --- WAV / RIFF ---
+
+/// The RIFF chunks in `head` as (id, offset of the chunk body, declared length).
+fn wav_chunks(head: &[u8]) -> impl Iterator<Item = ([u8; 4], usize, u32)> + '_ {
+    let mut at = 12;
+    std::iter::from_fn(move || {
+        let (id, len) = (bytes::<4>(head, at)?, u32_le(head, at.saturating_add(4))?);
+        let body = at + 8;
+        // Chunks are padded to an even length
+        at = at.saturating_add(len as usize).saturating_add(8 + (len as usize & 1));
+        Some((id, body, len))
+    })
+}
+
+fn parse_wav(head: &[u8]) -> Option<AudioMetadata> {
+    let mut meta = AudioMetadata { format: Some(AudioFormat::Wav), ..Default::default() };
+    let mut byte_rate = 0u32;
+    for (id, body, len) in wav_chunks(head) {
+        match &id {
+            b"fmt " => {
+                meta.channels = u16_le(head, body + 2);
+                meta.sample_rate = u32_le(head, body + 4);
+                byte_rate = u32_le(head, body + 8).unwrap_or(0);
+                meta.bit_depth = u16_le(head, body + 14);
+                meta.bitrate_kbps = Some((byte_rate as u64 * 8 / 1000) as u32);
+            }
+            b"data" => {
+                if byte_rate > 0 {
+                    meta.duration_ms = Some(len as u64 * 1000 / byte_rate as u64);
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+    Some(meta)
+}
+
+/// Where a WAV file's samples are and how they're encoded.
+#[derive(Clone, Copy, Debug)]
+pub struct PcmLayout {
+    pub data_start: u64,
+    /// As declared; may run past the end of a truncated file.
+    pub data_len: u64,
+    pub channels: u16,
+    pub bits: u16,
+    pub float: bool,
+}
+
+impl PcmLayout {
+    pub fn frame_bytes(&self) -> u64 {
+        self.channels as u64 * (self.bits / 8) as u64
+    }
+
+    /// Each sample in `frames` scaled to the i16 range, channels interleaved.
+    pub fn samples<'a>(&self, frames: &'a [u8]) -> impl Iterator<Item = i16> + 'a {
+        let (bits, float) = (self.bits, self.float);
+        frames.chunks_exact((bits / 8) as usize).map(move |s| match (bits, float) {
+            (8, _) => ((s[0] as i16) - 128) << 8,
+            (16, _) => i16::from_le_bytes([s[0], s[1]]),
+            (24, _) => i16::from_le_bytes([s[1], s[2]]),
+            (_, false) => i16::from_le_bytes([s[2], s[3]]),
+            (_, true) => (f32::from_le_bytes([s[0], s[1], s[2], s[3]]).clamp(-1.0, 1.0) * i16::MAX as f32) as i16,
+        })
+    }
+}
+
+/// The sample layout of an integer or float PCM WAV file; `None` for anything
+/// else, including compressed WAV encodings.
+pub fn pcm_layout(head: &[u8]) -> Option<PcmLayout> {
+    if !head.starts_with(b"RIFF") || head.get(8..12) != Some(b"WAVE") {
+        return None;
+    }
+    let mut fmt = None;
+    for (id, body, len) in wav_chunks(head) {
+        match &id {
+            b"fmt " => {
+                let mut tag = u16_le(head, body)?;
+                // WAVE_FORMAT_EXTENSIBLE keeps the real tag at the start of its sub-format GUID
+                if tag == 0xfffe {
+                    tag = u16_le(head, body + 24)?;
+                }
+                fmt = Some((tag, u16_le(head, body + 2)?, u16_le(head, body + 14)?));
+            }
+            b"data" => {
+                let (tag, channels, bits) = fmt?;
+                let float = match (tag, bits) {
+                    (1, 8 | 16 | 24 | 32) => false,
+                    (3, 32) => true,
+                    _ => return None,
+                };
+                if channels == 0 {
+                    return None;
+                }
+                return Some(PcmLayout { data_start: body as u64, data_len: len as u64, channels, bits, float });
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// --- FLAC ---
+
+fn parse_flac(body: &[u8]) -> Option<AudioMetadata> {
+    let mut meta = AudioMetadata { format: Some(AudioFormat::Flac), ..Default::default() };
+    let mut at = 4;
+    loop {
+        let header = *body.get(at)?;
+        let len = u32_be(body, at)? as usize & 0x00ff_ffff;
+        let block = body.get(at + 4..(at + 4).saturating_add(len));
+        match (header & 0x7f, block) {
+            (0, Some(info)) if info.len() >= 18 => {
+                // 20 bits sample rate, 3 bits channels - 1, 5 bits bits per sample - 1, 36 bits total samples
+                let packed = u64::from_be_bytes(info[10..18].try_into().ok()?);
+                let sample_rate = (packed >> 44) as u32;
+                let total_samples = packed & 0xf_ffff_ffff;
+                meta.sample_rate = Some(sample_rate);
+                meta.channels = Some(((packed >> 41) & 0x7) as u16 + 1);
+                meta.bit_depth = Some(((packed >> 36) & 0x1f) as u16 + 1);
+                if sample_rate > 0 && total_samples > 0 {
+                    meta.duration_ms = Some(total_samples * 1000 / sample_rate as u64);
+                }
+            }
+            (4, Some(comments)) => read_vorbis_comments(comments, &mut meta),
+            _ => {}
+        }
+        if header & 0x80 != 0 || block.is_none() {
+            break;
+        }
+        at += 4 + len;
+    }
+    meta.sample_rate.is_some().then_some(meta)
+}
+
+/// Vorbis comment block (FLAC, Ogg Vorbis and Opus): vendor string, then `KEY=value` entries.
+fn read_vorbis_comments(block: &[u8], meta: &mut AudioMetadata) {
+    let Some(vendor_len) = u32_le(block, 0) else { return };
+    let mut at = (vendor_len as usize).saturating_add(4);
+    let Some(count) = u32_le(block, at) else { return };
+    at = at.saturating_add(4);
+    for _ in 0..count {
+        let Some(len) = u32_le(block, at) else { return };
+        let Some(entry) = block.get(at.saturating_add(4)..).and_then(|b| b.get(..len as usize)) else { return };
+        at = at.saturating_add(len as usize).saturating_add(4);
+        let entry = String::from_utf8_lossy(entry);
+        let Some((key, value)) = entry.split_once('=') else { continue };
+        match key.to_ascii_uppercase().as_str() {
+            "TITLE" if meta.title.is_none() => meta.title = Some(value.to_string()),
+            "ARTIST" if meta.artist.is_none() => meta.artist = Some(value.to_string()),
+            _ => {}
+        }
+    }
+}
+
+// --- ID3v2 and MP3 ---
+
+#[derive(Default)]
+struct Id3Tag {
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+/// Parses a leading ID3v2 tag. Returns its text frames and where the audio starts.
+fn parse_id3(frames: &[u8]) -> (Id3Tag, u64) {
+    let mut tag = Id3Tag::default();
+    if !frames.starts_with(b"ID3") || frames.len() < 10 {
+        return (tag, 0);
+    }
+    let major = frames[3];
+    let footer = if frames[5] & 0x10 != 0 { 10 } else { 0 };
+    let tag_len = syncsafe(frames, 6).unwrap_or(0) as u64;
+    let audio_start = 10 + tag_len + footer;
+    // Only frames within the head are read; title and artist come before any cover art in practice
+    let frames = &frames[..frames.len().min(10 + tag_len as usize)];
+    let (id_len, header_len) = if major == 2 { (3, 6) } else { (4, 10) };
+    let mut at = 10;
+    while frames.len().saturating_sub(at) >= header_len {
+        let id = &frames[at..at + id_len];
+        if id[0] == 0 {
+            break; // padding
+        }
+        let len = match major {
+            2 => u32::from_be_bytes([0, frames[at + 3], frames[at + 4], frames[at + 5]]),
+            4 => syncsafe(frames, at + 4).unwrap_or(0),
+            _ => u32_be(frames, at + 4).unwrap_or(0),
+        } as usize;
+        let Some(body) = frames.get(at + header_len..(at + header_len).saturating_add(len)) else { break };
+        match id {
+            b"TIT2" | b"TT2" => tag.title = id3_text(body),
+            b"TPE1" | b"TP1" => tag.artist = id3_text(body),
+            _ => {}
+        }
+        at = at.saturating_add(len).saturating_add(header_len);
+    }
+    (tag, audio_start)
+}
+
+fn id3_text(body: &[u8]) -> Option<String> {
+    let (&encoding, text) = body.split_first()?;
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let big_endian = encoding == 2 || text.starts_with(&[0xfe, 0xff]);
+            let text = text.strip_prefix(&[0xfe, 0xff]).or_else(|| text.strip_prefix(&[0xff, 0xfe])).unwrap_or(text);
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| if big_endian { u16::from_be_bytes([c[0], c[1]]) } else { u16::from_le_bytes([c[0], c[1]]) })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    let text = text.trim_end_matches('\0').trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+const MP3_BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MP3_BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// Reads the first MPEG audio Layer III frame header, and a Xing/Info header
+/// if present for the exact duration of VBR files.
+fn parse_mp3(body: &[u8], audio_len: u64, search: bool) -> Option<AudioMetadata> {
+    let at = body.windows(2).position(|w| w[0] == 0xff && w[1] & 0xe0 == 0xe0)?;
+    if at > 0 && !search {
+        return None;
+    }
+    let header = u32_be(body, at)?;
+    let version = (header >> 19) & 0x3; // 0 = MPEG 2.5, 2 = MPEG 2, 3 = MPEG 1
+    let layer = (header >> 17) & 0x3; // 1 = Layer III
+    let bitrate_index = ((header >> 12) & 0xf) as usize;
+    let rate_index = ((header >> 10) & 0x3) as usize;
+    if version == 1 || layer != 1 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+        return None;
+    }
+    let mpeg1 = version == 3;
+    let sample_rate = [44100, 48000, 32000][rate_index] >> match version {
+        3 => 0,
+        2 => 1,
+        _ => 2,
+    };
+    let bitrate = if mpeg1 { MP3_BITRATES_V1 } else { MP3_BITRATES_V2 }[bitrate_index];
+    let mono = (header >> 6) & 0x3 == 3;
+    let mut meta = AudioMetadata {
+        format: Some(AudioFormat::Mp3),
+        sample_rate: Some(sample_rate),
+        channels: Some(if mono { 1 } else { 2 }),
+        bitrate_kbps: Some(bitrate),
+        ..Default::default()
+    };
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = at + 4 + side_info;
+    let samples_per_frame: u64 = if mpeg1 { 1152 } else { 576 };
+    if matches!(body.get(xing..xing + 4), Some(b"Xing") | Some(b"Info")) && u32_be(body, xing + 4)? & 1 != 0 {
+        let frames = u32_be(body, xing + 8)? as u64;
+        meta.duration_ms = Some(frames * samples_per_frame * 1000 / sample_rate as u64);
+        meta.bitrate_kbps = None; // VBR; the average is filled in from the file size
+    } else {
+        meta.duration_ms = Some(audio_len.saturating_sub(at as u64) * 8 / bitrate as u64);
+    }
+    Some(meta)
+}
+
+// --- Ogg (Vorbis and Opus) ---
+
+fn parse_ogg(head: &[u8], size: u64, read: Reader) -> Option<AudioMetadata> {
+    let segments = *head.get(26)? as usize;
+    let packet = head.get(27 + segments..)?;
+    let mut meta = AudioMetadata { format: Some(AudioFormat::Ogg), ..Default::default() };
+    // Granule positions count samples at the sample rate for Vorbis, always at 48 kHz for Opus
+    let (granule_rate, pre_skip) = if packet.starts_with(b"\x01vorbis") {
+        meta.channels = packet.get(11).map(|c| *c as u16);
+        meta.sample_rate = u32_le(packet, 12);
+        meta.bitrate_kbps = u32_le(packet, 20).filter(|b| *b > 0 && *b < i32::MAX as u32).map(|b| b / 1000);
+        if let Some(at) = find(head, b"\x03vorbis") {
+            read_vorbis_comments(&head[at + 7..], &mut meta);
+        }
+        (meta.sample_rate?, 0)
+    } else if packet.starts_with(b"OpusHead") {
+        meta.channels = packet.get(9).map(|c| *c as u16);
+        meta.sample_rate = u32_le(packet, 12).filter(|r| *r > 0).or(Some(48000));
+        if let Some(at) = find(head, b"OpusTags") {
+            read_vorbis_comments(&head[at + 8..], &mut meta);
+        }
+        (48000, u16_le(packet, 10)? as u64)
+    } else {
+        // Another codec in an Ogg container; the format is still right
+        return Some(meta);
+    };
+    let tail_start = size.saturating_sub(OGG_TAIL_BYTES);
+    let tail = read(tail_start, size);
+    let last_page = tail.windows(4).rposition(|w| w == b"OggS")?;
+    let granule = u64::from_le_bytes(tail.get(last_page + 6..last_page + 14)?.try_into().ok()?);
+    if granule != u64::MAX && granule_rate > 0 {
+        meta.duration_ms = Some((granule.saturating_sub(pre_skip) as u128 * 1000 / granule_rate as u128) as u64);
+    }
+    Some(meta)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(channels: u16, sample_rate: u32, bits: u16, data_len: u32) -> Vec<u8> {
+        let byte_rate = sample_rate * channels as u32 * bits as u32 / 8;
+        let mut b = b"RIFF".to_vec();
+        b.extend_from_slice(&(36 + data_len).to_le_bytes());
+        b.extend_from_slice(b"WAVEfmt ");
+        b.extend_from_slice(&16u32.to_le_bytes());
+        b.extend_from_slice(&1u16.to_le_bytes());
+        b.extend_from_slice(&channels.to_le_bytes());
+        b.extend_from_slice(&sample_rate.to_le_bytes());
+        b.extend_from_slice(&byte_rate.to_le_bytes());
+        b.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+        b.extend_from_slice(&bits.to_le_bytes());
+        b.extend_from_slice(b"data");
+        b.extend_from_slice(&data_len.to_le_bytes());
+        b
+    }
+
+    #[test]
+    fn wav_header() {
+        let meta = parse_wav(&wav(2, 44100, 16, 44100 * 4 * 3)).unwrap();
+        assert_eq!(meta.format, Some(AudioFormat::Wav));
+        assert_eq!((meta.channels, meta.sample_rate, meta.bit_depth), (Some(2), Some(44100), Some(16)));
+        assert_eq!(meta.duration_ms, Some(3000));
+        assert_eq!(meta.bitrate_kbps, Some(1411));
+    }
+
+    #[test]
+    fn truncated_wav_header() {
+        // Cut inside the fmt chunk: nothing past the cut is made up
+        let meta = parse_wav(&wav(2, 44100, 16, 1000)[..24]).unwrap();
+        assert_eq!(meta.channels, Some(2));
+        assert_eq!((meta.sample_rate, meta.bit_depth, meta.duration_ms), (None, None, None));
+        let meta = parse_wav(&wav(2, 44100, 16, 1000)[..12]).unwrap();
+        assert_eq!((meta.channels, meta.duration_ms), (None, None));
+    }
+
+    #[test]
+    fn malformed_wav_header() {
+        // A zero byte rate gives no duration rather than dividing by zero
+        let mut b = wav(2, 44100, 16, 1000);
+        b[28..32].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(parse_wav(&b).unwrap().duration_ms, None);
+        // A chunk claiming to run past the end ends the walk
+        let mut b = b"RIFF\0\0\0\0WAVEjunk".to_vec();
+        b.extend_from_slice(&u32::MAX.to_le_bytes());
+        b.extend_from_slice(&wav(2, 44100, 16, 1000)[12..]);
+        assert_eq!(parse_wav(&b).unwrap().sample_rate, None);
+    }
+
+    fn flac_block(kind: u8, last: bool, body: &[u8]) -> Vec<u8> {
+        let mut b = vec![kind | if last { 0x80 } else { 0 }];
+        b.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        b.extend_from_slice(body);
+        b
+    }
+
+    fn streaminfo(sample_rate: u64, channels: u64, bits: u64, total_samples: u64) -> Vec<u8> {
+        let packed = sample_rate << 44 | (channels - 1) << 41 | (bits - 1) << 36 | total_samples;
+        let mut b = vec![0; 10];
+        b.extend_from_slice(&packed.to_be_bytes());
+        b.extend_from_slice(&[0; 16]);
+        b
+    }
+
+    fn vorbis_comments(entries: &[&str]) -> Vec<u8> {
+        let mut b = 6u32.to_le_bytes().to_vec();
+        b.extend_from_slice(b"vendor");
+        b.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for entry in entries {
+            b.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            b.extend_from_slice(entry.as_bytes());
+        }
+        b
+    }
+
+    #[test]
+    fn flac_header() {
+        let mut b = b"fLaC".to_vec();
+        b.extend(flac_block(4, false, &vorbis_comments(&["title=Intro", "ARTIST=Someone", "GENRE=Ambient"])));
+        b.extend(flac_block(0, true, &streaminfo(48000, 2, 24, 48000 * 90)));
+        let meta = parse_flac(&b).unwrap();
+        assert_eq!(meta.format, Some(AudioFormat::Flac));
+        assert_eq!((meta.channels, meta.sample_rate, meta.bit_depth), (Some(2), Some(48000), Some(24)));
+        assert_eq!(meta.duration_ms, Some(90_000));
+        assert_eq!((meta.title.as_deref(), meta.artist.as_deref()), (Some("Intro"), Some("Someone")));
+    }
+
+    #[test]
+    fn truncated_flac_header() {
+        let mut b = b"fLaC".to_vec();
+        b.extend(flac_block(0, true, &streaminfo(44100, 2, 16, 44100)));
+        for len in [4, 6, 8, 20] {
+            assert!(parse_flac(&b[..len]).is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn malformed_flac_header() {
+        // A block longer than the file, and a comment count larger than the block
+        let mut b = b"fLaC".to_vec();
+        b.extend_from_slice(&[0x80, 0xff, 0xff, 0xff]);
+        b.extend(streaminfo(44100, 2, 16, 44100));
+        assert!(parse_flac(&b).is_none());
+        let mut comments = vorbis_comments(&["TITLE=Intro"]);
+        comments[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut b = b"fLaC".to_vec();
+        b.extend(flac_block(4, false, &comments));
+        b.extend(flac_block(0, true, &streaminfo(44100, 2, 16, 0)));
+        let meta = parse_flac(&b).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Intro"));
+        // No total sample count means no duration
+        assert_eq!(meta.duration_ms, None);
+    }
+
+    fn syncsafe_bytes(n: u32) -> [u8; 4] {
+        [(n >> 21) as u8 & 0x7f, (n >> 14) as u8 & 0x7f, (n >> 7) as u8 & 0x7f, n as u8 & 0x7f]
+    }
+
+    fn id3(major: u8, frames: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (id, text) in frames {
+            body.extend_from_slice(id);
+            match major {
+                4 => body.extend_from_slice(&syncsafe_bytes(text.len() as u32)),
+                _ => body.extend_from_slice(&(text.len() as u32).to_be_bytes()),
+            }
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(text);
+        }
+        let mut b = vec![b'I', b'D', b'3', major, 0, 0];
+        b.extend_from_slice(&syncsafe_bytes(body.len() as u32));
+        b.extend(body);
+        b
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        let mut b = vec![1, 0xff, 0xfe];
+        b.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        b
+    }
+
+    #[test]
+    fn id3_header() {
+        let b = id3(4, &[(b"TIT2", b"\x03Intro\0".to_vec()), (b"TPE1", utf16("Someone"))]);
+        let (tag, audio_start) = parse_id3(&b);
+        assert_eq!((tag.title.as_deref(), tag.artist.as_deref()), (Some("Intro"), Some("Someone")));
+        assert_eq!(audio_start, b.len() as u64);
+        // ID3v2.3 frame sizes are plain big-endian
+        let b = id3(3, &[(b"TIT2", b"\x00Intro".to_vec())]);
+        assert_eq!(parse_id3(&b).0.title.as_deref(), Some("Intro"));
+    }
+
+    #[test]
+    fn truncated_id3_header() {
+        let (tag, audio_start) = parse_id3(b"ID3\x04\0\0\0");
+        assert_eq!((tag.title, audio_start), (None, 0));
+        // Cut mid-frame: the frame is skipped, and the audio still starts after the declared tag
+        let b = id3(4, &[(b"TIT2", b"\x03Intro".to_vec())]);
+        let (tag, audio_start) = parse_id3(&b[..b.len() - 2]);
+        assert_eq!((tag.title, audio_start), (None, b.len() as u64));
+    }
+
+    #[test]
+    fn malformed_id3_header() {
+        assert_eq!(parse_id3(b"not a tag at all").1, 0);
+        // A frame claiming to be larger than the tag
+        let mut b = id3(3, &[(b"TIT2", b"\x03Intro".to_vec())]);
+        b[14..18].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(parse_id3(&b).0.title, None);
+        // An empty text frame
+        let b = id3(4, &[(b"TIT2", vec![3, 0, 0])]);
+        assert_eq!(parse_id3(&b).0.title, None);
+    }
+
+    // MPEG-1 Layer III, 128 kbps, 44.1 kHz, stereo
+    const MP3_FRAME: [u8; 4] = [0xff, 0xfb, 0x90, 0x00];
+
+    #[test]
+    fn mp3_header() {
+        let mut b = MP3_FRAME.to_vec();
+        b.resize(417, 0);
+        let meta = parse_mp3(&b, 160_000, false).unwrap();
+        assert_eq!(meta.format, Some(AudioFormat::Mp3));
+        assert_eq!((meta.channels, meta.sample_rate, meta.bitrate_kbps), (Some(2), Some(44100), Some(128)));
+        assert_eq!(meta.duration_ms, Some(10_000));
+        // A Xing header gives the frame count of a VBR file
+        b[36..40].copy_from_slice(b"Xing");
+        b[40..44].copy_from_slice(&1u32.to_be_bytes());
+        b[44..48].copy_from_slice(&441u32.to_be_bytes());
+        let meta = parse_mp3(&b, 160_000, false).unwrap();
+        assert_eq!((meta.duration_ms, meta.bitrate_kbps), (Some(11_520), None));
+    }
+
+    #[test]
+    fn truncated_mp3_header() {
+        assert!(parse_mp3(&MP3_FRAME[..3], 3, true).is_none());
+        // A Xing tag cut before its frame count
+        let mut b = MP3_FRAME.to_vec();
+        b.resize(36, 0);
+        b.extend_from_slice(b"Xing\0\0\0\x01");
+        assert!(parse_mp3(&b, 1000, false).is_none());
+    }
+
+    #[test]
+    fn malformed_mp3_header() {
+        // Reserved version, not Layer III, free and bad bitrates, reserved sample rate
+        for header in [[0xff, 0xeb, 0x90, 0x00], [0xff, 0xfd, 0x90, 0x00], [0xff, 0xfb, 0x00, 0x00], [0xff, 0xfb, 0xf0, 0x00], [0xff, 0xfb, 0x9c, 0x00]] {
+            assert!(parse_mp3(&header, 1000, true).is_none(), "{:02x?}", header);
+        }
+        // A frame sync after other bytes only counts when searching for it
+        let mut b = vec![0, 0];
+        b.extend_from_slice(&MP3_FRAME);
+        assert!(parse_mp3(&b, 1000, false).is_none());
+        assert!(parse_mp3(&b, 1000, true).is_some());
+    }
+
+    fn ogg_page(granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut b = b"OggS\0\x02".to_vec();
+        b.extend_from_slice(&granule.to_le_bytes());
+        b.extend_from_slice(&[0; 12]);
+        b.push(1);
+        b.push(packet.len() as u8);
+        b.extend_from_slice(packet);
+        b
+    }
+
+    fn vorbis_id(channels: u8, sample_rate: u32, bitrate: u32) -> Vec<u8> {
+        let mut b = b"\x01vorbis\0\0\0\0".to_vec();
+        b.push(channels);
+        b.extend_from_slice(&sample_rate.to_le_bytes());
+        b.extend_from_slice(&0u32.to_le_bytes());
+        b.extend_from_slice(&bitrate.to_le_bytes());
+        b.extend_from_slice(&[0; 6]);
+        b
+    }
+
+    fn parse_ogg_file(file: &[u8]) -> Option<AudioMetadata> {
+        let read = |start: u64, end: u64| file[start as usize..end.min(file.len() as u64) as usize].to_vec();
+        parse_ogg(&file[..file.len().min(HEAD_BYTES as usize)], file.len() as u64, &read)
+    }
+
+    #[test]
+    fn ogg_vorbis_header() {
+        let mut comments = b"\x03vorbis".to_vec();
+        comments.extend(vorbis_comments(&["TITLE=Intro"]));
+        let mut file = ogg_page(0, &vorbis_id(2, 44100, 160_000));
+        file.extend(ogg_page(0, &comments));
+        file.extend(ogg_page(44100 * 7, &[0; 16]));
+        let meta = parse_ogg_file(&file).unwrap();
+        assert_eq!(meta.format, Some(AudioFormat::Ogg));
+        assert_eq!((meta.channels, meta.sample_rate, meta.bitrate_kbps), (Some(2), Some(44100), Some(160)));
+        assert_eq!(meta.duration_ms, Some(7000));
+        assert_eq!(meta.title.as_deref(), Some("Intro"));
+    }
+
+    #[test]
+    fn ogg_opus_header() {
+        // Opus granules run at 48 kHz whatever the input rate, minus the pre-skip
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&44100u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let mut file = ogg_page(0, &head);
+        file.extend(ogg_page(48000 * 5 + 312, &[0; 16]));
+        let meta = parse_ogg_file(&file).unwrap();
+        assert_eq!((meta.channels, meta.sample_rate, meta.duration_ms), (Some(2), Some(44100), Some(5000)));
+    }
+
+    #[test]
+    fn truncated_ogg_header() {
+        let file = ogg_page(0, &vorbis_id(2, 44100, 0));
+        assert!(parse_ogg_file(&file[..20]).is_none());
+        // Cut before the sample rate
+        assert!(parse_ogg_file(&file[..28 + 12]).is_none());
+    }
+
+    #[test]
+    fn malformed_ogg_header() {
+        // A segment table running past the end
+        let mut file = ogg_page(0, &vorbis_id(2, 44100, 0));
+        file[26] = 255;
+        assert!(parse_ogg_file(&file).is_none());
+        // An unknown codec is still Ogg, and an unset final granule gives no duration
+        let meta = parse_ogg_file(&ogg_page(0, b"\x80theora")).unwrap();
+        assert_eq!((meta.format, meta.sample_rate), (Some(AudioFormat::Ogg), None));
+        let mut file = ogg_page(0, &vorbis_id(2, 44100, 0));
+        file.extend(ogg_page(u64::MAX, &[0; 16]));
+        assert_eq!(parse_ogg_file(&file).unwrap().duration_ms, None);
+    }
+}
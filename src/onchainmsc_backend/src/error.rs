@@ -0,0 +1,36 @@
+// Error type shared by every update endpoint. Candid encodes it as a variant,
+// so clients can tell a missing record from a permission or input problem.
+use crate::TrackRole;
+use candid::{CandidType, Deserialize};
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ApiError {
+    NotFound { resource: String, id: String },
+    Unauthorized(String),
+    InsufficientRole { required: TrackRole, actual: Option<TrackRole> },
+    Validation { field: String, message: String },
+    Conflict(String),
+    RateLimited { retry_after_secs: u64 },
+    /// `until` is in milliseconds; `None` for an indefinite suspension.
+    Suspended { reason: String, until: Option<u64> },
+    /// The ledger call failed or was rejected; no balances were changed.
+    Ledger(String),
+}
+
+impl ApiError {
+    pub fn not_found(resource: &str, id: impl ToString) -> Self {
+        ApiError::NotFound { resource: resource.to_string(), id: id.to_string() }
+    }
+
+    pub fn unauthorized(message: &str) -> Self {
+        ApiError::Unauthorized(message.to_string())
+    }
+
+    pub fn validation(field: &str, message: &str) -> Self {
+        ApiError::Validation { field: field.to_string(), message: message.to_string() }
+    }
+
+    pub fn conflict(message: &str) -> Self {
+        ApiError::Conflict(message.to_string())
+    }
+}
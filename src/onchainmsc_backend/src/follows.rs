@@ -0,0 +1,142 @@
+// Artist and track follows.
+//
+// FOLLOWED_ARTISTS and FOLLOWED_TRACKS hold (follower, followed) pairs, so what
+// a principal follows is one range scan. ARTIST_FOLLOWERS and TRACK_FOLLOWERS
+// hold the same pairs the other way round, so a principal's or a track's
+// followers are one range scan too, and FOLLOW_COUNTS keeps each principal's
+// totals so the engagement metrics don't have to count. Every change goes
+// through `follow_*`/`unfollow_*`, which keep all of them in step; `rebuild`
+// derives the reverse maps and counts from the forward ones when
+// FOLLOWS_VERSION changes.
+use crate::{
+    indexes, update_config, ApiError, FollowCounts, ARTIST_FOLLOWERS, CONFIG, FOLLOWED_ARTISTS, FOLLOWED_TRACKS, FOLLOW_COUNTS,
+    TRACK_FOLLOWERS,
+};
+use candid::Principal;
+
+/// Bump when the reverse maps change; they are rebuilt on upgrade.
+const FOLLOWS_VERSION: u32 = 1;
+
+pub fn counts(principal: Principal) -> FollowCounts {
+    FOLLOW_COUNTS.with(|c| c.borrow().get(&principal)).unwrap_or(FollowCounts { followers: 0, following: 0 })
+}
+
+fn update_counts(principal: Principal, f: impl FnOnce(&mut FollowCounts)) {
+    let mut counts = counts(principal);
+    f(&mut counts);
+    FOLLOW_COUNTS.with(|c| c.borrow_mut().insert(principal, counts));
+}
+
+fn add_artist_follow(follower: Principal, artist: Principal) {
+    ARTIST_FOLLOWERS.with(|f| f.borrow_mut().insert((artist, follower), ()));
+    update_counts(follower, |c| c.following += 1);
+    update_counts(artist, |c| c.followers += 1);
+}
+
+pub fn follow_artist(follower: Principal, artist: Principal) {
+    // Following twice changes nothing
+    if FOLLOWED_ARTISTS.with(|f| f.borrow_mut().insert((follower, artist), ())).is_none() {
+        add_artist_follow(follower, artist);
+    }
+}
+
+pub fn unfollow_artist(follower: Principal, artist: Principal) -> Result<(), ApiError> {
+    FOLLOWED_ARTISTS
+        .with(|f| f.borrow_mut().remove(&(follower, artist)))
+        .ok_or_else(|| ApiError::not_found("followed artist", artist))?;
+    ARTIST_FOLLOWERS.with(|f| f.borrow_mut().remove(&(artist, follower)));
+    update_counts(follower, |c| c.following = c.following.saturating_sub(1));
+    update_counts(artist, |c| c.followers = c.followers.saturating_sub(1));
+    Ok(())
+}
+
+pub fn follow_track(follower: Principal, track_id: u64) {
+    FOLLOWED_TRACKS.with(|f| f.borrow_mut().insert((follower, track_id), ()));
+    TRACK_FOLLOWERS.with(|f| f.borrow_mut().insert((track_id, follower), ()));
+}
+
+pub fn unfollow_track(follower: Principal, track_id: u64) -> Result<(), ApiError> {
+    FOLLOWED_TRACKS
+        .with(|f| f.borrow_mut().remove(&(follower, track_id)))
+        .ok_or_else(|| ApiError::not_found("followed track", track_id))?;
+    TRACK_FOLLOWERS.with(|f| f.borrow_mut().remove(&(track_id, follower)));
+    Ok(())
+}
+
+/// Artist principals `follower` follows.
+pub fn followed_artists(follower: Principal) -> Vec<Principal> {
+    FOLLOWED_ARTISTS.with(|f| {
+        f.borrow()
+            .range((follower, Principal::management_canister())..)
+            .take_while(|((p, _), _)| *p == follower)
+            .map(|((_, artist), _)| artist)
+            .collect()
+    })
+}
+
+pub fn followed_tracks(follower: Principal) -> Vec<u64> {
+    FOLLOWED_TRACKS.with(|f| f.borrow().range((follower, 0)..=(follower, u64::MAX)).map(|((_, id), _)| id).collect())
+}
+
+/// Principals following `artist`.
+pub fn artist_followers(artist: Principal) -> Vec<Principal> {
+    ARTIST_FOLLOWERS.with(|f| {
+        f.borrow()
+            .range((artist, Principal::management_canister())..)
+            .take_while(|((a, _), _)| *a == artist)
+            .map(|((_, follower), _)| follower)
+            .collect()
+    })
+}
+
+pub fn track_followers(track_id: u64) -> Vec<Principal> {
+    TRACK_FOLLOWERS.with(|f| {
+        f.borrow()
+            .range((track_id, Principal::management_canister())..)
+            .take_while(|((id, _), _)| *id == track_id)
+            .map(|((_, follower), _)| follower)
+            .collect()
+    })
+}
+
+/// Drops every follow of a deleted track.
+pub fn remove_track(track_id: u64) {
+    for follower in track_followers(track_id) {
+        FOLLOWED_TRACKS.with(|f| f.borrow_mut().remove(&(follower, track_id)));
+        TRACK_FOLLOWERS.with(|f| f.borrow_mut().remove(&(track_id, follower)));
+    }
+}
+
+/// Drops every artist and track `follower` follows, when their account is deleted.
+pub fn remove_follower(follower: Principal) {
+    for artist in followed_artists(follower) {
+        let _ = unfollow_artist(follower, artist);
+    }
+    for track_id in followed_tracks(follower) {
+        let _ = unfollow_track(follower, track_id);
+    }
+}
+
+/// Rederives the reverse maps and counts if they were built by another FOLLOWS_VERSION (or never).
+pub fn rebuild() {
+    if CONFIG.with(|c| c.borrow().get().follows_version) == Some(FOLLOWS_VERSION) {
+        return;
+    }
+    ARTIST_FOLLOWERS.with(|f| indexes::clear(&mut f.borrow_mut()));
+    TRACK_FOLLOWERS.with(|f| indexes::clear(&mut f.borrow_mut()));
+    FOLLOW_COUNTS.with(|c| indexes::clear(&mut c.borrow_mut()));
+    let artist_follows: Vec<(Principal, Principal)> = FOLLOWED_ARTISTS.with(|f| f.borrow().keys().collect());
+    for (follower, artist) in &artist_follows {
+        add_artist_follow(*follower, *artist);
+    }
+    let track_follows: Vec<(Principal, u64)> = FOLLOWED_TRACKS.with(|f| f.borrow().keys().collect());
+    for (follower, track_id) in &track_follows {
+        TRACK_FOLLOWERS.with(|f| f.borrow_mut().insert((*track_id, *follower), ()));
+    }
+    update_config(|c| c.follows_version = Some(FOLLOWS_VERSION));
+    ic_cdk::println!(
+        "Rebuilt the follower indexes: {} artist follows, {} track follows",
+        artist_follows.len(),
+        track_follows.len()
+    );
+}
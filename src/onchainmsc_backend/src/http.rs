@@ -0,0 +1,236 @@
+// HTTP gateway interface, so browsers can play track audio straight from the
+// canister with an `<audio src=".../tracks/{id}/audio">` tag. That path serves
+// the track's newest Master file; `/tracks/{id}/files/{file_id}` serves any file.
+//
+// Requests come in through the gateway as anonymous queries, so only tracks and
+// files an anonymous viewer may see are served. Range requests get a 206 with at most
+// MAX_BODY_BYTES of the requested range (browsers ask again for the rest); plain
+// GETs get the first MAX_BODY_BYTES and stream the remainder through
+// `http_streaming_callback`. `?download=1` asks for an attachment and is refused
+// unless the track is downloadable. That flag is a UI hint: playable bytes are
+// readable bytes, so inline responses are served whatever it says.
+use crate::{permissions, uploads, FileKind, TrackFile};
+use candid::{define_function, CandidType, Deserialize};
+
+/// Stays under the query response size limit with room for headers.
+const MAX_BODY_BYTES: u64 = 1_900_000;
+
+pub type HeaderField = (String, String);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackToken {
+    pub track_id: u64,
+    pub file_id: u64,
+    /// The stream ends if the file is replaced meanwhile.
+    pub uploaded_at: u64,
+    pub offset: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StreamingStrategy {
+    Callback { callback: StreamingCallback, token: StreamingCallbackToken },
+}
+
+fn text_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        body: message.as_bytes().to_vec(),
+        streaming_strategy: None,
+    }
+}
+
+/// Parses `/tracks/{id}/audio` or `/tracks/{id}/files/{file_id}` (plus an
+/// optional query) into the track id, the file id if given, and whether `download=1` was given.
+fn parse_url(url: &str) -> Option<(u64, Option<u64>, bool)> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let (track_id, rest) = path.strip_prefix("/tracks/")?.split_once('/')?;
+    let file_id = match rest.strip_prefix("files/") {
+        Some(file_id) => Some(file_id.parse().ok()?),
+        None if rest == "audio" => None,
+        None => return None,
+    };
+    let download = query.split('&').any(|param| param == "download=1" || param == "download=true");
+    Some((track_id.parse().ok()?, file_id, download))
+}
+
+/// Resolves a `Range` header against a file of `size` bytes into `start..end`.
+/// `Err(())` means the range can't be satisfied; only single ranges are supported.
+fn parse_range(header: &str, size: u64) -> Result<(u64, u64), ()> {
+    let spec = header.trim().strip_prefix("bytes=").ok_or(())?;
+    if spec.contains(',') {
+        return Err(());
+    }
+    let (first, last) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let len: u64 = suffix.parse().map_err(|_| ())?;
+            (size.saturating_sub(len), size)
+        }
+        (first, "") => (first.parse().map_err(|_| ())?, size),
+        (first, last) => {
+            let last: u64 = last.parse().map_err(|_| ())?;
+            (first.parse().map_err(|_| ())?, last.saturating_add(1).min(size))
+        }
+    };
+    if start >= end {
+        return Err(());
+    }
+    Ok((start, end))
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+/// A file the caller may fetch, with its track's `downloadable` flag. Without
+/// a file id, the track's newest Master.
+fn visible_file(track_id: u64, file_id: Option<u64>) -> Option<(bool, TrackFile)> {
+    let track = permissions::visible_track(track_id)?;
+    let file = match file_id {
+        Some(file_id) => permissions::visible_file(track_id, file_id)?,
+        None => permissions::visible_files(track_id).into_iter().rev().find(|f| f.kind == FileKind::Master)?,
+    };
+    Some((track.downloadable, file))
+}
+
+pub fn serve(req: HttpRequest) -> HttpResponse {
+    let head = req.method.eq_ignore_ascii_case("HEAD");
+    if !head && !req.method.eq_ignore_ascii_case("GET") {
+        return text_response(405, "method not allowed");
+    }
+    let Some((track_id, file_id, download)) = parse_url(&req.url) else {
+        return text_response(404, "not found");
+    };
+    let Some((downloadable, file)) = visible_file(track_id, file_id) else {
+        return text_response(404, "file not found");
+    };
+    if download && !downloadable {
+        return text_response(403, "downloads are disabled for this track");
+    }
+
+    let size = uploads::file_size(&file);
+    let mut headers = vec![
+        ("Content-Type".to_string(), file.content_type.clone()),
+        ("Accept-Ranges".to_string(), "bytes".to_string()),
+        ("Cache-Control".to_string(), "no-cache".to_string()),
+    ];
+    let disposition = if download { "attachment" } else { "inline" };
+    headers.push(("Content-Disposition".to_string(), format!("{}; filename=\"{}\"", disposition, file.filename.replace('"', ""))));
+
+    if let Some(range) = header(&req, "Range") {
+        let Ok((start, end)) = parse_range(range, size) else {
+            let mut response = text_response(416, "range not satisfiable");
+            response.headers.push(("Content-Range".to_string(), format!("bytes */{}", size)));
+            return response;
+        };
+        let end = end.min(start + MAX_BODY_BYTES);
+        headers.push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end - 1, size)));
+        headers.push(("Content-Length".to_string(), (end - start).to_string()));
+        let body = if head { vec![] } else { uploads::read_range(&file, start, end) };
+        return HttpResponse { status_code: 206, headers, body, streaming_strategy: None };
+    }
+
+    headers.push(("Content-Length".to_string(), size.to_string()));
+    if head {
+        return HttpResponse { status_code: 200, headers, body: vec![], streaming_strategy: None };
+    }
+    let end = size.min(MAX_BODY_BYTES);
+    let streaming_strategy = next_token(&file, end, size).map(|token| StreamingStrategy::Callback {
+        callback: StreamingCallback::new(ic_cdk::api::id(), "http_streaming_callback".to_string()),
+        token,
+    });
+    HttpResponse { status_code: 200, headers, body: uploads::read_range(&file, 0, end), streaming_strategy }
+}
+
+fn next_token(file: &TrackFile, offset: u64, size: u64) -> Option<StreamingCallbackToken> {
+    (offset < size).then_some(StreamingCallbackToken {
+        track_id: file.track_id,
+        file_id: file.file_id,
+        uploaded_at: file.uploaded_at,
+        offset,
+    })
+}
+
+pub fn stream(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let done = StreamingCallbackHttpResponse { body: vec![], token: None };
+    // Tokens come back from the client, so visibility is checked again
+    let Some((_, file)) = visible_file(token.track_id, Some(token.file_id)) else {
+        return done;
+    };
+    let size = uploads::file_size(&file);
+    if file.uploaded_at != token.uploaded_at || token.offset >= size {
+        return done;
+    }
+    let end = size.min(token.offset + MAX_BODY_BYTES);
+    StreamingCallbackHttpResponse {
+        body: uploads::read_range(&file, token.offset, end),
+        token: next_token(&file, end, size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn closed_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok((0, 100)));
+        assert_eq!(parse_range(" bytes=10 - 19 ", 1000), Ok((10, 20)));
+        // The end is clamped to the file
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok((900, 1000)));
+    }
+
+    #[test]
+    fn open_ended_ranges() {
+        assert_eq!(parse_range("bytes=0-", 1000), Ok((0, 1000)));
+        assert_eq!(parse_range("bytes=999-", 1000), Ok((999, 1000)));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), Ok((900, 1000)));
+        // Asking for more than the file returns all of it
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok((0, 1000)));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+    }
+
+    #[test]
+    fn start_at_or_beyond_the_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=2000-2999", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        assert_eq!(parse_range("bytes=50-10", 1000), Err(()));
+    }
+
+    #[test]
+    fn malformed_headers() {
+        for header in ["", "bytes=", "bytes=-", "bytes=abc-", "bytes=1-x", "items=0-99", "bytes 0-99", "bytes=0-1,5-9"] {
+            assert_eq!(parse_range(header, 1000), Err(()), "{:?}", header);
+        }
+    }
+}
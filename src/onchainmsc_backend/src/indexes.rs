@@ -0,0 +1,244 @@
+// Secondary indexes over the entity stores.
+//
+// Every entity lives in one primary map (TRACKS, ARTISTS and PLAYLISTS by id,
+// USERS by principal). The maps here answer the other lookups without scanning
+// them: tracks by contributor, tag and genre, users by id and username,
+// artists and playlists by owner, and active suspensions by suspended user. They hold keys only and are derived from the
+// primary maps, so writers call `index_*`/`unindex_*` next to every insert and
+// remove, and `rebuild` recreates everything when INDEX_VERSION changes.
+//
+// Text keys are lowercased and cut to MAX_VALUE_BYTES, so a lookup can return
+// more than an exact match; callers check the loaded records themselves.
+use crate::{
+    memory::Memory, update_config, Artist, Playlist, Suspension, SuspensionStatus, SuspensionTargetType, Track, User,
+    ARTISTS, ARTISTS_BY_OWNER, CONFIG, PLAYLISTS, PLAYLISTS_BY_OWNER, SUSPENSIONS, SUSPENSIONS_BY_USER, TRACKS,
+    TRACKS_BY_CONTRIBUTOR, TRACKS_BY_GENRE, TRACKS_BY_TAG, USERS, USERS_BY_USERNAME, USER_PRINCIPALS,
+};
+use candid::Principal;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use std::borrow::Cow;
+
+/// Bump when the keys change; the indexes are rebuilt on upgrade.
+const INDEX_VERSION: u32 = 2;
+/// Longer values are cut to this many bytes, so keys stay bounded.
+const MAX_VALUE_BYTES: usize = 64;
+
+/// A text value and the id of a record holding it; ordered by value so one value is a range scan.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ValueKey {
+    value: String,
+    id: u64,
+}
+
+impl ValueKey {
+    fn new(value: &str, id: u64) -> Self {
+        let value = value.to_lowercase();
+        let mut end = value.len().min(MAX_VALUE_BYTES);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        ValueKey { value: value[..end].to_string(), id }
+    }
+}
+
+impl Storable for ValueKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.value.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (value, id) = bytes.split_at(bytes.len() - 8);
+        ValueKey {
+            value: String::from_utf8(value.to_vec()).expect("index values are UTF-8"),
+            id: u64::from_be_bytes(id.try_into().expect("8-byte record id")),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_BYTES as u32 + 8, is_fixed_size: false };
+}
+
+/// Ids filed under `value`, lowest first.
+fn ids_with_value<V: Storable>(index: &StableBTreeMap<ValueKey, V, Memory>, value: &str) -> Vec<u64> {
+    let start = ValueKey::new(value, 0);
+    let end = ValueKey { id: u64::MAX, ..start.clone() };
+    index.range(start..=end).map(|(key, _)| key.id).collect()
+}
+
+/// Ids paired with `owner` in an (owner, id) index, lowest first.
+fn ids_of<K: Storable + Ord + Clone>(index: &StableBTreeMap<(K, u64), (), Memory>, owner: K) -> Vec<u64> {
+    index.range((owner.clone(), 0)..=(owner, u64::MAX)).map(|((_, id), _)| id).collect()
+}
+
+pub fn index_track(track: &Track) {
+    TRACKS_BY_CONTRIBUTOR.with(|index| {
+        let mut index = index.borrow_mut();
+        for artist_id in &track.contributors {
+            index.insert((*artist_id, track.id), ());
+        }
+    });
+    TRACKS_BY_TAG.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in &track.tags {
+            index.insert(ValueKey::new(tag, track.id), ());
+        }
+    });
+    if let Some(genre) = &track.genre {
+        TRACKS_BY_GENRE.with(|index| index.borrow_mut().insert(ValueKey::new(genre, track.id), ()));
+    }
+}
+
+pub fn unindex_track(track: &Track) {
+    TRACKS_BY_CONTRIBUTOR.with(|index| {
+        let mut index = index.borrow_mut();
+        for artist_id in &track.contributors {
+            index.remove(&(*artist_id, track.id));
+        }
+    });
+    TRACKS_BY_TAG.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in &track.tags {
+            index.remove(&ValueKey::new(tag, track.id));
+        }
+    });
+    if let Some(genre) = &track.genre {
+        TRACKS_BY_GENRE.with(|index| index.borrow_mut().remove(&ValueKey::new(genre, track.id)));
+    }
+}
+
+pub fn index_user(user: &User) {
+    USER_PRINCIPALS.with(|index| index.borrow_mut().insert(user.id, user.principal));
+    USERS_BY_USERNAME.with(|index| index.borrow_mut().insert(ValueKey::new(&user.username, user.id), user.principal));
+}
+
+pub fn unindex_user(user: &User) {
+    USER_PRINCIPALS.with(|index| index.borrow_mut().remove(&user.id));
+    USERS_BY_USERNAME.with(|index| index.borrow_mut().remove(&ValueKey::new(&user.username, user.id)));
+}
+
+pub fn index_artist(artist: &Artist) {
+    ARTISTS_BY_OWNER.with(|index| index.borrow_mut().insert((artist.user_principal, artist.id), ()));
+}
+
+pub fn unindex_artist(artist: &Artist) {
+    ARTISTS_BY_OWNER.with(|index| index.borrow_mut().remove(&(artist.user_principal, artist.id)));
+}
+
+pub fn index_playlist(playlist: &Playlist) {
+    PLAYLISTS_BY_OWNER.with(|index| index.borrow_mut().insert((playlist.owner, playlist.id), ()));
+}
+
+pub fn unindex_playlist(playlist: &Playlist) {
+    PLAYLISTS_BY_OWNER.with(|index| index.borrow_mut().remove(&(playlist.owner, playlist.id)));
+}
+
+/// The suspended user, for an active user suspension.
+fn suspended_user(suspension: &Suspension) -> Option<Principal> {
+    if suspension.status != SuspensionStatus::Active || suspension.target_type != SuspensionTargetType::User {
+        return None;
+    }
+    Principal::from_text(&suspension.target_id).ok()
+}
+
+pub fn index_suspension(suspension: &Suspension) {
+    if let Some(user) = suspended_user(suspension) {
+        SUSPENSIONS_BY_USER.with(|index| index.borrow_mut().insert((user, suspension.id), ()));
+    }
+}
+
+/// Call with the suspension as it was indexed, before it stops being active.
+pub fn unindex_suspension(suspension: &Suspension) {
+    if let Some(user) = suspended_user(suspension) {
+        SUSPENSIONS_BY_USER.with(|index| index.borrow_mut().remove(&(user, suspension.id)));
+    }
+}
+
+/// Tracks listing the artist as a contributor.
+pub fn tracks_by_contributor(artist_id: u64) -> Vec<u64> {
+    TRACKS_BY_CONTRIBUTOR.with(|index| ids_of(&index.borrow(), artist_id))
+}
+
+/// Tracks that may carry `tag`, matched case-insensitively.
+pub fn tracks_with_tag(tag: &str) -> Vec<u64> {
+    TRACKS_BY_TAG.with(|index| ids_with_value(&index.borrow(), tag))
+}
+
+/// Tracks that may be in `genre`, matched case-insensitively.
+pub fn tracks_in_genre(genre: &str) -> Vec<u64> {
+    TRACKS_BY_GENRE.with(|index| ids_with_value(&index.borrow(), genre))
+}
+
+pub fn user_by_id(user_id: u64) -> Option<User> {
+    let principal = USER_PRINCIPALS.with(|index| index.borrow().get(&user_id))?;
+    USERS.with(|users| users.borrow().get(&principal))
+}
+
+/// Users whose username may equal `username`, matched case-insensitively.
+pub fn users_named(username: &str) -> Vec<User> {
+    let start = ValueKey::new(username, 0);
+    let end = ValueKey { id: u64::MAX, ..start.clone() };
+    let principals: Vec<Principal> =
+        USERS_BY_USERNAME.with(|index| index.borrow().range(start..=end).map(|(_, principal)| principal).collect());
+    USERS.with(|users| {
+        let users = users.borrow();
+        principals.iter().filter_map(|principal| users.get(principal)).collect()
+    })
+}
+
+pub fn artists_of(principal: Principal) -> Vec<u64> {
+    ARTISTS_BY_OWNER.with(|index| ids_of(&index.borrow(), principal))
+}
+
+pub fn playlists_of(principal: Principal) -> Vec<u64> {
+    PLAYLISTS_BY_OWNER.with(|index| ids_of(&index.borrow(), principal))
+}
+
+/// Active suspensions of the user, expired or not.
+pub fn suspensions_of(principal: Principal) -> Vec<Suspension> {
+    let ids = SUSPENSIONS_BY_USER.with(|index| ids_of(&index.borrow(), principal));
+    SUSPENSIONS.with(|s| {
+        let s = s.borrow();
+        ids.iter().filter_map(|id| s.get(id)).collect()
+    })
+}
+
+pub(crate) fn clear<K: Storable + Ord + Clone, V: Storable>(index: &mut StableBTreeMap<K, V, Memory>) {
+    let keys: Vec<K> = index.keys().collect();
+    for key in &keys {
+        index.remove(key);
+    }
+}
+
+/// Reindexes everything if the indexes were built by another INDEX_VERSION (or never).
+pub fn rebuild() {
+    if CONFIG.with(|c| c.borrow().get().lookup_index_version) == Some(INDEX_VERSION) {
+        return;
+    }
+    TRACKS_BY_CONTRIBUTOR.with(|index| clear(&mut index.borrow_mut()));
+    TRACKS_BY_TAG.with(|index| clear(&mut index.borrow_mut()));
+    TRACKS_BY_GENRE.with(|index| clear(&mut index.borrow_mut()));
+    USER_PRINCIPALS.with(|index| clear(&mut index.borrow_mut()));
+    USERS_BY_USERNAME.with(|index| clear(&mut index.borrow_mut()));
+    ARTISTS_BY_OWNER.with(|index| clear(&mut index.borrow_mut()));
+    PLAYLISTS_BY_OWNER.with(|index| clear(&mut index.borrow_mut()));
+    SUSPENSIONS_BY_USER.with(|index| clear(&mut index.borrow_mut()));
+    let tracks: Vec<Track> = TRACKS.with(|t| t.borrow().values().collect());
+    tracks.iter().for_each(index_track);
+    let users: Vec<User> = USERS.with(|u| u.borrow().values().collect());
+    users.iter().for_each(index_user);
+    let artists: Vec<Artist> = ARTISTS.with(|a| a.borrow().values().collect());
+    artists.iter().for_each(index_artist);
+    let playlists: Vec<Playlist> = PLAYLISTS.with(|p| p.borrow().values().collect());
+    playlists.iter().for_each(index_playlist);
+    let suspensions: Vec<Suspension> = SUSPENSIONS.with(|s| s.borrow().values().collect());
+    suspensions.iter().for_each(index_suspension);
+    update_config(|c| c.lookup_index_version = Some(INDEX_VERSION));
+    ic_cdk::println!(
+        "Rebuilt the lookup indexes: {} tracks, {} users, {} artists, {} playlists",
+        tracks.len(),
+        users.len(),
+        artists.len(),
+        playlists.len()
+    );
+}
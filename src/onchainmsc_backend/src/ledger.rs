@@ -0,0 +1,117 @@
+// Minimal ICRC-1 / ICRC-2 ledger client.
+//
+// Listener payments are pulled with `icrc2_transfer_from` (the payer first
+// approves this canister as spender) into the royalty pool subaccount, and
+// royalty withdrawals are pushed out of that pool with `icrc1_transfer`. Only
+// the parts of the ICRC standards these two flows use are declared here.
+use candid::{CandidType, Deserialize, Nat, Principal};
+
+pub type Subaccount = [u8; 32];
+
+/// Subaccount of this canister that holds every paid-in, not yet withdrawn token.
+pub const ROYALTY_POOL_SUBACCOUNT: Subaccount = *b"onchainmsc-royalty-pool\0\0\0\0\0\0\0\0\0";
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn royalty_pool() -> Self {
+        Account { owner: ic_cdk::api::id(), subaccount: Some(ROYALTY_POOL_SUBACCOUNT) }
+    }
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>, // blob
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Subaccount>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>, // blob
+    created_at_time: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Pulls `amount` from `from` into the royalty pool. Returns the ledger block index.
+pub async fn transfer_from(ledger: Principal, from: Account, amount: u64, memo: Vec<u8>) -> Result<u64, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to: Account::royalty_pool(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, msg)| format!("icrc2_transfer_from call failed ({:?}): {}", code, msg))?;
+    let index = result.map_err(|e| format!("icrc2_transfer_from rejected: {:?}", e))?;
+    block_index(index)
+}
+
+/// Sends `amount` out of the royalty pool to `to`. Returns the ledger block index.
+pub async fn transfer(ledger: Principal, to: Account, amount: u64, memo: Vec<u8>) -> Result<u64, String> {
+    let args = TransferArg {
+        from_subaccount: Some(ROYALTY_POOL_SUBACCOUNT),
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(memo),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, msg)| format!("icrc1_transfer call failed ({:?}): {}", code, msg))?;
+    let index = result.map_err(|e| format!("icrc1_transfer rejected: {:?}", e))?;
+    block_index(index)
+}
+
+/// The ledger's current transfer fee.
+pub async fn fee(ledger: Principal) -> Result<u64, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("icrc1_fee call failed ({:?}): {}", code, msg))?;
+    u64::try_from(fee.0).map_err(|_| "ledger fee does not fit in nat64".to_string())
+}
+
+fn block_index(index: Nat) -> Result<u64, String> {
+    u64::try_from(index.0.clone()).map_err(|_| format!("ledger block index {} does not fit in nat64", index))
+}
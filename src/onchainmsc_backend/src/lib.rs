@@ -1,11 +1,32 @@
-use ic_cdk::storage;
 use candid::{CandidType, Deserialize};
 use std::cell::RefCell;
 use ic_cdk::api::caller;
 use candid::Principal;
-use ic_stable_structures::{StableBTreeMap, DefaultMemoryImpl, storable::BoundedStorable, storable::Storable};
-use std::borrow::Cow;
-use serde::{Deserialize as SerdeDeserialize, Serialize};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+
+mod audio;
+mod error;
+mod follows;
+mod http;
+mod indexes;
+mod ledger;
+mod metrics;
+mod memory;
+mod migration;
+mod paging;
+mod permissions;
+mod plays;
+mod rate_limits;
+mod referrals;
+mod royalties;
+mod search;
+mod uploads;
+mod waveforms;
+
+pub use error::ApiError;
+use memory::{candid_storable, Memory};
+use migration::initial_schema;
+use permissions::TrackOperation;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Artist {
@@ -21,7 +42,7 @@ pub struct Artist {
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Comment {
-    pub commenter: u64, // artist id
+    pub commenter: u64, // user id
     pub text: String,
 }
 
@@ -62,7 +83,18 @@ pub struct Track {
     pub tags: Vec<String>,
     pub genre: Option<String>,
     pub play_count: u64, // new field for analytics
-    pub downloadable: bool,
+    pub downloadable: bool, // offer a download in the UI; playback still serves every byte (see http.rs)
+    pub pending_splits: Option<SplitProposal>, // awaiting approval from every owner
+    pub duration_ms: Option<u64>, // from the newest Master file's audio headers
+    pub unique_listeners: Option<u64>, // principals with a counted play (see plays.rs)
+    pub referral_share_pct: Option<u8>, // share of payments through a referral code that goes to its sharer (see referrals.rs)
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SplitProposal {
+    pub splits: Vec<Split>,
+    pub proposed_by: u64, // user id
+    pub approvals: Vec<u64>, // owner user ids
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -74,13 +106,18 @@ pub struct TrackVersion {
     pub changed_by: Principal,
     pub changed_at: u64,
     pub change_description: Option<String>,
+    /// Track files as they were at this version, without inline data; `None`
+    /// for versions recorded before versions tracked files.
+    pub files: Option<Vec<TrackFile>>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Payment {
-    pub payer: u64, // artist id or user id
+    pub payer: u64, // user id
     pub amount: u64, // in smallest unit (e.g., tokens)
     pub timestamp: u64,
+    pub block_index: Option<u64>, // ledger block of the transfer_from; None for pre-ledger payments
+    pub referral: Option<String>, // code the payment came through
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -131,6 +168,7 @@ pub struct Task {
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct User {
+    pub id: u64,
     pub principal: Principal,
     pub username: String,
     pub bio: Option<String>,
@@ -138,6 +176,43 @@ pub struct User {
     pub role: UserRole,
 }
 
+// User layout before numeric user ids (schema v1)
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UserV1 {
+    pub principal: Principal,
+    pub username: String,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub role: UserRole,
+}
+
+impl From<UserV1> for User {
+    // Runs once per stored user when post_upgrade rewrites the collection,
+    // which is where pre-existing users get their id.
+    fn from(user: UserV1) -> Self {
+        User {
+            id: next_id(IdCounter::User),
+            principal: user.principal,
+            username: user.username,
+            bio: user.bio,
+            avatar_url: user.avatar_url,
+            role: user.role,
+        }
+    }
+}
+
+impl migration::Schema for User {
+    const MIGRATIONS: &'static [migration::Migration] = &[migration::convert::<UserV1, User>];
+}
+
+// Who the caller is: their user id and the artist profiles they own
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Identity {
+    pub principal: Principal,
+    pub user_id: Option<u64>,
+    pub artist_ids: Vec<u64>,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub enum UserRole {
     User,
@@ -217,6 +292,7 @@ pub struct TrackLicense {
 }
 
 // --- API Rate Limiting ---
+// Calls to one method by one principal in the current window (see rate_limits.rs)
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct RateLimitEntry {
     pub principal: Principal,
@@ -225,6 +301,22 @@ pub struct RateLimitEntry {
     pub window_start: u64,
 }
 
+// Endpoints with a per-caller rate limit
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum RateLimitedMethod {
+    SendMessage,
+    AddComment,
+    ReportContent,
+    RateTrack,
+    RecordPlay,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RateLimitPolicy {
+    pub max_calls: u32,
+    pub window_secs: u64,
+}
+
 // --- Audit Log & Admin Actions History ---
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct AuditLogEntry {
@@ -315,61 +407,457 @@ pub struct SuspensionAppeal {
     pub notes: Option<String>,
 }
 
-// Storage for workflow management
+candid_storable!(
+    Artist, Track, TrackVersion, Activity, CollabRequest, Task, User, UserActivity,
+    Notification, Playlist, PlayDownloadCount, Message, Report, TrackLicense,
+    RateLimitEntry, AuditLogEntry, ModerationQueueItem, Suspension, SuspensionAppeal,
+    WorkflowStep, CollaborationSession, WorkflowTemplate, TrackFile, TrackFileV1, UploadSession, StorageUsage,
+    Waveform, StoredContent, RateLimitPolicy, PlayBucket, MetricTotals, ActiveDays, FollowCounts,
+    ReferralCode,
+);
+
+// Canister-wide settings. Add fields as `Option`s so older records still decode.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Config {
+    /// Installing controller; always an admin, whatever its user record says.
+    pub super_admin: Option<Principal>,
+    /// ICRC-1/ICRC-2 ledger that payments and withdrawals settle on.
+    pub ledger_canister_id: Option<Principal>,
+    /// Tokenizer version the search index was built with (see search.rs).
+    pub search_index_version: Option<u32>,
+    /// Key layout the lookup indexes were built with (see indexes.rs).
+    pub lookup_index_version: Option<u32>,
+    /// Bucketing the analytics rollups were built with (see metrics.rs).
+    pub metrics_version: Option<u32>,
+    /// Layout the follower indexes were built with (see follows.rs).
+    pub follows_version: Option<u32>,
+    /// Whether the analytics rollups include registrations (see metrics.rs).
+    pub registrations_backfilled: Option<bool>,
+    /// Whether the track payout index covers payments posted before it existed (see royalties.rs).
+    pub payouts_indexed: Option<bool>,
+}
+
+// Optional install/upgrade argument, e.g. `(opt record { ledger_canister_id = opt principal "..." })`
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct InitArgs {
+    pub ledger_canister_id: Option<Principal>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentConfig {
+    pub ledger_canister_id: Option<Principal>,
+    /// Payers approve this canister as ICRC-2 spender; paid-in tokens land in this account.
+    pub royalty_pool: ledger::Account,
+}
+
+candid_storable!(Config);
+
+// Royalty ledger (see royalties.rs). Every entry moves `amount` from the debit
+// account to the credit account; artists' balances are the net of their entries.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum LedgerAccount {
+    /// Tokens held in the royalty pool subaccount that no artist has been credited with.
+    Pool,
+    Artist(u64),
+    /// A sharer's referral earnings (see referrals.rs).
+    Referrer(Principal),
+}
+
+impl LedgerAccount {
+    pub fn artist_id(&self) -> Option<u64> {
+        match self {
+            LedgerAccount::Pool | LedgerAccount::Referrer(_) => None,
+            LedgerAccount::Artist(id) => Some(*id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum EntryReference {
+    /// Balance the artist already had when the ledger was introduced.
+    OpeningBalance,
+    /// An artist's share of a track payment, with the split percentage it was computed from.
+    Payment { track_id: u64, block_index: u64, split_pct: u8 },
+    Withdrawal { withdrawal_id: u64 },
+    /// Gives a failed withdrawal back to the artist.
+    WithdrawalReversal { withdrawal_id: u64 },
+    /// Unwithdrawn balance returned to the pool when an admin deleted the artist.
+    ArtistRemoved { removed_by: Principal },
+    /// A referrer's share of a track payment made through their code.
+    Referral { track_id: u64, block_index: u64, code: String },
+    ReferralWithdrawal { withdrawal_id: u64 },
+    /// Gives a failed referral withdrawal back to the referrer.
+    ReferralWithdrawalReversal { withdrawal_id: u64 },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RoyaltyEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: u64,
+    pub reference: EntryReference,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum WithdrawalStatus {
+    Pending,
+    Completed { block_index: u64 },
+    Failed { reason: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Withdrawal {
+    pub id: u64,
+    pub artist_id: u64,
+    pub amount: u64, // debited from the balance; the recipient gets amount - fee
+    pub fee: u64,
+    pub to: Principal,
+    pub requested_at: u64,
+    pub status: WithdrawalStatus,
+}
+
+// A payout of referral earnings; its id comes from the same counter as Withdrawal's
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReferralWithdrawal {
+    pub id: u64,
+    pub referrer: Principal,
+    pub amount: u64, // debited from the balance; the referrer gets amount - fee
+    pub fee: u64,
+    pub requested_at: u64,
+    pub status: WithdrawalStatus,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RoyaltyStatement {
+    pub artist_id: u64,
+    pub opening_balance: u64, // before the period start
+    pub total_credits: u64,   // within the period
+    pub total_debits: u64,
+    pub closing_balance: u64, // at the period end
+    pub entries: Vec<RoyaltyEntry>,
+    /// Pass as `after` to fetch the next page; `None` on the last page.
+    pub next_after: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TrackRoyaltyReport {
+    pub track_id: u64,
+    pub total_paid: u64,
+    pub payouts: Vec<(u64, u64)>, // (artist_id, amount credited)
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BalanceMismatch {
+    pub artist_id: u64,
+    /// `None` when the artist no longer exists.
+    pub stored_balance: Option<u64>,
+    pub ledger_balance: i128,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReferrerMismatch {
+    pub referrer: Principal,
+    pub stored_balance: u64,
+    pub ledger_balance: i128,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReconciliationReport {
+    pub artists_checked: u64,
+    pub referrers_checked: u64,
+    pub total_entries: u64,
+    pub pool_liability: u64,
+    /// Empty when every balance equals the sum of its entries.
+    pub mismatches: Vec<BalanceMismatch>,
+    pub referrer_mismatches: Vec<ReferrerMismatch>,
+}
+
+candid_storable!(RoyaltyEntry, Withdrawal, ReferralWithdrawal);
+
+// Types that have never changed layout; the rest implement Schema next to
+// their definition (see migration.rs).
+initial_schema!(
+    Config, RoyaltyEntry, Withdrawal, ReferralWithdrawal,
+    Artist, Track, TrackVersion, Activity, CollabRequest, Task, UserActivity,
+    Notification, Playlist, PlayDownloadCount, Message, Report, TrackLicense,
+    RateLimitEntry, AuditLogEntry, ModerationQueueItem, Suspension, SuspensionAppeal,
+    WorkflowStep, CollaborationSession, WorkflowTemplate, TrackFile, TrackFileV1, UploadSession, StorageUsage,
+    Waveform, StoredContent, RateLimitPolicy, PlayBucket, ActiveDays, FollowCounts,
+    ReferralCode,
+);
+
+// Keys into ID_COUNTERS. The discriminant is what gets persisted, so only append.
+#[derive(Clone, Copy)]
+enum IdCounter {
+    Artist = 0,
+    Track = 1,
+    CollabRequest = 2,
+    Task = 3,
+    Notification = 4,
+    Playlist = 5,
+    Message = 6,
+    Report = 7,
+    AuditLog = 8,
+    ModerationQueue = 9,
+    Suspension = 10,
+    SuspensionAppeal = 11,
+    WorkflowStep = 12,
+    Session = 13,
+    Template = 14,
+    User = 15,
+    RoyaltyEntry = 16,
+    Withdrawal = 17,
+    Upload = 18,
+    TrackFile = 19,
+    Referral = 20,
+}
+
+// All canister state lives in stable memory (see memory.rs) and survives upgrades.
 thread_local! {
-    static ARTISTS: RefCell<Vec<Artist>> = RefCell::new(Vec::new());
-    static TRACKS: RefCell<Vec<Track>> = RefCell::new(Vec::new());
-    static ARTIST_ID: RefCell<u64> = RefCell::new(1);
-    static TRACK_ID: RefCell<u64> = RefCell::new(1);
-    static TRACK_VERSIONS: RefCell<Vec<(u64, Vec<TrackVersion>)>> = RefCell::new(Vec::new()); // track_id -> versions
-    static ACTIVITY_LOG: RefCell<Vec<Activity>> = RefCell::new(Vec::new());
-    static COLLAB_REQUESTS: RefCell<Vec<CollabRequest>> = RefCell::new(Vec::new());
-    static COLLAB_REQUEST_ID: RefCell<u64> = RefCell::new(1);
-    static TASKS: RefCell<Vec<Task>> = RefCell::new(Vec::new());
-    static TASK_ID: RefCell<u64> = RefCell::new(1);
-    static USERS: RefCell<Vec<User>> = RefCell::new(Vec::new());
-    static USER_ACTIVITY_LOG: RefCell<Vec<UserActivity>> = RefCell::new(Vec::new());
-    static NOTIFICATIONS: RefCell<Vec<Notification>> = RefCell::new(Vec::new());
-    static NOTIFICATION_ID: RefCell<u64> = RefCell::new(1);
-    static FOLLOWED_ARTISTS: RefCell<Vec<(Principal, Vec<Principal>)>> = RefCell::new(Vec::new());
-    static FOLLOWED_TRACKS: RefCell<Vec<(Principal, Vec<u64>)>> = RefCell::new(Vec::new());
-    static PLAYLISTS: RefCell<Vec<Playlist>> = RefCell::new(Vec::new());
-    static PLAYLIST_ID: RefCell<u64> = RefCell::new(1);
-    static PLAY_COUNTS: RefCell<Vec<PlayDownloadCount>> = RefCell::new(Vec::new());
-    static MESSAGES: RefCell<Vec<Message>> = RefCell::new(Vec::new());
-    static MESSAGE_ID: RefCell<u64> = RefCell::new(1);
-    static REPORTS: RefCell<Vec<Report>> = RefCell::new(Vec::new());
-    static REPORT_ID: RefCell<u64> = RefCell::new(1);
-    static TRACK_LICENSES: RefCell<Vec<TrackLicense>> = RefCell::new(Vec::new());
-    static RATE_LIMITS: RefCell<Vec<RateLimitEntry>> = RefCell::new(Vec::new());
-    static AUDIT_LOG: RefCell<Vec<AuditLogEntry>> = RefCell::new(Vec::new());
-    static AUDIT_LOG_ID: RefCell<u64> = RefCell::new(1);
-    static MODERATION_QUEUE: RefCell<Vec<ModerationQueueItem>> = RefCell::new(Vec::new());
-    static MODERATION_QUEUE_ID: RefCell<u64> = RefCell::new(1);
-    static SUSPENSIONS: RefCell<Vec<Suspension>> = RefCell::new(Vec::new());
-    static SUSPENSION_ID: RefCell<u64> = RefCell::new(1);
-    static SUSPENSION_APPEALS: RefCell<Vec<SuspensionAppeal>> = RefCell::new(Vec::new());
-    static SUSPENSION_APPEAL_ID: RefCell<u64> = RefCell::new(1);
-    static BANNED_KEYWORDS: RefCell<Vec<String>> = RefCell::new(vec![
-        "spam".to_string(),
-        "scam".to_string(),
-        "fake".to_string(),
-        "copyright".to_string(),
-        "illegal".to_string(),
-    ]);
-    
+    static ID_COUNTERS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ID_COUNTERS)));
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::CONFIG), Config::default()).expect("failed to initialize config cell"),
+    );
+    static ARTISTS: RefCell<StableBTreeMap<u64, Artist, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ARTISTS)));
+    static TRACKS: RefCell<StableBTreeMap<u64, Track, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACKS)));
+    static TRACK_VERSIONS: RefCell<StableBTreeMap<(u64, u32), TrackVersion, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_VERSIONS))); // (track_id, version) -> version
+    static ACTIVITY_LOG: RefCell<StableBTreeMap<u64, Activity, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ACTIVITY_LOG)));
+    static COLLAB_REQUESTS: RefCell<StableBTreeMap<u64, CollabRequest, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::COLLAB_REQUESTS)));
+    static TASKS: RefCell<StableBTreeMap<u64, Task, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TASKS)));
+    static USERS: RefCell<StableBTreeMap<Principal, User, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::USERS)));
+    static USER_ACTIVITY_LOG: RefCell<StableBTreeMap<u64, UserActivity, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::USER_ACTIVITY_LOG)));
+    static NOTIFICATIONS: RefCell<StableBTreeMap<u64, Notification, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::NOTIFICATIONS)));
+    static FOLLOWED_ARTISTS: RefCell<StableBTreeMap<(Principal, Principal), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::FOLLOWED_ARTISTS))); // (follower, artist)
+    static FOLLOWED_TRACKS: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::FOLLOWED_TRACKS))); // (follower, track_id)
+    // The same follows the other way round, and per-principal totals (see follows.rs)
+    static ARTIST_FOLLOWERS: RefCell<StableBTreeMap<(Principal, Principal), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ARTIST_FOLLOWERS))); // (artist, follower)
+    static TRACK_FOLLOWERS: RefCell<StableBTreeMap<(u64, Principal), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_FOLLOWERS))); // (track_id, follower)
+    static FOLLOW_COUNTS: RefCell<StableBTreeMap<Principal, FollowCounts, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::FOLLOW_COUNTS)));
+
+    // Share links and referral earnings (see referrals.rs)
+    static REFERRAL_CODES: RefCell<StableBTreeMap<String, ReferralCode, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::REFERRAL_CODES)));
+    static REFERRALS_BY_SHARER: RefCell<StableBTreeMap<(Principal, u64), String, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::REFERRALS_BY_SHARER))); // (sharer, track_id) -> code
+    static TRACK_SHARES: RefCell<StableBTreeMap<(u64, Principal), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_SHARES))); // (track_id, sharer)
+    static REFERRER_BALANCES: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::REFERRER_BALANCES)));
+    static REFERRAL_WITHDRAWALS: RefCell<StableBTreeMap<(Principal, u64), ReferralWithdrawal, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::REFERRAL_WITHDRAWALS))); // (referrer, withdrawal_id)
+    static PLAYLISTS: RefCell<StableBTreeMap<u64, Playlist, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::PLAYLISTS)));
+    static PLAY_COUNTS: RefCell<StableBTreeMap<(Principal, u64), PlayDownloadCount, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::PLAY_COUNTS)));
+    static MESSAGES: RefCell<StableBTreeMap<u64, Message, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::MESSAGES)));
+    static REPORTS: RefCell<StableBTreeMap<u64, Report, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::REPORTS)));
+    static TRACK_LICENSES: RefCell<StableBTreeMap<u64, TrackLicense, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_LICENSES)));
+    static RATE_LIMITS: RefCell<StableBTreeMap<(Principal, u8), RateLimitEntry, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::RATE_LIMIT_WINDOWS))); // (principal, method)
+    static RATE_LIMIT_POLICIES: RefCell<StableBTreeMap<u8, RateLimitPolicy, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::RATE_LIMIT_POLICIES))); // method -> override
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditLogEntry, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::AUDIT_LOG)));
+    static MODERATION_QUEUE: RefCell<StableBTreeMap<u64, ModerationQueueItem, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::MODERATION_QUEUE)));
+    static SUSPENSIONS: RefCell<StableBTreeMap<u64, Suspension, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::SUSPENSIONS)));
+    static SUSPENSION_APPEALS: RefCell<StableBTreeMap<u64, SuspensionAppeal, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::SUSPENSION_APPEALS)));
+    static BANNED_KEYWORDS: RefCell<StableBTreeMap<String, (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::BANNED_KEYWORDS)));
+
     // Workflow management storage
-    static WORKFLOW_STEPS: RefCell<Vec<WorkflowStep>> = RefCell::new(Vec::new());
-    static COLLABORATION_SESSIONS: RefCell<Vec<CollaborationSession>> = RefCell::new(Vec::new());
-    static WORKFLOW_TEMPLATES: RefCell<Vec<WorkflowTemplate>> = RefCell::new(Vec::new());
-    static WORKFLOW_STEP_ID: RefCell<u64> = RefCell::new(1);
-    static SESSION_ID: RefCell<u64> = RefCell::new(1);
-    static TEMPLATE_ID: RefCell<u64> = RefCell::new(1);
-    static TRACK_FILES: RefCell<StableBTreeMap<u64, TrackFile, DefaultMemoryImpl>> = RefCell::new(StableBTreeMap::new(DefaultMemoryImpl::default()));
+    static WORKFLOW_STEPS: RefCell<StableBTreeMap<u64, WorkflowStep, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::WORKFLOW_STEPS)));
+    static COLLABORATION_SESSIONS: RefCell<StableBTreeMap<u64, CollaborationSession, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::COLLABORATION_SESSIONS)));
+    static WORKFLOW_TEMPLATES: RefCell<StableBTreeMap<u64, WorkflowTemplate, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::WORKFLOW_TEMPLATES)));
+    // Single file per track from before the catalog; emptied by uploads::move_legacy_files
+    static LEGACY_TRACK_FILES: RefCell<StableBTreeMap<u64, TrackFileV1, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_FILES)));
+    static TRACK_FILES: RefCell<StableBTreeMap<(u64, u64), TrackFile, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_FILE_CATALOG))); // (track_id, file_id)
+
+    // Royalty ledger storage
+    static ROYALTY_ENTRIES: RefCell<StableBTreeMap<u64, RoyaltyEntry, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ROYALTY_ENTRIES)));
+    static ARTIST_ROYALTY_ENTRIES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ARTIST_ROYALTY_ENTRIES))); // (artist_id, entry_id)
+    static WITHDRAWALS: RefCell<StableBTreeMap<u64, Withdrawal, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::WITHDRAWALS)));
+
+    // Chunked upload storage (see uploads.rs)
+    static UPLOAD_SESSIONS: RefCell<StableBTreeMap<u64, UploadSession, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::UPLOAD_SESSIONS)));
+    static FILE_CHUNKS: RefCell<StableBTreeMap<(u64, u32), Vec<u8>, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::FILE_CHUNKS))); // (content_id, index) -> bytes
+    static STORAGE_USAGE: RefCell<StableBTreeMap<Principal, StorageUsage, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::STORAGE_USAGE)));
+    static STORED_CONTENTS: RefCell<StableBTreeMap<u64, StoredContent, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::STORED_CONTENTS))); // content_id -> content
+    static CONTENT_HASHES: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::CONTENT_HASHES))); // sha256 -> content_id
+    static CONTENT_CHARGES: RefCell<StableBTreeMap<(u64, u64), Principal, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::CONTENT_CHARGES))); // (track_id, content_id) -> charged user
+
+    // Search index (see search.rs); derived from the stores above and rebuilt on demand
+    static SEARCH_POSTINGS: RefCell<StableBTreeMap<search::PostingKey, u32, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::SEARCH_POSTINGS))); // (term, kind, id) -> weight
+    static SEARCH_DOCS: RefCell<StableBTreeMap<(u8, u64), String, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::SEARCH_DOCS))); // (kind, id) -> indexed terms
+
+    // Lookup indexes (see indexes.rs); derived from the entity stores and rebuilt on demand
+    static TRACKS_BY_CONTRIBUTOR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACKS_BY_CONTRIBUTOR))); // (artist_id, track_id)
+    static TRACKS_BY_TAG: RefCell<StableBTreeMap<indexes::ValueKey, (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACKS_BY_TAG))); // (tag, track_id)
+    static TRACKS_BY_GENRE: RefCell<StableBTreeMap<indexes::ValueKey, (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACKS_BY_GENRE))); // (genre, track_id)
+    static USER_PRINCIPALS: RefCell<StableBTreeMap<u64, Principal, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::USER_PRINCIPALS))); // user_id -> principal
+    static USERS_BY_USERNAME: RefCell<StableBTreeMap<indexes::ValueKey, Principal, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::USERS_BY_USERNAME))); // (username, user_id) -> principal
+    static ARTISTS_BY_OWNER: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ARTISTS_BY_OWNER))); // (user_principal, artist_id)
+    static PLAYLISTS_BY_OWNER: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::PLAYLISTS_BY_OWNER))); // (owner, playlist_id)
+    static SUSPENSIONS_BY_USER: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::SUSPENSIONS_BY_USER))); // (user, suspension_id), active ones only
+    static TRACK_PAYOUT_ENTRIES: RefCell<StableBTreeMap<(u64, u64, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::TRACK_PAYOUT_ENTRIES))); // (track_id, timestamp, entry_id), payment entries only
+
+    // Waveform previews (see waveforms.rs), keyed by content id
+    static WAVEFORMS: RefCell<StableBTreeMap<u64, Waveform, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::WAVEFORMS)));
+    static WAVEFORM_QUEUE: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::WAVEFORM_QUEUE)));
+
+    // Play counts per track over time (see plays.rs)
+    static PLAYS_BY_HOUR: RefCell<StableBTreeMap<(u64, u64), PlayBucket, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::PLAYS_BY_HOUR))); // (track_id, hour start ms)
+    static PLAYS_BY_DAY: RefCell<StableBTreeMap<(u64, u64), PlayBucket, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::PLAYS_BY_DAY))); // (track_id, day start ms)
+
+    // Daily and monthly analytics rollups (see metrics.rs)
+    static METRICS: RefCell<StableBTreeMap<metrics::SeriesKey, MetricTotals, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::METRICS)));
+    static ACTIVE_DAYS: RefCell<StableBTreeMap<Principal, ActiveDays, Memory>> = RefCell::new(StableBTreeMap::init(memory::get(memory::ACTIVE_DAYS)));
+}
+
+const MAX_STATEMENT_PAGE: u32 = 100;
+
+const DEFAULT_BANNED_KEYWORDS: [&str; 5] = ["spam", "scam", "fake", "copyright", "illegal"];
+
+fn next_id(counter: IdCounter) -> u64 {
+    ID_COUNTERS.with(|c| {
+        let mut c = c.borrow_mut();
+        let id = c.get(&(counter as u8)).unwrap_or(1);
+        c.insert(counter as u8, id + 1);
+        id
+    })
+}
+
+// Every store of versioned records, keyed by a stable name for SCHEMA_VERSIONS.
+fn stored_collections() -> Vec<migration::Collection> {
+    vec![
+        migration::collection("artists", &ARTISTS),
+        migration::collection("tracks", &TRACKS),
+        migration::collection("track_versions", &TRACK_VERSIONS),
+        migration::collection("activity_log", &ACTIVITY_LOG),
+        migration::collection("collab_requests", &COLLAB_REQUESTS),
+        migration::collection("tasks", &TASKS),
+        migration::collection("users", &USERS),
+        migration::collection("user_activity_log", &USER_ACTIVITY_LOG),
+        migration::collection("notifications", &NOTIFICATIONS),
+        migration::collection("playlists", &PLAYLISTS),
+        migration::collection("play_counts", &PLAY_COUNTS),
+        migration::collection("messages", &MESSAGES),
+        migration::collection("reports", &REPORTS),
+        migration::collection("track_licenses", &TRACK_LICENSES),
+        migration::collection("rate_limits", &RATE_LIMITS),
+        migration::collection("rate_limit_policies", &RATE_LIMIT_POLICIES),
+        migration::collection("audit_log", &AUDIT_LOG),
+        migration::collection("moderation_queue", &MODERATION_QUEUE),
+        migration::collection("suspensions", &SUSPENSIONS),
+        migration::collection("suspension_appeals", &SUSPENSION_APPEALS),
+        migration::collection("workflow_steps", &WORKFLOW_STEPS),
+        migration::collection("collaboration_sessions", &COLLABORATION_SESSIONS),
+        migration::collection("workflow_templates", &WORKFLOW_TEMPLATES),
+        migration::collection("track_file_catalog", &TRACK_FILES),
+        migration::collection("royalty_entries", &ROYALTY_ENTRIES),
+        migration::collection("withdrawals", &WITHDRAWALS),
+        migration::collection("upload_sessions", &UPLOAD_SESSIONS),
+        migration::collection("storage_usage", &STORAGE_USAGE),
+        migration::collection("waveforms", &WAVEFORMS),
+        migration::collection("stored_contents", &STORED_CONTENTS),
+        migration::collection("plays_by_hour", &PLAYS_BY_HOUR),
+        migration::collection("plays_by_day", &PLAYS_BY_DAY),
+        migration::collection("metrics", &METRICS),
+        migration::collection("active_days", &ACTIVE_DAYS),
+        migration::collection("follow_counts", &FOLLOW_COUNTS),
+        migration::collection("referral_codes", &REFERRAL_CODES),
+        migration::collection("referral_withdrawals", &REFERRAL_WITHDRAWALS),
+    ]
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    BANNED_KEYWORDS.with(|keywords| {
+        let mut keywords = keywords.borrow_mut();
+        for keyword in DEFAULT_BANNED_KEYWORDS {
+            keywords.insert(keyword.to_string(), ());
+        }
+    });
+    migration::stamp(&stored_collections());
+    bootstrap_super_admin(caller());
+    apply_init_args(args);
+    uploads::start_pruning();
+    rate_limits::start_pruning();
+    plays::start_pruning();
+    search::rebuild();
+    indexes::rebuild();
+    follows::rebuild();
+    metrics::backfill();
+    metrics::backfill_registrations();
+    royalties::index_payouts();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    migration::run(&stored_collections());
+    royalties::open_ledger();
+    royalties::index_payouts();
+    uploads::move_legacy_files();
+    uploads::move_inline_data();
+    uploads::index_contents();
+    uploads::index_charges();
+    plays::count_listeners();
+    search::rebuild();
+    indexes::rebuild();
+    follows::rebuild();
+    metrics::backfill();
+    metrics::backfill_registrations();
+    // Canisters installed before the super-admin existed get the upgrading controller
+    if config().super_admin.is_none() {
+        bootstrap_super_admin(caller());
+    }
+    apply_init_args(args);
+    // Timers don't survive upgrades, so start again the ones `init` started
+    uploads::start_pruning();
+    rate_limits::start_pruning();
+    plays::start_pruning();
+    waveforms::resume();
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    if let Some(ledger) = args.and_then(|a| a.ledger_canister_id) {
+        update_config(|c| c.ledger_canister_id = Some(ledger));
+    }
+}
+
+fn config() -> Config {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn update_config(f: impl FnOnce(&mut Config)) {
+    CONFIG.with(|c| {
+        let mut c = c.borrow_mut();
+        let mut config = c.get().clone();
+        f(&mut config);
+        c.set(config).expect("failed to write config");
+    });
+}
+
+fn ledger_canister() -> Result<Principal, ApiError> {
+    config().ledger_canister_id.ok_or_else(|| ApiError::Ledger("no ledger canister configured".to_string()))
+}
+
+#[ic_cdk::query]
+fn get_payment_config() -> PaymentConfig {
+    PaymentConfig {
+        ledger_canister_id: config().ledger_canister_id,
+        royalty_pool: ledger::Account::royalty_pool(),
+    }
+}
+
+#[ic_cdk::update]
+fn set_ledger_canister(ledger: Principal) -> Result<(), ApiError> {
+    let admin = require_admin()?;
+    update_config(|c| c.ledger_canister_id = Some(ledger));
+    log_admin_action(admin, "set_ledger_canister", "Config", &ledger.to_text(), None);
+    Ok(())
 }
 
-/// Max file size: 10MB
-const MAX_FILE_SIZE: usize = 10 * 1024 * 1024;
+fn bootstrap_super_admin(controller: Principal) {
+    update_config(|c| c.super_admin = Some(controller));
+    log_admin_action(
+        controller,
+        "bootstrap_super_admin",
+        "User",
+        &controller.to_text(),
+        Some("Installing controller set as super-admin".to_string()),
+    );
+}
 
 #[ic_cdk::query]
 fn greet(name: String) -> String {
@@ -378,396 +866,632 @@ fn greet(name: String) -> String {
 
 // Artist CRUD
 #[ic_cdk::update]
-fn register_artist(name: String, bio: String, social: Option<String>, profile_image_url: Option<String>, links: Option<Vec<String>>) -> Option<Artist> {
+fn register_artist(name: String, bio: String, social: Option<String>, profile_image_url: Option<String>, links: Option<Vec<String>>) -> Result<Artist, ApiError> {
     if name.trim().is_empty() {
-        return None;
+        return Err(ApiError::validation("name", "must not be empty"));
     }
     let principal = caller();
-    ARTISTS.with(|artists| {
-        ARTIST_ID.with(|id| {
-            let mut id_mut = id.borrow_mut();
-            let artist = Artist {
-                id: *id_mut,
-                name: name.clone(),
-                bio: bio.clone(),
-                social: social.clone(),
-                royalty_balance: 0,
-                profile_image_url: profile_image_url.clone(),
-                links: links.clone(),
-                user_principal: principal,
-            };
-            artists.borrow_mut().push(artist.clone());
-            *id_mut += 1;
-            let now = ic_cdk::api::time() / 1_000_000;
-            log_user_activity(principal, "register_artist", now, &format!("Registered artist: {}", name));
-            Some(artist)
-        })
-    })
+    ensure_not_suspended(principal)?;
+    let artist = Artist {
+        id: next_id(IdCounter::Artist),
+        name: name.clone(),
+        bio,
+        social,
+        royalty_balance: 0,
+        profile_image_url,
+        links,
+        user_principal: principal,
+    };
+    ARTISTS.with(|artists| artists.borrow_mut().insert(artist.id, artist.clone()));
+    search::index_artist(&artist);
+    indexes::index_artist(&artist);
+    let now = ic_cdk::api::time() / 1_000_000;
+    log_user_activity(principal, "register_artist", now, &format!("Registered artist: {}", name));
+    Ok(artist)
 }
 
 #[ic_cdk::query]
 fn get_artist(id: u64) -> Option<Artist> {
-    ARTISTS.with(|artists| artists.borrow().iter().find(|a| a.id == id).cloned())
+    ARTISTS.with(|artists| artists.borrow().get(&id))
 }
 
 #[ic_cdk::update]
-fn update_artist(id: u64, name: String, bio: String, social: Option<String>, profile_image_url: Option<String>, links: Option<Vec<String>>) -> Option<Artist> {
+fn update_artist(id: u64, name: String, bio: String, social: Option<String>, profile_image_url: Option<String>, links: Option<Vec<String>>) -> Result<Artist, ApiError> {
     let principal = caller();
-    ARTISTS.with(|artists| {
-        let mut artists = artists.borrow_mut();
-        if let Some(artist) = artists.iter_mut().find(|a| a.id == id) {
-            artist.name = name.clone();
-            artist.bio = bio.clone();
-            artist.social = social.clone();
-            artist.profile_image_url = profile_image_url.clone();
-            artist.links = links.clone();
-            let now = ic_cdk::api::time() / 1_000_000;
-            log_user_activity(principal, "update_artist", now, &format!("Updated artist: {}", name));
-            return Some(artist.clone());
-        }
-        None
-    })
+    let mut artist = get_artist(id).ok_or_else(|| ApiError::not_found("artist", id))?;
+    if artist.user_principal != principal {
+        return Err(ApiError::unauthorized("caller does not own this artist"));
+    }
+    if name.trim().is_empty() {
+        return Err(ApiError::validation("name", "must not be empty"));
+    }
+    artist.name = name.clone();
+    artist.bio = bio;
+    artist.social = social;
+    artist.profile_image_url = profile_image_url;
+    artist.links = links;
+    ARTISTS.with(|artists| artists.borrow_mut().insert(id, artist.clone()));
+    search::index_artist(&artist);
+    let now = ic_cdk::api::time() / 1_000_000;
+    log_user_activity(principal, "update_artist", now, &format!("Updated artist: {}", name));
+    Ok(artist)
 }
 
 #[ic_cdk::query]
-fn list_artists() -> Vec<Artist> {
-    ARTISTS.with(|artists| artists.borrow().clone())
+fn list_artists(page: Option<PageRequest>) -> Result<Page<Artist>, ApiError> {
+    let artists = ARTISTS.with(|artists| artists.borrow().iter().collect());
+    paging::page(artists, page, SortKey::CreatedAt, false)
 }
 
 // Track CRUD
-#[ic_cdk::update]
-fn create_track(title: String, description: String, contributors: Vec<u64>) -> Option<Track> {
-    if title.trim().is_empty() || description.trim().is_empty() || contributors.is_empty() {
-        return None;
+
+// Checks a track's title, description and contributors, returning the contributing artists
+fn validate_track_details(title: &str, description: &str, contributors: &[u64]) -> Result<Vec<Artist>, ApiError> {
+    if title.trim().is_empty() {
+        return Err(ApiError::validation("title", "must not be empty"));
+    }
+    if description.trim().is_empty() {
+        return Err(ApiError::validation("description", "must not be empty"));
+    }
+    if contributors.is_empty() {
+        return Err(ApiError::validation("contributors", "at least one contributor is required"));
     }
+    contributors.iter().map(|&cid| get_artist(cid).ok_or_else(|| ApiError::not_found("artist", cid))).collect()
+}
+
+#[ic_cdk::update]
+fn create_track(title: String, description: String, contributors: Vec<u64>) -> Result<Track, ApiError> {
+    let contributor_artists = validate_track_details(&title, &description, &contributors)?;
+    let creator = caller();
+    let creator_id = require_user()?;
     let now = ic_cdk::api::time() / 1_000_000;
-    let contributors_for_log = contributors.clone();
-    let track_id = TRACK_ID.with(|id| {
-        let mut id_mut = id.borrow_mut();
-        let current_id = *id_mut;
-        *id_mut += 1;
-        current_id
-    });
+    let track_id = next_id(IdCounter::Track);
     
     // Check content for banned words
     let content_to_check = format!("{} {}", title, description);
     auto_flag_content_if_needed(ModerationTargetType::Track, track_id.to_string(), &content_to_check);
     
-    let creator = caller();
-    TRACKS.with(|tracks| {
-        let mut roles = vec![];
-        for &cid in &contributors {
-            roles.push((cid, TrackRole::Owner));
-        }
-        let track = Track {
-            id: track_id,
-            title: title.clone(),
-            description: description.clone(),
-            contributors: contributors.clone(),
-            version: 1,
-            splits: None,
-            comments: vec![],
-            payments: vec![],
-            visibility: TrackVisibility::Public,
-            invited: vec![],
-            roles,
-            ratings: vec![],
-            tags: vec![],
-            genre: None,
-            play_count: 0,
-            downloadable: true,
-        };
-        tracks.borrow_mut().push(track.clone());
-        // Store initial version
-        TRACK_VERSIONS.with(|tv| {
-            let mut tv = tv.borrow_mut();
-            let version = TrackVersion {
-                version: 1,
-                title,
-                description,
-                contributors,
-                changed_by: creator,
-                changed_at: now,
-                change_description: Some("Initial version".to_string()),
-            };
-            tv.push((track.id, vec![version]));
-        });
-        // Log activity for each contributor
-        for &cid in &contributors_for_log {
-            log_activity(cid, "create_track", now, &format!("Track {} created", track.id));
+    // The creator and every contributing artist's owner get the Owner role, keyed by user id
+    let mut roles: Vec<(u64, TrackRole)> = vec![(creator_id, TrackRole::Owner)];
+    for artist in &contributor_artists {
+        if let Some(uid) = get_user_id_by_principal(artist.user_principal) {
+            if !roles.iter().any(|(id, _)| *id == uid) {
+                roles.push((uid, TrackRole::Owner));
+            }
         }
-        Some(track)
-    })
+    }
+    let track = Track {
+        id: track_id,
+        title: title.clone(),
+        description: description.clone(),
+        contributors: contributors.clone(),
+        version: 1,
+        splits: None,
+        comments: vec![],
+        payments: vec![],
+        visibility: TrackVisibility::Public,
+        invited: vec![],
+        roles,
+        ratings: vec![],
+        tags: vec![],
+        genre: None,
+        play_count: 0,
+        downloadable: true,
+        pending_splits: None,
+        duration_ms: None,
+        unique_listeners: Some(0),
+        referral_share_pct: None,
+    };
+    save_track(&track);
+    // Store initial version
+    let version = TrackVersion {
+        version: 1,
+        title,
+        description,
+        contributors,
+        changed_by: creator,
+        changed_at: now,
+        change_description: Some("Initial version".to_string()),
+        files: Some(vec![]),
+    };
+    TRACK_VERSIONS.with(|tv| tv.borrow_mut().insert((track_id, 1), version));
+    // Log activity for each contributor
+    for &cid in &track.contributors {
+        log_activity(cid, "create_track", now, &format!("Track {} created", track.id));
+    }
+    Ok(track)
 }
 
 #[ic_cdk::query]
 fn get_track(id: u64) -> Option<Track> {
-    TRACKS.with(|tracks| tracks.borrow().iter().find(|t| t.id == id).cloned())
+    permissions::visible_track(id)
 }
 
+// Edits the track's details as a new version
 #[ic_cdk::update]
-fn update_track(id: u64, title: String, description: String, contributors: Vec<u64>, version: u32) -> Option<Track> {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == id) {
-            track.title = title;
-            track.description = description;
-            track.contributors = contributors;
-            track.version = version;
-            return Some(track.clone());
-        }
-        None
-    })
+fn update_track(id: u64, title: String, description: String, contributors: Vec<u64>) -> Result<Track, ApiError> {
+    let (track, _) = record_version(TrackOperation::UpdateMetadata, id, title, description, contributors, None)?;
+    Ok(track)
 }
 
 #[ic_cdk::query]
-fn list_tracks() -> Vec<Track> {
-    TRACKS.with(|tracks| tracks.borrow().clone())
+fn list_tracks(page: Option<PageRequest>) -> Result<Page<Track>, ApiError> {
+    page_of_tracks(permissions::visible_tracks(|_| true), page)
+}
+
+fn page_of_tracks(tracks: Vec<Track>, page: Option<PageRequest>) -> Result<Page<Track>, ApiError> {
+    paging::page(tracks.into_iter().map(|t| (t.id, t)).collect(), page, SortKey::CreatedAt, false)
 }
 
-// Add/Update splits for a track
+// Proposes new splits; they take effect once every owner has approved them.
+// The proposer's approval is implied, so a sole owner's splits apply immediately.
 #[ic_cdk::update]
-fn set_track_splits(track_id: u64, splits: Vec<Split>) -> Option<Track> {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.splits = Some(splits);
-            return Some(track.clone());
-        }
-        None
-    })
+fn set_track_splits(track_id: u64, splits: Vec<Split>) -> Result<Track, ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::SetSplits)?;
+    royalties::validate_splits(&splits)?;
+    let proposer = require_user()?;
+    track.pending_splits = Some(SplitProposal { splits, proposed_by: proposer, approvals: vec![proposer] });
+    royalties::apply_if_approved(&mut track);
+    save_track(&track);
+    Ok(track)
+}
+
+#[ic_cdk::update]
+fn approve_track_splits(track_id: u64) -> Result<Track, ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::SetSplits)?;
+    let approver = require_user()?;
+    let proposal = track
+        .pending_splits
+        .as_mut()
+        .ok_or_else(|| ApiError::not_found("split proposal", track_id))?;
+    if !proposal.approvals.contains(&approver) {
+        proposal.approvals.push(approver);
+    }
+    royalties::apply_if_approved(&mut track);
+    save_track(&track);
+    Ok(track)
 }
 
 #[ic_cdk::query]
 fn get_track_splits(track_id: u64) -> Option<Vec<Split>> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).and_then(|t| t.splits.clone())
-    })
+    permissions::visible_track(track_id).and_then(|t| t.splits)
 }
 
 // Add a comment to a track
 #[ic_cdk::update]
-fn add_comment(track_id: u64, commenter: u64, text: String) -> Option<Track> {
+fn add_comment(track_id: u64, text: String) -> Result<Track, ApiError> {
+    rate_limits::enforce(RateLimitedMethod::AddComment)?;
+    let commenter = require_user()?;
+    if text.trim().is_empty() {
+        return Err(ApiError::validation("text", "must not be empty"));
+    }
+    let mut track = permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
     let now = ic_cdk::api::time() / 1_000_000;
-    
+
     // Check comment for banned words
     auto_flag_content_if_needed(ModerationTargetType::Comment, format!("track_{}_comment_{}", track_id, now), &text);
-    
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.comments.push(Comment { commenter, text: text.clone() });
-            log_activity(commenter, "add_comment", now, &format!("Commented on track {}: {}", track_id, text));
-            return Some(track.clone());
-        }
-        None
-    })
+
+    track.comments.push(Comment { commenter, text: text.clone() });
+    save_track(&track);
+    metrics::track_event(&track, metrics::Counter::Comments);
+    log_activity(commenter, "add_comment", now, &format!("Commented on track {}: {}", track_id, text));
+    Ok(track)
 }
 
 #[ic_cdk::query]
 fn list_comments(track_id: u64) -> Vec<Comment> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).map(|t| t.comments.clone()).unwrap_or_default()
-    })
+    permissions::visible_track(track_id).map(|t| t.comments).unwrap_or_default()
 }
 
 // Add a new version to a track
 #[ic_cdk::update]
-fn add_track_version(track_id: u64, title: String, description: String, contributors: Vec<u64>, change_description: Option<String>) -> Option<TrackVersion> {
+fn add_track_version(track_id: u64, title: String, description: String, contributors: Vec<u64>, change_description: Option<String>) -> Result<TrackVersion, ApiError> {
+    let (_, version) = record_version(TrackOperation::AddVersion, track_id, title, description, contributors, change_description)?;
+    Ok(version)
+}
+
+// Stores the details as the track's next version; changing the contributors takes an owner
+fn record_version(op: TrackOperation, track_id: u64, title: String, description: String, contributors: Vec<u64>, change_description: Option<String>) -> Result<(Track, TrackVersion), ApiError> {
+    let mut track = permissions::authorize(track_id, op)?;
+    if contributors != track.contributors {
+        permissions::authorize(track_id, TrackOperation::SetContributors)?;
+    }
+    validate_track_details(&title, &description, &contributors)?;
     let now = ic_cdk::api::time() / 1_000_000;
     let changer = caller();
-    TRACK_VERSIONS.with(|tv| {
-        let mut tv = tv.borrow_mut();
-        if let Some((id, versions)) = tv.iter_mut().find(|(tid, _)| *tid == track_id) {
-            let new_version_num = versions.len() as u32 + 1;
-            let version = TrackVersion {
-                version: new_version_num,
-                title: title.clone(),
-                description: description.clone(),
-                contributors: contributors.clone(),
-                changed_by: changer,
-                changed_at: now,
-                change_description,
-            };
-            versions.push(version.clone());
-            // Update the main track
-            TRACKS.with(|tracks| {
-                if let Some(track) = tracks.borrow_mut().iter_mut().find(|t| t.id == track_id) {
-                    track.title = title;
-                    track.description = description;
-                    track.contributors = contributors;
-                    track.version = new_version_num;
-                }
-            });
-            Some(version)
-        } else {
-            None
-        }
-    })
+    let new_version_num = latest_version_number(track_id).unwrap_or(0) + 1;
+    let version = TrackVersion {
+        version: new_version_num,
+        title: title.clone(),
+        description: description.clone(),
+        contributors: contributors.clone(),
+        changed_by: changer,
+        changed_at: now,
+        change_description,
+        files: Some(uploads::snapshot(track_id)),
+    };
+    TRACK_VERSIONS.with(|tv| tv.borrow_mut().insert((track_id, new_version_num), version.clone()));
+    // Update the main track
+    track.title = title;
+    track.description = description;
+    track.contributors = contributors;
+    track.version = new_version_num;
+    save_track(&track);
+    Ok((track, version))
 }
 
 #[ic_cdk::query]
 fn get_track_versions(track_id: u64) -> Vec<TrackVersion> {
+    let Some(track) = permissions::visible_track(track_id) else {
+        return vec![];
+    };
+    let viewer = permissions::viewer();
     TRACK_VERSIONS.with(|tv| {
-        tv.borrow().iter().find(|(id, _)| *id == track_id).map(|(_, versions)| versions.clone()).unwrap_or_default()
+        tv.borrow()
+            .range((track_id, 0)..=(track_id, u32::MAX))
+            .map(|(_, v)| permissions::redact_version(&track, v, viewer))
+            .collect()
     })
 }
 
+// Highest stored version number for a track, if the track has any history
+fn latest_version_number(track_id: u64) -> Option<u32> {
+    TRACK_VERSIONS.with(|tv| {
+        tv.borrow().range((track_id, 0)..=(track_id, u32::MAX)).last().map(|((_, version), _)| version)
+    })
+}
+
+// Paging for list and search queries (see paging.rs)
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum SortKey {
+    CreatedAt,
+    PlayCount,
+    Rating,
+    Title,
+    Relevance, // search only
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct PageRequest {
+    /// `next_cursor` from the previous page; omit for the first page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+    pub sort: Option<SortKey>,
+    pub descending: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// None on the last page.
+    pub next_cursor: Option<String>,
+    pub total: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum SearchKind {
+    Track,
+    Artist,
+    User,
+}
+
+// Every field is optional except the text, which may be empty when filtering
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SearchQuery {
+    pub text: String,
+    pub kinds: Option<Vec<SearchKind>>, // all kinds when unset or empty
+    pub genre: Option<String>,
+    pub tag: Option<String>,
+    pub contributor: Option<u64>, // artist id
+    pub license_type: Option<LicenseType>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SearchResult {
+    Track(Box<Track>),
+    Artist(Artist),
+    User(User),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SearchHit {
+    pub score: u32,
+    pub result: SearchResult,
+}
+
+// Ranked search over track, artist and user text, with optional track filters (see search.rs)
+#[ic_cdk::query]
+fn search(query: SearchQuery, page: Option<PageRequest>) -> Result<Page<SearchHit>, ApiError> {
+    paging::page(search::search(&query)?, page, SortKey::Relevance, true)
+}
+
 // Search tracks by title (case-insensitive substring)
 #[ic_cdk::query]
-fn search_tracks_by_title(query: String) -> Vec<Track> {
+fn search_tracks_by_title(query: String, page: Option<PageRequest>) -> Result<Page<Track>, ApiError> {
     let q = query.to_lowercase();
-    TRACKS.with(|tracks| {
-        if q.is_empty() {
-            tracks.borrow().clone()
-        } else {
-            tracks.borrow().iter().filter(|t| t.title.to_lowercase().contains(&q)).cloned().collect()
-        }
-    })
+    page_of_tracks(permissions::visible_tracks(|t| q.is_empty() || t.title.to_lowercase().contains(&q)), page)
 }
 
 // Search tracks by contributor (artist id)
 #[ic_cdk::query]
-fn search_tracks_by_contributor(artist_id: u64) -> Vec<Track> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().filter(|t| t.contributors.contains(&artist_id)).cloned().collect()
-    })
+fn search_tracks_by_contributor(artist_id: u64, page: Option<PageRequest>) -> Result<Page<Track>, ApiError> {
+    let candidates = indexes::tracks_by_contributor(artist_id);
+    page_of_tracks(permissions::visible_tracks_among(candidates, |t| t.contributors.contains(&artist_id)), page)
 }
 
 // Delete a track by id
 #[ic_cdk::update]
-fn delete_track(track_id: u64) -> bool {
-    let mut deleted = false;
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        let len_before = tracks.len();
-        tracks.retain(|t| t.id != track_id);
-        deleted = tracks.len() < len_before;
-    });
+fn delete_track(track_id: u64) -> Result<(), ApiError> {
+    let track = permissions::authorize(track_id, TrackOperation::Delete)?;
+    TRACKS.with(|tracks| tracks.borrow_mut().remove(&track_id));
+    indexes::unindex_track(&track);
+    search::remove(SearchKind::Track, track_id);
+    uploads::remove_track_files(track_id);
+    remove_track_versions(track_id);
+    plays::remove_track(track_id);
+    metrics::remove_track(track_id);
+    follows::remove_track(track_id);
+    Ok(())
+}
+
+fn save_track(track: &Track) {
+    if let Some(previous) = TRACKS.with(|tracks| tracks.borrow_mut().insert(track.id, track.clone())) {
+        indexes::unindex_track(&previous);
+    }
+    indexes::index_track(track);
+    search::index_track(track);
+}
+
+fn remove_track_versions(track_id: u64) {
     TRACK_VERSIONS.with(|tv| {
         let mut tv = tv.borrow_mut();
-        tv.retain(|(id, _)| *id != track_id);
+        let keys: Vec<(u64, u32)> = tv.range((track_id, 0)..=(track_id, u32::MAX)).map(|(k, _)| k).collect();
+        for key in keys {
+            tv.remove(&key);
+        }
     });
-    deleted
 }
 
-// Distribute payment for a track
+// Pays for a track through the ledger. The caller must first `icrc2_approve`
+// this canister for `amount` plus the ledger fee. A `referral` code from
+// `share_track` attributes the payment to its sharer. Returns the ledger block index.
 #[ic_cdk::update]
-fn distribute_payment(track_id: u64, payer: u64, amount: u64, timestamp: u64) -> bool {
-    let mut distributed = false;
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            if let Some(splits) = &track.splits {
-                for split in splits {
-                    let share = amount * (split.pct as u64) / 100;
-                    // Debug: log split info
-                    ic_cdk::println!("Distributing {} to artist {} ({}%)", share, split.id, split.pct);
-                    ARTISTS.with(|artists| {
-                        let mut artists = artists.borrow_mut();
-                        if let Some(artist) = artists.iter_mut().find(|a| a.id == split.id) {
-                            artist.royalty_balance += share;
-                            ic_cdk::println!("Updated artist {} balance: {}", artist.id, artist.royalty_balance);
-                        } else {
-                            ic_cdk::println!("Artist {} not found for royalty distribution", split.id);
-                        }
-                    });
-                }
-                track.payments.push(Payment { payer, amount, timestamp });
-                distributed = true;
-                log_activity(payer, "distribute_payment", timestamp, &format!("Paid {} for track {}", amount, track_id));
-            } else {
-                ic_cdk::println!("No splits set for track {}", track_id);
+async fn distribute_payment(track_id: u64, amount: u64, referral: Option<String>) -> Result<u64, ApiError> {
+    let payer_principal = caller();
+    let payer = require_user()?;
+    if amount == 0 {
+        return Err(ApiError::validation("amount", "must be greater than zero"));
+    }
+    let ledger = ledger_canister()?;
+    let track = permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    // Splits are fixed before the await so a concurrent change can't redirect this payment
+    let Some(splits) = track.splits else {
+        return Err(ApiError::validation("track_id", "track has no royalty splits"));
+    };
+    royalties::validate_splits(&splits)?;
+    // Checked before the transfer so a bad code costs the payer nothing; the share is fixed with the splits
+    let referrer = match &referral {
+        Some(code) => referrals::resolve(code, track_id, payer_principal)?,
+        None => None,
+    };
+    let referral_share_pct = track.referral_share_pct.unwrap_or(0);
+    let referrer_cut = referrer.as_ref().map_or(0, |_| referrals::referrer_cut(amount, referral_share_pct));
+    let artists_amount = amount - referrer_cut;
+    royalties::check_credits(
+        &royalties::allocate(artists_amount, &splits),
+        referrer.as_ref().map(|r| (r.sharer, referrer_cut)),
+    )?;
+
+    let from = ledger::Account { owner: payer_principal, subaccount: None };
+    let block_index = ledger::transfer_from(ledger, from, amount, format!("track:{}", track_id).into_bytes())
+        .await
+        .map_err(ApiError::Ledger)?;
+
+    // Nothing was written before the transfer, so a failed transfer leaves no state to roll back.
+    // From here the tokens have moved, so nothing may trap: a credit that fails
+    // anyway (a balance grew during the await) leaves its share in the pool.
+    let timestamp = ic_cdk::api::time() / 1_000_000;
+    if let Some(referrer) = &referrer {
+        let mut earned = 0;
+        if referrer_cut > 0 {
+            let reference = EntryReference::Referral { track_id, block_index, code: referrer.code.clone() };
+            match royalties::credit_referrer(referrer.sharer, referrer_cut, reference) {
+                Ok(_) => earned = referrer_cut,
+                Err(e) => ic_cdk::println!(
+                    "Failed to credit referrer {} for block {}: {:?}; {} tokens stay in the pool",
+                    referrer.sharer, block_index, e, referrer_cut
+                ),
             }
-        } else {
-            ic_cdk::println!("Track {} not found for payment distribution", track_id);
         }
+        referrals::count_purchase(&referrer.code, amount, earned);
+    }
+    // An artist deleted while the transfer was in flight drops out, and the
+    // others' shares grow so the whole amount is still credited
+    let live_splits: Vec<Split> = ARTISTS.with(|artists| {
+        let artists = artists.borrow();
+        splits.into_iter().filter(|s| artists.contains_key(&s.id)).collect()
     });
-    distributed
+    if live_splits.is_empty() {
+        ic_cdk::println!("No split artist left for track {}; {} tokens stay in the pool", track_id, artists_amount);
+    }
+    for (split, (artist_id, share)) in live_splits.iter().zip(royalties::allocate(artists_amount, &live_splits)) {
+        let reference = EntryReference::Payment { track_id, block_index, split_pct: split.pct };
+        match royalties::credit_artist(artist_id, share, reference) {
+            Ok(_) => metrics::artist_revenue(artist_id, share, timestamp),
+            Err(e) => ic_cdk::println!(
+                "Failed to credit artist {} for block {}: {:?}; {} tokens stay in the pool",
+                artist_id, block_index, e, share
+            ),
+        }
+    }
+    // The track may have been deleted while the transfer was in flight
+    TRACKS.with(|tracks| {
+        memory::modify(&mut tracks.borrow_mut(), &track_id, |track| {
+            track.payments.push(Payment { payer, amount, timestamp, block_index: Some(block_index), referral: referrer.map(|r| r.code) });
+        })
+    });
+    metrics::payment(track_id, amount, timestamp);
+    metrics::mark_active(payer_principal, timestamp);
+    log_activity(payer, "distribute_payment", timestamp, &format!("Paid {} for track {} (block {})", amount, track_id, block_index));
+    Ok(block_index)
 }
 
 // View artist royalty balance
 #[ic_cdk::query]
 fn get_royalty_balance(artist_id: u64) -> u64 {
     ARTISTS.with(|artists| {
-        artists.borrow().iter().find(|a| a.id == artist_id).map(|a| a.royalty_balance).unwrap_or(0)
+        artists.borrow().get(&artist_id).map(|a| a.royalty_balance).unwrap_or(0)
+    })
+}
+
+// The artist's owner or an admin may read its royalty records
+fn require_artist_owner_or_admin(artist_id: u64) -> Result<(), ApiError> {
+    let artist = get_artist(artist_id).ok_or_else(|| ApiError::not_found("artist", artist_id))?;
+    if artist.user_principal != caller() && !is_admin(caller()) {
+        return Err(ApiError::unauthorized("only the artist's owner or an admin can view its royalties"));
+    }
+    Ok(())
+}
+
+// Ledger entries for an artist within `[start, end)` (milliseconds), oldest
+// first. Pass the previous page's `next_after` as `after` to continue.
+#[ic_cdk::query]
+fn get_royalty_statement(artist_id: u64, start: Option<u64>, end: Option<u64>, after: Option<u64>, limit: u32) -> Result<RoyaltyStatement, ApiError> {
+    require_artist_owner_or_admin(artist_id)?;
+    let limit = limit.clamp(1, MAX_STATEMENT_PAGE) as usize;
+    Ok(royalties::statement(artist_id, start, end, after, limit))
+}
+
+#[ic_cdk::query]
+fn list_artist_withdrawals(artist_id: u64) -> Result<Vec<Withdrawal>, ApiError> {
+    require_artist_owner_or_admin(artist_id)?;
+    Ok(WITHDRAWALS.with(|w| w.borrow().values().filter(|w| w.artist_id == artist_id).collect()))
+}
+
+// What a track's payments credited to each artist within `[start, end)`; for
+// the track's owners and admins.
+#[ic_cdk::query]
+fn get_track_royalty_report(track_id: u64, start: Option<u64>, end: Option<u64>) -> Result<TrackRoyaltyReport, ApiError> {
+    if !is_admin(caller()) {
+        permissions::authorize(track_id, permissions::TrackOperation::SetSplits)?;
+    }
+    let payouts = royalties::track_payouts(track_id, start, end);
+    Ok(TrackRoyaltyReport {
+        track_id,
+        total_paid: payouts.iter().map(|(_, amount)| amount).sum(),
+        payouts,
     })
 }
 
+// Checks every artist's balance against the sum of their ledger entries
+#[ic_cdk::query]
+fn reconcile_royalties() -> Result<ReconciliationReport, ApiError> {
+    require_admin()?;
+    Ok(royalties::reconcile())
+}
+
 // View payment history for a track
 #[ic_cdk::query]
 fn get_payment_history(track_id: u64) -> Vec<Payment> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).map(|t| t.payments.clone()).unwrap_or_default()
-    })
+    permissions::visible_track(track_id).map(|t| t.payments).unwrap_or_default()
 }
 
 // Set track visibility
 #[ic_cdk::update]
-fn set_track_visibility(track_id: u64, visibility: TrackVisibility) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.visibility = visibility;
-            return true;
-        }
-        false
-    })
+fn set_track_visibility(track_id: u64, visibility: TrackVisibility) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::SetVisibility)?;
+    track.visibility = visibility;
+    save_track(&track);
+    Ok(())
 }
 
 // Get track visibility
 #[ic_cdk::query]
 fn get_track_visibility(track_id: u64) -> Option<TrackVisibility> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).map(|t| t.visibility.clone())
-    })
+    permissions::visible_track(track_id).map(|t| t.visibility)
 }
 
 // Invite user to track
 #[ic_cdk::update]
-fn invite_user(track_id: u64, user_id: u64) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            if !track.invited.contains(&user_id) {
-                track.invited.push(user_id);
-            }
-            return true;
-        }
-        false
-    })
+fn invite_user(track_id: u64, user_id: u64) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::InviteUser)?;
+    if !track.invited.contains(&user_id) {
+        track.invited.push(user_id);
+        save_track(&track);
+    }
+    Ok(())
+}
+
+// Tracks the caller has been invited to and not yet answered
+#[ic_cdk::query]
+fn list_invitations() -> Vec<Track> {
+    let Some(user_id) = permissions::viewer() else {
+        return vec![];
+    };
+    permissions::visible_tracks(|t| t.invited.contains(&user_id))
+}
+
+// Accepting an invite turns it into a Viewer role; owners can raise it with assign_role
+#[ic_cdk::update]
+fn accept_invite(track_id: u64) -> Result<(), ApiError> {
+    let (mut track, user_id) = pending_invite(track_id)?;
+    track.invited.retain(|id| *id != user_id);
+    if permissions::role_of(&track, user_id).is_none() {
+        track.roles.push((user_id, TrackRole::Viewer));
+    }
+    save_track(&track);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn decline_invite(track_id: u64) -> Result<(), ApiError> {
+    let (mut track, user_id) = pending_invite(track_id)?;
+    track.invited.retain(|id| *id != user_id);
+    save_track(&track);
+    Ok(())
+}
+
+fn pending_invite(track_id: u64) -> Result<(Track, u64), ApiError> {
+    let user_id = require_user()?;
+    let track = TRACKS
+        .with(|tracks| tracks.borrow().get(&track_id))
+        .filter(|t| t.invited.contains(&user_id))
+        .ok_or_else(|| ApiError::not_found("invitation", track_id))?;
+    Ok((track, user_id))
 }
 
 // Assign role to user
 #[ic_cdk::update]
-fn assign_role(track_id: u64, user_id: u64, role: TrackRole) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            if let Some(r) = track.roles.iter_mut().find(|(id, _)| *id == user_id) {
-                r.1 = role;
-            } else {
-                track.roles.push((user_id, role));
-            }
-            return true;
-        }
-        false
-    })
+fn assign_role(track_id: u64, user_id: u64, role: TrackRole) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::AssignRole)?;
+    let owners = track.roles.iter().filter(|(_, r)| *r == TrackRole::Owner).count();
+    let demotes_last_owner = role != TrackRole::Owner
+        && owners == 1
+        && permissions::role_of(&track, user_id) == Some(TrackRole::Owner);
+    if demotes_last_owner {
+        return Err(ApiError::validation("role", "a track must keep at least one owner"));
+    }
+    if let Some(r) = track.roles.iter_mut().find(|(id, _)| *id == user_id) {
+        r.1 = role;
+    } else {
+        track.roles.push((user_id, role));
+    }
+    save_track(&track);
+    Ok(())
 }
 
 // Get user role for a track
 #[ic_cdk::query]
 fn get_user_role(track_id: u64, user_id: u64) -> Option<TrackRole> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id)
-            .and_then(|track| track.roles.iter().find(|(id, _)| *id == user_id).map(|(_, role)| role.clone()))
-    })
+    permissions::visible_track(track_id).and_then(|track| permissions::role_of(&track, user_id))
 }
 
 // Helper to log activity
 fn log_activity(user_id: u64, action: &str, timestamp: u64, details: &str) {
     ACTIVITY_LOG.with(|log| {
-        log.borrow_mut().push(Activity {
+        let mut log = log.borrow_mut();
+        let seq = log.len();
+        log.insert(seq, Activity {
             user_id,
             action: action.to_string(),
             timestamp,
@@ -780,260 +1504,365 @@ fn log_activity(user_id: u64, action: &str, timestamp: u64, details: &str) {
 #[ic_cdk::query]
 fn get_user_activity(user_id: u64) -> Vec<Activity> {
     ACTIVITY_LOG.with(|log| {
-        log.borrow().iter().filter(|a| a.user_id == user_id).cloned().collect()
+        log.borrow().values().filter(|a| a.user_id == user_id).collect()
     })
 }
 
-// Fetch recent activity (most recent N)
+// Fetch recent activity, most recent first
 #[ic_cdk::query]
-fn get_recent_activity(count: u32) -> Vec<Activity> {
-    ACTIVITY_LOG.with(|log| {
-        let log = log.borrow();
-        let len = log.len();
-        let start = if len > count as usize { len - count as usize } else { 0 };
-        log[start..].to_vec()
-    })
+fn get_recent_activity(page: Option<PageRequest>) -> Result<Page<Activity>, ApiError> {
+    let activity = ACTIVITY_LOG.with(|log| log.borrow().iter().collect());
+    paging::page(activity, page, SortKey::CreatedAt, true)
 }
 
 // Rate a track
 #[ic_cdk::update]
-fn rate_track(track_id: u64, user_id: u64, rating: u8) -> bool {
-    if rating < 1 || rating > 5 {
-        return false;
+fn rate_track(track_id: u64, rating: u8) -> Result<(), ApiError> {
+    rate_limits::enforce(RateLimitedMethod::RateTrack)?;
+    if !(1..=5).contains(&rating) {
+        return Err(ApiError::validation("rating", "must be between 1 and 5"));
     }
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            if let Some(r) = track.ratings.iter_mut().find(|(uid, _)| *uid == user_id) {
-                r.1 = rating;
-            } else {
-                track.ratings.push((user_id, rating));
-            }
-            return true;
+    let user_id = require_user()?;
+    let mut track = permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    let new_rating = match track.ratings.iter_mut().find(|(uid, _)| *uid == user_id) {
+        Some(r) => {
+            r.1 = rating;
+            false
         }
-        false
-    })
+        None => {
+            track.ratings.push((user_id, rating));
+            true
+        }
+    };
+    save_track(&track);
+    if new_rating {
+        metrics::track_event(&track, metrics::Counter::Ratings);
+    }
+    Ok(())
 }
 
 // Get average rating and count for a track
 #[ic_cdk::query]
 fn get_track_rating(track_id: u64) -> (u32, u8) {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).map(|t| {
-            let count = t.ratings.len() as u32;
-            let sum: u32 = t.ratings.iter().map(|(_, r)| *r as u32).sum();
-            let avg = if count > 0 { (sum / count) as u8 } else { 0 };
-            (count, avg)
-        }).unwrap_or((0, 0))
-    })
+    permissions::visible_track(track_id).map(|t| {
+        let count = t.ratings.len() as u32;
+        let sum: u32 = t.ratings.iter().map(|(_, r)| *r as u32).sum();
+        let avg = sum.checked_div(count).unwrap_or(0) as u8;
+        (count, avg)
+    }).unwrap_or((0, 0))
 }
 
 // Get a user's rating for a track
 #[ic_cdk::query]
 fn get_user_track_rating(track_id: u64, user_id: u64) -> Option<u8> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id)
-            .and_then(|t| t.ratings.iter().find(|(uid, _)| *uid == user_id).map(|(_, r)| *r))
-    })
+    permissions::visible_track(track_id)
+        .and_then(|t| t.ratings.iter().find(|(uid, _)| *uid == user_id).map(|(_, r)| *r))
 }
 
 // Add a tag to a track
 #[ic_cdk::update]
-fn add_tag(track_id: u64, tag: String) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            if !track.tags.contains(&tag) {
-                track.tags.push(tag);
-            }
-            return true;
-        }
-        false
-    })
+fn add_tag(track_id: u64, tag: String) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::ManageTags)?;
+    if !track.tags.contains(&tag) {
+        track.tags.push(tag);
+        save_track(&track);
+    }
+    Ok(())
 }
 
 // Remove a tag from a track
 #[ic_cdk::update]
-fn remove_tag(track_id: u64, tag: String) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.tags.retain(|t| t != &tag);
-            return true;
-        }
-        false
-    })
+fn remove_tag(track_id: u64, tag: String) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::ManageTags)?;
+    track.tags.retain(|t| t != &tag);
+    save_track(&track);
+    Ok(())
 }
 
 // Set genre for a track
 #[ic_cdk::update]
-fn set_genre(track_id: u64, genre: String) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.genre = Some(genre);
-            return true;
-        }
-        false
-    })
+fn set_genre(track_id: u64, genre: String) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::SetGenre)?;
+    track.genre = Some(genre);
+    save_track(&track);
+    Ok(())
 }
 
 // Get genre for a track
 #[ic_cdk::query]
 fn get_genre(track_id: u64) -> Option<String> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).and_then(|t| t.genre.clone())
-    })
+    permissions::visible_track(track_id).and_then(|t| t.genre)
 }
 
 // Search tracks by tag
 #[ic_cdk::query]
-fn search_tracks_by_tag(tag: String) -> Vec<Track> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().filter(|t| t.tags.contains(&tag)).cloned().collect()
-    })
+fn search_tracks_by_tag(tag: String, page: Option<PageRequest>) -> Result<Page<Track>, ApiError> {
+    let candidates = indexes::tracks_with_tag(&tag);
+    page_of_tracks(permissions::visible_tracks_among(candidates, |t| t.tags.contains(&tag)), page)
 }
 
 // Search tracks by genre
 #[ic_cdk::query]
-fn search_tracks_by_genre(genre: String) -> Vec<Track> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().filter(|t| t.genre.as_ref().map(|g| g == &genre).unwrap_or(false)).cloned().collect()
-    })
+fn search_tracks_by_genre(genre: String, page: Option<PageRequest>) -> Result<Page<Track>, ApiError> {
+    let candidates = indexes::tracks_in_genre(&genre);
+    page_of_tracks(permissions::visible_tracks_among(candidates, |t| t.genre.as_ref() == Some(&genre)), page)
 }
 
 // Collaboration Request Endpoints
 #[ic_cdk::update]
-fn send_collab_request(from: u64, to: u64, track_id: u64, message: Option<String>) -> Option<CollabRequest> {
+fn send_collab_request(to: u64, track_id: u64, message: Option<String>) -> Result<CollabRequest, ApiError> {
+    let principal = caller();
+    ensure_not_suspended(principal)?;
+    let from = primary_artist_id(principal).ok_or_else(|| ApiError::unauthorized("caller has no artist profile"))?;
+    get_artist(to).ok_or_else(|| ApiError::not_found("artist", to))?;
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
     let now = ic_cdk::api::time() / 1_000_000;
     COLLAB_REQUESTS.with(|requests| {
-        COLLAB_REQUEST_ID.with(|id| {
-            let mut id_mut = id.borrow_mut();
-            // Prevent duplicate pending requests
-            if requests.borrow().iter().any(|r| r.from == from && r.to == to && r.track_id == track_id && r.status == CollabRequestStatus::Pending) {
-                return None;
-            }
-            let req = CollabRequest {
-                id: *id_mut,
-                from,
-                to,
-                track_id,
-                message,
-                status: CollabRequestStatus::Pending,
-                timestamp: now,
-            };
-            requests.borrow_mut().push(req.clone());
-            *id_mut += 1;
-            Some(req)
-        })
+        let mut requests = requests.borrow_mut();
+        // Prevent duplicate pending requests
+        if requests.values().any(|r| r.from == from && r.to == to && r.track_id == track_id && r.status == CollabRequestStatus::Pending) {
+            return Err(ApiError::conflict("a pending request for this track already exists"));
+        }
+        let req = CollabRequest {
+            id: next_id(IdCounter::CollabRequest),
+            from,
+            to,
+            track_id,
+            message,
+            status: CollabRequestStatus::Pending,
+            timestamp: now,
+        };
+        requests.insert(req.id, req.clone());
+        Ok(req)
     })
 }
 
 #[ic_cdk::update]
-fn respond_collab_request(request_id: u64, accept: bool) -> Option<CollabRequest> {
-    COLLAB_REQUESTS.with(|requests| {
-        let mut requests = requests.borrow_mut();
-        if let Some(req) = requests.iter_mut().find(|r| r.id == request_id && r.status == CollabRequestStatus::Pending) {
-            req.status = if accept { CollabRequestStatus::Accepted } else { CollabRequestStatus::Declined };
-            return Some(req.clone());
-        }
-        None
-    })
+fn respond_collab_request(request_id: u64, accept: bool) -> Result<CollabRequest, ApiError> {
+    let mut req = COLLAB_REQUESTS
+        .with(|requests| requests.borrow().get(&request_id))
+        .ok_or_else(|| ApiError::not_found("collab request", request_id))?;
+    if !owns_artist(caller(), req.to) {
+        return Err(ApiError::unauthorized("only the invited artist can respond"));
+    }
+    if req.status != CollabRequestStatus::Pending {
+        return Err(ApiError::conflict("request has already been answered"));
+    }
+    req.status = if accept { CollabRequestStatus::Accepted } else { CollabRequestStatus::Declined };
+    COLLAB_REQUESTS.with(|requests| requests.borrow_mut().insert(request_id, req.clone()));
+    Ok(req)
 }
 
 #[ic_cdk::query]
 fn list_collab_requests_for_user(user_id: u64) -> Vec<CollabRequest> {
     COLLAB_REQUESTS.with(|requests| {
-        requests.borrow().iter().filter(|r| r.to == user_id || r.from == user_id).cloned().collect()
+        requests.borrow().values().filter(|r| r.to == user_id || r.from == user_id).collect()
     })
 }
 
 // Task Management Endpoints
 #[ic_cdk::update]
-fn create_task(track_id: u64, assigned_to: u64, description: String) -> Option<Task> {
+fn create_task(track_id: u64, assigned_to: u64, description: String) -> Result<Task, ApiError> {
+    require_user()?;
     if description.trim().is_empty() {
-        return None;
+        return Err(ApiError::validation("description", "must not be empty"));
     }
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
     let now = ic_cdk::api::time() / 1_000_000;
-    TASKS.with(|tasks| {
-        TASK_ID.with(|id| {
-            let mut id_mut = id.borrow_mut();
-            let task = Task {
-                id: *id_mut,
-                track_id,
-                assigned_to,
-                description,
-                status: TaskStatus::Open,
-                created_at: now,
-                updated_at: now,
-            };
-            tasks.borrow_mut().push(task.clone());
-            *id_mut += 1;
-            Some(task)
-        })
-    })
+    let task = Task {
+        id: next_id(IdCounter::Task),
+        track_id,
+        assigned_to,
+        description,
+        status: TaskStatus::Open,
+        created_at: now,
+        updated_at: now,
+    };
+    TASKS.with(|tasks| tasks.borrow_mut().insert(task.id, task.clone()));
+    Ok(task)
 }
 
 #[ic_cdk::update]
-fn update_task_status(task_id: u64, status: TaskStatus) -> Option<Task> {
+fn update_task_status(task_id: u64, status: TaskStatus) -> Result<Task, ApiError> {
+    let task = TASKS.with(|tasks| tasks.borrow().get(&task_id)).ok_or_else(|| ApiError::not_found("task", task_id))?;
+    // The assigned artist's owner may move their own task along
+    if get_artist(task.assigned_to).is_none_or(|a| a.user_principal != caller()) {
+        permissions::authorize(task.track_id, TrackOperation::ManageWorkflow)?;
+    }
     let now = ic_cdk::api::time() / 1_000_000;
     TASKS.with(|tasks| {
-        let mut tasks = tasks.borrow_mut();
-        if let Some(task) = tasks.iter_mut().find(|t| t.id == task_id) {
+        memory::modify(&mut tasks.borrow_mut(), &task_id, |task| {
             task.status = status;
             task.updated_at = now;
-            return Some(task.clone());
-        }
-        None
+            task.clone()
+        })
     })
+    .ok_or_else(|| ApiError::not_found("task", task_id))
+}
+
+#[ic_cdk::query]
+fn list_tasks_for_track(track_id: u64) -> Vec<Task> {
+    if permissions::visible_track(track_id).is_none() {
+        return vec![];
+    }
+    TASKS.with(|tasks| {
+        tasks.borrow().values().filter(|t| t.track_id == track_id).collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_tasks_for_user(user_id: u64) -> Vec<Task> {
+    TASKS.with(|tasks| {
+        tasks.borrow().values().filter(|t| t.assigned_to == user_id).collect()
+    })
+}
+
+// Sends `amount` of the artist's royalties to the owner's principal, less the
+// ledger fee. The balance is reserved before the transfer and restored if it fails.
+#[ic_cdk::update]
+async fn withdraw_royalties(artist_id: u64, amount: u64) -> Result<u64, ApiError> {
+    let ledger = ledger_canister()?;
+    let artist = get_artist(artist_id).ok_or_else(|| ApiError::not_found("artist", artist_id))?;
+    if artist.user_principal != caller() {
+        return Err(ApiError::unauthorized("caller does not own this artist"));
+    }
+    let fee = ledger::fee(ledger).await.map_err(ApiError::Ledger)?;
+    if amount <= fee {
+        return Err(ApiError::validation("amount", "must be greater than the ledger fee"));
+    }
+
+    // Debiting before the await reserves the amount; the balance is re-checked here
+    // because it may have moved while the fee was fetched
+    let withdrawal_id = next_id(IdCounter::Withdrawal);
+    royalties::debit_artist(artist_id, amount, EntryReference::Withdrawal { withdrawal_id })?;
+    let withdrawal = Withdrawal {
+        id: withdrawal_id,
+        artist_id,
+        amount,
+        fee,
+        to: artist.user_principal,
+        requested_at: ic_cdk::api::time() / 1_000_000,
+        status: WithdrawalStatus::Pending,
+    };
+    WITHDRAWALS.with(|w| w.borrow_mut().insert(withdrawal_id, withdrawal));
+
+    let to = ledger::Account { owner: artist.user_principal, subaccount: None };
+    let memo = format!("withdraw:{}", withdrawal_id).into_bytes();
+    let result = ledger::transfer(ledger, to, amount - fee, memo).await;
+    let status = match &result {
+        Ok(block_index) => WithdrawalStatus::Completed { block_index: *block_index },
+        Err(e) => {
+            // If the artist was deleted meanwhile, the debit stands and the tokens stay in the pool
+            if let Err(err) = royalties::credit_artist(artist_id, amount, EntryReference::WithdrawalReversal { withdrawal_id }) {
+                ic_cdk::println!("Could not reverse withdrawal {}: {:?}", withdrawal_id, err);
+            }
+            WithdrawalStatus::Failed { reason: e.clone() }
+        }
+    };
+    WITHDRAWALS.with(|w| memory::modify(&mut w.borrow_mut(), &withdrawal_id, |w| w.status = status));
+    let block_index = result.map_err(ApiError::Ledger)?;
+    let now = ic_cdk::api::time() / 1_000_000;
+    log_activity(artist_id, "withdraw_royalties", now, &format!("Withdrew {} tokens (block {})", amount, block_index));
+    Ok(block_index)
+}
+
+// A sharer's code for one track and what came through it
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ReferralCode {
+    pub code: String,
+    pub track_id: u64,
+    pub sharer: Principal,
+    pub created_at: u64,
+    pub plays: u64, // counted plays
+    pub purchases: u64,
+    pub revenue: u64, // paid through the code
+    pub earned: u64, // the sharer's referral share of that
+}
+
+// Referral code for sharing the track, attributing plays and payments to the caller
+#[ic_cdk::update]
+fn share_track(track_id: u64) -> Result<ReferralCode, ApiError> {
+    require_user()?;
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    Ok(referrals::share(caller(), track_id))
+}
+
+// The caller's referral codes with their stats
+#[ic_cdk::query]
+fn list_referral_codes() -> Vec<ReferralCode> {
+    referrals::codes_of(caller())
+}
+
+// Gives sharers `pct`% of payments made through their codes; `None` turns it off
+#[ic_cdk::update]
+fn set_referral_share(track_id: u64, pct: Option<u8>) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::SetReferralShare)?;
+    if let Some(pct) = pct {
+        referrals::validate_share_pct(pct)?;
+    }
+    track.referral_share_pct = pct;
+    save_track(&track);
+    Ok(())
 }
 
 #[ic_cdk::query]
-fn list_tasks_for_track(track_id: u64) -> Vec<Task> {
-    TASKS.with(|tasks| {
-        tasks.borrow().iter().filter(|t| t.track_id == track_id).cloned().collect()
-    })
+fn get_referral_balance() -> u64 {
+    royalties::referrer_balance(caller())
 }
 
+// The caller's referral withdrawals, oldest first
 #[ic_cdk::query]
-fn list_tasks_for_user(user_id: u64) -> Vec<Task> {
-    TASKS.with(|tasks| {
-        tasks.borrow().iter().filter(|t| t.assigned_to == user_id).cloned().collect()
-    })
+fn list_referral_withdrawals() -> Vec<ReferralWithdrawal> {
+    let referrer = caller();
+    REFERRAL_WITHDRAWALS.with(|w| w.borrow().range((referrer, 0)..=(referrer, u64::MAX)).map(|(_, w)| w).collect())
 }
 
-// Royalty withdrawal endpoint
+// Sends `amount` of the caller's referral earnings, less the ledger fee, to
+// their account. Returns the ledger block index.
 #[ic_cdk::update]
-fn withdraw_royalties(artist_id: u64, amount: u64) -> bool {
-    if amount == 0 {
-        return false;
+async fn withdraw_referral_earnings(amount: u64) -> Result<u64, ApiError> {
+    let referrer = caller();
+    let ledger = ledger_canister()?;
+    let fee = ledger::fee(ledger).await.map_err(ApiError::Ledger)?;
+    if amount <= fee {
+        return Err(ApiError::validation("amount", "must be greater than the ledger fee"));
     }
-    ARTISTS.with(|artists| {
-        let mut artists = artists.borrow_mut();
-        if let Some(artist) = artists.iter_mut().find(|a| a.id == artist_id) {
-            if artist.royalty_balance >= amount {
-                artist.royalty_balance -= amount;
-                let now = ic_cdk::api::time() / 1_000_000;
-                log_activity(artist_id, "withdraw_royalties", now, &format!("Withdrew {} tokens", amount));
-                // In production, integrate with ICP ledger here
-                return true;
+    // Debiting before the await reserves the amount, as in withdraw_royalties
+    let withdrawal_id = next_id(IdCounter::Withdrawal);
+    royalties::debit_referrer(referrer, amount, EntryReference::ReferralWithdrawal { withdrawal_id })?;
+    let withdrawal = ReferralWithdrawal {
+        id: withdrawal_id,
+        referrer,
+        amount,
+        fee,
+        requested_at: ic_cdk::api::time() / 1_000_000,
+        status: WithdrawalStatus::Pending,
+    };
+    REFERRAL_WITHDRAWALS.with(|w| w.borrow_mut().insert((referrer, withdrawal_id), withdrawal));
+
+    let to = ledger::Account { owner: referrer, subaccount: None };
+    let memo = format!("withdraw:{}", withdrawal_id).into_bytes();
+    let result = ledger::transfer(ledger, to, amount - fee, memo).await;
+    let status = match &result {
+        Ok(block_index) => WithdrawalStatus::Completed { block_index: *block_index },
+        Err(e) => {
+            if let Err(err) = royalties::credit_referrer(referrer, amount, EntryReference::ReferralWithdrawalReversal { withdrawal_id }) {
+                ic_cdk::println!("Could not reverse referral withdrawal {}: {:?}", withdrawal_id, err);
             }
+            WithdrawalStatus::Failed { reason: e.clone() }
         }
-        false
-    })
+    };
+    REFERRAL_WITHDRAWALS.with(|w| memory::modify(&mut w.borrow_mut(), &(referrer, withdrawal_id), |w| w.status = status));
+    let block_index = result.map_err(ApiError::Ledger)?;
+    let now = ic_cdk::api::time() / 1_000_000;
+    log_user_activity(referrer, "withdraw_referral_earnings", now, &format!("Withdrew {} tokens (block {})", amount, block_index));
+    Ok(block_index)
 }
 
-// Analytics: increment play count
+// Analytics: increment play count; same as record_play without a referral
 #[ic_cdk::update]
-fn increment_play_count(track_id: u64) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.play_count += 1;
-            return true;
-        }
-        false
-    })
+fn increment_play_count(track_id: u64) -> Result<(), ApiError> {
+    record_play(track_id, None)
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -1047,109 +1876,124 @@ pub struct TrackAnalytics {
 
 #[ic_cdk::query]
 fn get_track_analytics(track_id: u64) -> Option<TrackAnalytics> {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).map(|t| {
-            let revenue: u64 = t.payments.iter().map(|p| p.amount).sum();
-            let comments_count = t.comments.len() as u64;
-            let ratings_count = t.ratings.len() as u64;
-            let avg_rating = if ratings_count > 0 {
-                (t.ratings.iter().map(|(_, r)| *r as u32).sum::<u32>() / ratings_count as u32) as u8
-            } else { 0 };
-            TrackAnalytics {
-                play_count: t.play_count,
-                revenue,
-                comments_count,
-                ratings_count,
-                avg_rating,
-            }
-        })
+    permissions::visible_track(track_id).map(|t| {
+        let revenue: u64 = t.payments.iter().map(|p| p.amount).sum();
+        let comments_count = t.comments.len() as u64;
+        let ratings_count = t.ratings.len() as u64;
+        let avg_rating = t.ratings.iter().map(|(_, r)| *r as u32).sum::<u32>()
+            .checked_div(ratings_count as u32)
+            .unwrap_or(0) as u8;
+        TrackAnalytics {
+            play_count: t.play_count,
+            revenue,
+            comments_count,
+            ratings_count,
+            avg_rating,
+        }
     })
 }
 
 // User CRUD
 #[ic_cdk::update]
-pub fn register_user(username: String, bio: Option<String>, avatar_url: Option<String>) -> Option<User> {
+pub fn register_user(username: String, bio: Option<String>, avatar_url: Option<String>) -> Result<User, ApiError> {
     let principal = caller();
     if username.trim().is_empty() {
-        return None;
+        return Err(ApiError::validation("username", "must not be empty"));
     }
     USERS.with(|users| {
         let mut users = users.borrow_mut();
-        if users.iter().any(|u| u.principal == principal) {
-            return None; // Already registered
+        if users.contains_key(&principal) {
+            return Err(ApiError::conflict("caller is already registered"));
         }
         let user = User {
+            id: next_id(IdCounter::User),
             principal,
             username: username.clone(),
-            bio: bio.clone(),
-            avatar_url: avatar_url.clone(),
+            bio,
+            avatar_url,
             role: UserRole::User,
         };
-        users.push(user.clone());
+        users.insert(principal, user.clone());
+        indexes::index_user(&user);
+        search::index_user(&user);
         let now = ic_cdk::api::time() / 1_000_000;
+        metrics::registration(now);
         log_user_activity(principal, "register_user", now, &format!("Registered user: {}", username));
-        Some(user)
+        Ok(user)
     })
 }
 
 #[ic_cdk::query]
 fn get_user() -> Option<User> {
     let principal = caller();
-    USERS.with(|users| users.borrow().iter().find(|u| u.principal == principal).cloned())
+    USERS.with(|users| users.borrow().get(&principal))
 }
 
 #[ic_cdk::update]
-fn update_user(username: String, bio: Option<String>, avatar_url: Option<String>) -> Option<User> {
+fn update_user(username: String, bio: Option<String>, avatar_url: Option<String>) -> Result<User, ApiError> {
     let principal = caller();
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        if let Some(user) = users.iter_mut().find(|u| u.principal == principal) {
+    if username.trim().is_empty() {
+        return Err(ApiError::validation("username", "must not be empty"));
+    }
+    let (previous, user) = USERS.with(|users| {
+        memory::modify(&mut users.borrow_mut(), &principal, |user| {
+            let previous = user.clone();
             user.username = username.clone();
-            user.bio = bio.clone();
-            user.avatar_url = avatar_url.clone();
-            let now = ic_cdk::api::time() / 1_000_000;
-            log_user_activity(principal, "update_user", now, &format!("Updated user: {}", username));
-            return Some(user.clone());
-        }
-        None
+            user.bio = bio;
+            user.avatar_url = avatar_url;
+            (previous, user.clone())
+        })
     })
+    .ok_or_else(|| ApiError::not_found("user", principal))?;
+    indexes::unindex_user(&previous);
+    indexes::index_user(&user);
+    search::index_user(&user);
+    let now = ic_cdk::api::time() / 1_000_000;
+    log_user_activity(principal, "update_user", now, &format!("Updated user: {}", username));
+    Ok(user)
 }
 
 #[ic_cdk::update]
-fn delete_user() -> bool {
+fn delete_user() -> Result<(), ApiError> {
     let principal = caller();
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        let len_before = users.len();
-        users.retain(|u| u.principal != principal);
-        let deleted = users.len() < len_before;
-        if deleted {
-            let now = ic_cdk::api::time() / 1_000_000;
-            log_user_activity(principal, "delete_user", now, "Deleted user profile");
-        }
-        deleted
-    })
+    let user = USERS
+        .with(|users| users.borrow_mut().remove(&principal))
+        .ok_or_else(|| ApiError::not_found("user", principal))?;
+    indexes::unindex_user(&user);
+    search::remove(SearchKind::User, user.id);
+    follows::remove_follower(principal);
+    let now = ic_cdk::api::time() / 1_000_000;
+    log_user_activity(principal, "delete_user", now, "Deleted user profile");
+    Ok(())
 }
 
 // 1. List all users
 #[ic_cdk::query]
-fn list_users() -> Vec<User> {
-    USERS.with(|users| users.borrow().clone())
+fn list_users(page: Option<PageRequest>) -> Result<Page<User>, ApiError> {
+    let users = USERS.with(|users| users.borrow().values().map(|u| (u.id, u)).collect());
+    paging::page(users, page, SortKey::CreatedAt, false)
 }
 
 // 2. Get user by principal
 #[ic_cdk::query]
 fn get_user_by_principal(principal: Principal) -> Option<User> {
-    USERS.with(|users| users.borrow().iter().find(|u| u.principal == principal).cloned())
+    USERS.with(|users| users.borrow().get(&principal))
 }
 
 // 3. Search users by username (case-insensitive substring)
 #[ic_cdk::query]
-fn search_users_by_username(query: String) -> Vec<User> {
+fn search_users_by_username(query: String, page: Option<PageRequest>) -> Result<Page<User>, ApiError> {
     let q = query.to_lowercase();
-    USERS.with(|users| {
-        users.borrow().iter().filter(|u| u.username.to_lowercase().contains(&q)).cloned().collect()
-    })
+    let users = USERS.with(|users| {
+        users.borrow().values().filter(|u| u.username.to_lowercase().contains(&q)).map(|u| (u.id, u)).collect()
+    });
+    paging::page(users, page, SortKey::Title, false)
+}
+
+// 4. Users with exactly this username, ignoring case (usernames need not be unique)
+#[ic_cdk::query]
+fn get_users_by_username(username: String) -> Vec<User> {
+    indexes::users_named(&username).into_iter().filter(|u| u.username.to_lowercase() == username.to_lowercase()).collect()
 }
 
 // 6. User activity log
@@ -1163,271 +2007,264 @@ pub struct UserActivity {
 
 fn log_user_activity(principal: Principal, action: &str, timestamp: u64, details: &str) {
     USER_ACTIVITY_LOG.with(|log| {
-        log.borrow_mut().push(UserActivity {
+        let mut log = log.borrow_mut();
+        let seq = log.len();
+        log.insert(seq, UserActivity {
             principal,
             action: action.to_string(),
             timestamp,
             details: details.to_string(),
         });
     });
+    metrics::mark_active(principal, timestamp);
 }
 
 #[ic_cdk::query]
 fn get_user_activity_log(principal: Principal) -> Vec<UserActivity> {
     USER_ACTIVITY_LOG.with(|log| {
-        log.borrow().iter().filter(|a| a.principal == principal).cloned().collect()
+        log.borrow().values().filter(|a| a.principal == principal).collect()
     })
 }
 
 #[ic_cdk::update]
-fn add_dummy_activity() {
+fn add_dummy_activity() -> Result<(), ApiError> {
     let principal = caller();
     let now = ic_cdk::api::time() / 1_000_000;
     log_user_activity(principal, "dummy_action", now, "This is a test activity");
+    Ok(())
 }
 
 // 2. Audit/Admin Tools
 pub fn is_admin(principal: Principal) -> bool {
-    USERS.with(|users| users.borrow().iter().any(|u| u.principal == principal && u.role == UserRole::Admin))
+    config().super_admin == Some(principal) || user_role(principal) == Some(UserRole::Admin)
 }
 
-#[ic_cdk::update]
-pub fn ban_user(principal_to_ban: Principal) -> bool {
+// Moderators handle reports and the moderation queue; admins can do everything they can
+pub fn is_moderator(principal: Principal) -> bool {
+    is_admin(principal) || user_role(principal) == Some(UserRole::Moderator)
+}
+
+fn user_role(principal: Principal) -> Option<UserRole> {
+    USERS.with(|users| users.borrow().get(&principal).map(|u| u.role))
+}
+
+fn require_admin() -> Result<Principal, ApiError> {
     let principal = caller();
-    if !is_admin(principal) { return false; }
-    let mut success = false;
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        if let Some(user) = users.iter_mut().find(|u| u.principal == principal_to_ban) {
-            user.role = UserRole::User; // Or add a Banned role if desired
-            success = true;
-        }
-    });
-    if success {
-        log_admin_action(
-            principal,
-            "ban_user",
-            "User",
-            &principal_to_ban.to_text(),
-            Some("User banned by admin".to_string()),
-        );
+    if is_admin(principal) {
+        Ok(principal)
+    } else {
+        Err(ApiError::unauthorized("admin role required"))
     }
-    success
 }
 
-#[ic_cdk::update]
-pub fn delete_user_by_admin(principal_to_delete: Principal) -> bool {
+fn require_moderator() -> Result<Principal, ApiError> {
     let principal = caller();
-    if !is_admin(principal) { return false; }
-    let mut deleted = false;
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        let len_before = users.len();
-        users.retain(|u| u.principal != principal_to_delete);
-        deleted = users.len() < len_before;
-    });
-    if deleted {
-        log_admin_action(
-            principal,
-            "delete_user_by_admin",
-            "User",
-            &principal_to_delete.to_text(),
-            Some("User deleted by admin".to_string()),
-        );
+    if is_moderator(principal) {
+        Ok(principal)
+    } else {
+        Err(ApiError::unauthorized("moderator role required"))
     }
-    deleted
 }
 
+// Grants or revokes Admin/Moderator; setting `User` clears either
 #[ic_cdk::update]
-pub fn delete_artist_by_admin(artist_id: u64) -> bool {
-    let principal = caller();
-    if !is_admin(principal) { return false; }
-    let mut deleted = false;
-    ARTISTS.with(|artists| {
-        let mut artists = artists.borrow_mut();
-        let len_before = artists.len();
-        artists.retain(|a| a.id != artist_id);
-        deleted = artists.len() < len_before;
-    });
-    if deleted {
-        log_admin_action(
-            principal,
-            "delete_artist_by_admin",
-            "Artist",
-            &artist_id.to_string(),
-            Some("Artist deleted by admin".to_string()),
-        );
-    }
-    deleted
+pub fn set_user_role(target: Principal, role: UserRole) -> Result<(), ApiError> {
+    let admin = require_admin()?;
+    let previous = USERS
+        .with(|users| {
+            memory::modify(&mut users.borrow_mut(), &target, |user| std::mem::replace(&mut user.role, role.clone()))
+        })
+        .ok_or_else(|| ApiError::not_found("user", target))?;
+    log_admin_action(
+        admin,
+        "set_user_role",
+        "User",
+        &target.to_text(),
+        Some(format!("Role changed from {:?} to {:?}", previous, role)),
+    );
+    Ok(())
 }
 
 #[ic_cdk::update]
-pub fn delete_track_by_admin(track_id: u64) -> bool {
-    let principal = caller();
-    if !is_admin(principal) { return false; }
-    let mut deleted = false;
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        let len_before = tracks.len();
-        tracks.retain(|t| t.id != track_id);
-        deleted = tracks.len() < len_before;
-    });
-    if deleted {
-        log_admin_action(
-            principal,
-            "delete_track_by_admin",
-            "Track",
-            &track_id.to_string(),
-            Some("Track deleted by admin".to_string()),
-        );
+pub fn ban_user(principal_to_ban: Principal) -> Result<(), ApiError> {
+    let principal = require_admin()?;
+    USERS.with(|users| {
+        memory::modify(&mut users.borrow_mut(), &principal_to_ban, |user| {
+            user.role = UserRole::User; // Or add a Banned role if desired
+        })
+    })
+    .ok_or_else(|| ApiError::not_found("user", principal_to_ban))?;
+    log_admin_action(
+        principal,
+        "ban_user",
+        "User",
+        &principal_to_ban.to_text(),
+        Some("User banned by admin".to_string()),
+    );
+    Ok(())
+}
+
+#[ic_cdk::update]
+pub fn delete_user_by_admin(principal_to_delete: Principal) -> Result<(), ApiError> {
+    let principal = require_admin()?;
+    let user = USERS
+        .with(|users| users.borrow_mut().remove(&principal_to_delete))
+        .ok_or_else(|| ApiError::not_found("user", principal_to_delete))?;
+    indexes::unindex_user(&user);
+    search::remove(SearchKind::User, user.id);
+    follows::remove_follower(principal_to_delete);
+    log_admin_action(
+        principal,
+        "delete_user_by_admin",
+        "User",
+        &principal_to_delete.to_text(),
+        Some("User deleted by admin".to_string()),
+    );
+    Ok(())
+}
+
+#[ic_cdk::update]
+pub fn delete_artist_by_admin(artist_id: u64) -> Result<(), ApiError> {
+    let principal = require_admin()?;
+    let balance = get_artist(artist_id).ok_or_else(|| ApiError::not_found("artist", artist_id))?.royalty_balance;
+    // Close the artist's ledger account so reconciliation still balances
+    if balance > 0 {
+        royalties::debit_artist(artist_id, balance, EntryReference::ArtistRemoved { removed_by: principal })?;
+    }
+    if let Some(artist) = ARTISTS.with(|artists| artists.borrow_mut().remove(&artist_id)) {
+        indexes::unindex_artist(&artist);
     }
-    deleted
+    search::remove(SearchKind::Artist, artist_id);
+    log_admin_action(
+        principal,
+        "delete_artist_by_admin",
+        "Artist",
+        &artist_id.to_string(),
+        Some("Artist deleted by admin".to_string()),
+    );
+    Ok(())
+}
+
+#[ic_cdk::update]
+pub fn delete_track_by_admin(track_id: u64) -> Result<(), ApiError> {
+    let principal = require_admin()?;
+    let track = TRACKS
+        .with(|tracks| tracks.borrow_mut().remove(&track_id))
+        .ok_or_else(|| ApiError::not_found("track", track_id))?;
+    indexes::unindex_track(&track);
+    search::remove(SearchKind::Track, track_id);
+    uploads::remove_track_files(track_id);
+    remove_track_versions(track_id);
+    plays::remove_track(track_id);
+    metrics::remove_track(track_id);
+    follows::remove_track(track_id);
+    log_admin_action(
+        principal,
+        "delete_track_by_admin",
+        "Track",
+        &track_id.to_string(),
+        Some("Track deleted by admin".to_string()),
+    );
+    Ok(())
 }
 
 // 3. Track/Artist Following
 #[ic_cdk::update]
-pub fn follow_artist(artist_principal: Principal) -> bool {
-    let principal = caller();
-    FOLLOWED_ARTISTS.with(|fa| {
-        let mut fa = fa.borrow_mut();
-        if let Some((p, artists)) = fa.iter_mut().find(|(p, _)| *p == principal) {
-            if !artists.contains(&artist_principal) {
-                artists.push(artist_principal);
-            }
-        } else {
-            fa.push((principal, vec![artist_principal]));
-        }
-        true
-    })
+pub fn follow_artist(artist_principal: Principal) -> Result<(), ApiError> {
+    follows::follow_artist(caller(), artist_principal);
+    Ok(())
 }
 
 #[ic_cdk::update]
-pub fn unfollow_artist(artist_principal: Principal) -> bool {
-    let principal = caller();
-    FOLLOWED_ARTISTS.with(|fa| {
-        let mut fa = fa.borrow_mut();
-        if let Some((p, artists)) = fa.iter_mut().find(|(p, _)| *p == principal) {
-            artists.retain(|a| a != &artist_principal);
-            return true;
-        }
-        false
-    })
+pub fn unfollow_artist(artist_principal: Principal) -> Result<(), ApiError> {
+    follows::unfollow_artist(caller(), artist_principal)
 }
 
 #[ic_cdk::query]
 pub fn list_followed_artists() -> Vec<Principal> {
-    let principal = caller();
-    FOLLOWED_ARTISTS.with(|fa| {
-        fa.borrow().iter().find(|(p, _)| *p == principal).map(|(_, artists)| artists.clone()).unwrap_or_default()
-    })
+    follows::followed_artists(caller())
+}
+
+// Principals following the caller as an artist
+#[ic_cdk::query]
+pub fn list_followers() -> Vec<Principal> {
+    follows::artist_followers(caller())
 }
 
 #[ic_cdk::update]
-pub fn follow_track(track_id: u64) -> bool {
-    let principal = caller();
-    FOLLOWED_TRACKS.with(|ft| {
-        let mut ft = ft.borrow_mut();
-        if let Some((p, tracks)) = ft.iter_mut().find(|(p, _)| *p == principal) {
-            if !tracks.contains(&track_id) {
-                tracks.push(track_id);
-            }
-        } else {
-            ft.push((principal, vec![track_id]));
-        }
-        true
-    })
+pub fn follow_track(track_id: u64) -> Result<(), ApiError> {
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    follows::follow_track(caller(), track_id);
+    Ok(())
 }
 
 #[ic_cdk::update]
-pub fn unfollow_track(track_id: u64) -> bool {
-    let principal = caller();
-    FOLLOWED_TRACKS.with(|ft| {
-        let mut ft = ft.borrow_mut();
-        if let Some((p, tracks)) = ft.iter_mut().find(|(p, _)| *p == principal) {
-            tracks.retain(|t| t != &track_id);
-            return true;
-        }
-        false
-    })
+pub fn unfollow_track(track_id: u64) -> Result<(), ApiError> {
+    follows::unfollow_track(caller(), track_id)
 }
 
 #[ic_cdk::query]
 pub fn list_followed_tracks() -> Vec<u64> {
-    let principal = caller();
-    FOLLOWED_TRACKS.with(|ft| {
-        ft.borrow().iter().find(|(p, _)| *p == principal).map(|(_, tracks)| tracks.clone()).unwrap_or_default()
-    })
+    follows::followed_tracks(caller())
+}
+
+// How many principals follow this one as an artist, and how many artists it follows
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FollowCounts {
+    pub followers: u64,
+    pub following: u64, // artists only; followed tracks aren't counted
 }
 
 // Notifications System
 #[ic_cdk::update]
-pub fn send_notification(user_principal: Principal, message: String) -> Notification {
+pub fn send_notification(user_principal: Principal, message: String) -> Result<Notification, ApiError> {
+    if message.trim().is_empty() {
+        return Err(ApiError::validation("message", "must not be empty"));
+    }
     let now = ic_cdk::api::time() / 1_000_000;
-    let id = NOTIFICATION_ID.with(|nid| {
-        let mut nid = nid.borrow_mut();
-        let id = *nid;
-        *nid += 1;
-        id
-    });
     let notification = Notification {
-        id,
+        id: next_id(IdCounter::Notification),
         user_principal,
         message,
         timestamp: now,
         read: false,
     };
-    NOTIFICATIONS.with(|n| n.borrow_mut().push(notification.clone()));
-    notification
+    NOTIFICATIONS.with(|n| n.borrow_mut().insert(notification.id, notification.clone()));
+    Ok(notification)
 }
 
 #[ic_cdk::query]
 pub fn list_notifications() -> Vec<Notification> {
     let principal = caller();
-    NOTIFICATIONS.with(|n| n.borrow().iter().filter(|notif| notif.user_principal == principal).cloned().collect())
+    NOTIFICATIONS.with(|n| n.borrow().values().filter(|notif| notif.user_principal == principal).collect())
 }
 
 #[ic_cdk::update]
-pub fn mark_notification_read(notification_id: u64) -> bool {
+pub fn mark_notification_read(notification_id: u64) -> Result<(), ApiError> {
     let principal = caller();
     NOTIFICATIONS.with(|n| {
         let mut n = n.borrow_mut();
-        if let Some(notif) = n.iter_mut().find(|notif| notif.id == notification_id && notif.user_principal == principal) {
-            notif.read = true;
-            return true;
-        }
-        false
-    })
-}
-
-#[ic_cdk::update]
-pub fn promote_to_admin() -> bool {
-    let principal = caller();
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        if let Some(user) = users.iter_mut().find(|u| u.principal == principal) {
-            user.role = UserRole::Admin;
-            return true;
+        match n.get(&notification_id) {
+            Some(mut notif) if notif.user_principal == principal => {
+                notif.read = true;
+                n.insert(notification_id, notif);
+                Ok(())
+            }
+            _ => Err(ApiError::not_found("notification", notification_id)),
         }
-        false
     })
 }
 
 // 4. Playlist Management
 #[ic_cdk::update]
-pub fn create_playlist(name: String, description: Option<String>, track_ids: Vec<u64>) -> Option<Playlist> {
+pub fn create_playlist(name: String, description: Option<String>, track_ids: Vec<u64>) -> Result<Playlist, ApiError> {
     let owner = caller();
     if name.trim().is_empty() {
-        return None;
+        return Err(ApiError::validation("name", "must not be empty"));
     }
     let now = ic_cdk::api::time() / 1_000_000;
-    let id = PLAYLIST_ID.with(|pid| {
-        let mut pid = pid.borrow_mut();
-        let id = *pid;
-        *pid += 1;
-        id
-    });
     let playlist = Playlist {
-        id,
+        id: next_id(IdCounter::Playlist),
         owner,
         name,
         description,
@@ -1435,46 +2272,66 @@ pub fn create_playlist(name: String, description: Option<String>, track_ids: Vec
         created_at: now,
         updated_at: now,
     };
-    PLAYLISTS.with(|p| p.borrow_mut().push(playlist.clone()));
-    Some(playlist)
+    PLAYLISTS.with(|p| p.borrow_mut().insert(playlist.id, playlist.clone()));
+    indexes::index_playlist(&playlist);
+    Ok(playlist)
 }
 
 #[ic_cdk::update]
-pub fn update_playlist(playlist_id: u64, name: String, description: Option<String>, track_ids: Vec<u64>) -> Option<Playlist> {
-    let owner = caller();
-    PLAYLISTS.with(|p| {
-        let mut p = p.borrow_mut();
-        if let Some(playlist) = p.iter_mut().find(|pl| pl.id == playlist_id && pl.owner == owner) {
-            playlist.name = name;
-            playlist.description = description;
-            playlist.track_ids = track_ids;
-            playlist.updated_at = ic_cdk::api::time() / 1_000_000;
-            return Some(playlist.clone());
-        }
-        None
-    })
+pub fn update_playlist(playlist_id: u64, name: String, description: Option<String>, track_ids: Vec<u64>) -> Result<Playlist, ApiError> {
+    if name.trim().is_empty() {
+        return Err(ApiError::validation("name", "must not be empty"));
+    }
+    let mut playlist = owned_playlist(playlist_id)?;
+    playlist.name = name;
+    playlist.description = description;
+    playlist.track_ids = track_ids;
+    playlist.updated_at = ic_cdk::api::time() / 1_000_000;
+    PLAYLISTS.with(|p| p.borrow_mut().insert(playlist_id, playlist.clone()));
+    Ok(playlist)
 }
 
 #[ic_cdk::update]
-pub fn delete_playlist(playlist_id: u64) -> bool {
-    let owner = caller();
-    PLAYLISTS.with(|p| {
-        let mut p = p.borrow_mut();
-        let len_before = p.len();
-        p.retain(|pl| !(pl.id == playlist_id && pl.owner == owner));
-        p.len() < len_before
-    })
+pub fn delete_playlist(playlist_id: u64) -> Result<(), ApiError> {
+    let playlist = owned_playlist(playlist_id)?;
+    PLAYLISTS.with(|p| p.borrow_mut().remove(&playlist_id));
+    indexes::unindex_playlist(&playlist);
+    Ok(())
+}
+
+fn owned_playlist(playlist_id: u64) -> Result<Playlist, ApiError> {
+    let playlist = PLAYLISTS
+        .with(|p| p.borrow().get(&playlist_id))
+        .ok_or_else(|| ApiError::not_found("playlist", playlist_id))?;
+    if playlist.owner != caller() {
+        return Err(ApiError::unauthorized("caller does not own this playlist"));
+    }
+    Ok(playlist)
 }
 
 #[ic_cdk::query]
 pub fn list_playlists() -> Vec<Playlist> {
     let owner = caller();
-    PLAYLISTS.with(|p| p.borrow().iter().filter(|pl| pl.owner == owner).cloned().collect())
+    let ids = indexes::playlists_of(owner);
+    PLAYLISTS.with(|p| {
+        let playlists = p.borrow();
+        ids.iter().filter_map(|id| playlists.get(id)).map(hide_invisible_tracks).collect()
+    })
 }
 
 #[ic_cdk::query]
 pub fn get_playlist(playlist_id: u64) -> Option<Playlist> {
-    PLAYLISTS.with(|p| p.borrow().iter().find(|pl| pl.id == playlist_id).cloned())
+    PLAYLISTS.with(|p| p.borrow().get(&playlist_id)).map(hide_invisible_tracks)
+}
+
+// Drops the tracks the caller is not allowed to see from a playlist
+fn hide_invisible_tracks(mut playlist: Playlist) -> Playlist {
+    let viewer = permissions::viewer();
+    TRACKS.with(|tracks| {
+        let tracks = tracks.borrow();
+        playlist.track_ids.retain(|id| tracks.get(id).is_some_and(|t| permissions::can_view(&t, viewer)));
+    });
+    playlist
 }
 
 // 5. Track Download/Streaming Controls
@@ -1484,64 +2341,75 @@ pub struct PlayDownloadCount {
     pub track_id: u64,
     pub play_count: u64,
     pub download_count: u64,
+    pub last_played_at: Option<u64>, // last counted play, for the cooldown
+}
+
+// Plays of one track in one hour or day
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PlayBucket {
+    pub plays: u64,
+    pub new_listeners: u64, // principals whose first counted play of the track fell in this period
 }
 
-// Extend Track with downloadable
-// (Add: pub downloadable: bool, default true)
-// Update Track struct definition and all usages accordingly
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+pub enum Granularity {
+    Hour,
+    Day,
+    Month, // calendar month, UTC
+}
 
 #[ic_cdk::update]
-pub fn set_track_downloadable(track_id: u64, downloadable: bool) -> bool {
-    TRACKS.with(|tracks| {
-        let mut tracks = tracks.borrow_mut();
-        if let Some(track) = tracks.iter_mut().find(|t| t.id == track_id) {
-            track.downloadable = downloadable;
-            return true;
-        }
-        false
-    })
+pub fn set_track_downloadable(track_id: u64, downloadable: bool) -> Result<(), ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::SetDownloadable)?;
+    track.downloadable = downloadable;
+    save_track(&track);
+    Ok(())
 }
 
 #[ic_cdk::query]
 pub fn can_download_track(track_id: u64) -> bool {
-    TRACKS.with(|tracks| {
-        tracks.borrow().iter().find(|t| t.id == track_id).map(|t| t.downloadable).unwrap_or(false)
-    })
+    permissions::visible_track(track_id).is_some_and(|t| t.downloadable)
 }
 
 #[ic_cdk::update]
-pub fn record_play(track_id: u64) -> bool {
-    let principal = caller();
-    PLAY_COUNTS.with(|pc| {
-        let mut pc = pc.borrow_mut();
-        if let Some(entry) = pc.iter_mut().find(|e| e.principal == principal && e.track_id == track_id) {
-            entry.play_count += 1;
-        } else {
-            pc.push(PlayDownloadCount { principal, track_id, play_count: 1, download_count: 0 });
+pub fn record_play(track_id: u64, referral: Option<String>) -> Result<(), ApiError> {
+    rate_limits::enforce(RateLimitedMethod::RecordPlay)?;
+    let referrer = match &referral {
+        Some(code) => referrals::resolve(code, track_id, caller())?,
+        None => None,
+    };
+    // Plays inside the cooldown aren't attributed either
+    if plays::record(track_id)? {
+        if let Some(referrer) = referrer {
+            referrals::count_play(&referrer.code);
         }
-        true
-    })
+    }
+    Ok(())
 }
 
 #[ic_cdk::update]
-pub fn record_download(track_id: u64) -> bool {
+pub fn record_download(track_id: u64) -> Result<(), ApiError> {
     let principal = caller();
+    let track = permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    if !track.downloadable {
+        return Err(ApiError::unauthorized("downloads are disabled for this track"));
+    }
     PLAY_COUNTS.with(|pc| {
         let mut pc = pc.borrow_mut();
-        if let Some(entry) = pc.iter_mut().find(|e| e.principal == principal && e.track_id == track_id) {
-            entry.download_count += 1;
-        } else {
-            pc.push(PlayDownloadCount { principal, track_id, play_count: 0, download_count: 1 });
-        }
-        true
-    })
+        let mut entry = pc.get(&(principal, track_id))
+            .unwrap_or(PlayDownloadCount { principal, track_id, play_count: 0, download_count: 0, last_played_at: None });
+        entry.download_count += 1;
+        pc.insert((principal, track_id), entry);
+    });
+    metrics::track_event(&track, metrics::Counter::Downloads);
+    Ok(())
 }
 
 #[ic_cdk::query]
 pub fn get_user_play_count(track_id: u64) -> u64 {
     let principal = caller();
     PLAY_COUNTS.with(|pc| {
-        pc.borrow().iter().find(|e| e.principal == principal && e.track_id == track_id).map(|e| e.play_count).unwrap_or(0)
+        pc.borrow().get(&(principal, track_id)).map(|e| e.play_count).unwrap_or(0)
     })
 }
 
@@ -1549,10 +2417,18 @@ pub fn get_user_play_count(track_id: u64) -> u64 {
 pub fn get_user_download_count(track_id: u64) -> u64 {
     let principal = caller();
     PLAY_COUNTS.with(|pc| {
-        pc.borrow().iter().find(|e| e.principal == principal && e.track_id == track_id).map(|e| e.download_count).unwrap_or(0)
+        pc.borrow().get(&(principal, track_id)).map(|e| e.download_count).unwrap_or(0)
     })
 }
 
+// The track's plays per hour, day or month for periods starting in [from, to) (ms), oldest first;
+// periods without plays are left out, and hourly counts only go back HOURLY_RETENTION_MS
+#[ic_cdk::query]
+pub fn get_track_play_history(track_id: u64, granularity: Granularity, from: u64, to: u64) -> Result<Vec<(u64, PlayBucket)>, ApiError> {
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    Ok(plays::history(track_id, granularity, from, to))
+}
+
 // 6. User-to-User Messaging
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Message {
@@ -1565,64 +2441,61 @@ pub struct Message {
 }
 
 #[ic_cdk::update]
-pub fn send_message(to: Principal, content: String) -> Option<Message> {
+pub fn send_message(to: Principal, content: String) -> Result<Message, ApiError> {
+    rate_limits::enforce(RateLimitedMethod::SendMessage)?;
     let from = caller();
     if content.trim().is_empty() {
-        return None;
+        return Err(ApiError::validation("content", "must not be empty"));
     }
+    ensure_not_suspended(from)?;
     let now = ic_cdk::api::time() / 1_000_000;
-    let id = MESSAGE_ID.with(|mid| {
-        let mut mid = mid.borrow_mut();
-        let id = *mid;
-        *mid += 1;
-        id
-    });
     let message = Message {
-        id,
+        id: next_id(IdCounter::Message),
         from,
         to,
         content,
         timestamp: now,
         read: false,
     };
-    MESSAGES.with(|m| m.borrow_mut().push(message.clone()));
-    Some(message)
+    MESSAGES.with(|m| m.borrow_mut().insert(message.id, message.clone()));
+    Ok(message)
 }
 
 #[ic_cdk::query]
 pub fn list_messages_with(user: Principal) -> Vec<Message> {
     let me = caller();
     MESSAGES.with(|m| {
-        m.borrow().iter().filter(|msg| (msg.from == me && msg.to == user) || (msg.from == user && msg.to == me)).cloned().collect()
+        m.borrow().values().filter(|msg| (msg.from == me && msg.to == user) || (msg.from == user && msg.to == me)).collect()
     })
 }
 
 #[ic_cdk::update]
-pub fn mark_message_read(message_id: u64) -> bool {
+pub fn mark_message_read(message_id: u64) -> Result<(), ApiError> {
     let me = caller();
     MESSAGES.with(|m| {
         let mut m = m.borrow_mut();
-        if let Some(msg) = m.iter_mut().find(|msg| msg.id == message_id && msg.to == me) {
-            msg.read = true;
-            return true;
+        match m.get(&message_id) {
+            Some(mut msg) if msg.to == me => {
+                msg.read = true;
+                m.insert(message_id, msg);
+                Ok(())
+            }
+            _ => Err(ApiError::not_found("message", message_id)),
         }
-        false
     })
 }
 
 // --- Reporting & Moderation Endpoints ---
 #[ic_cdk::update]
-pub fn report_content(target_type: ReportTargetType, target_id: String, reason: String, details: Option<String>) -> Option<Report> {
+pub fn report_content(target_type: ReportTargetType, target_id: String, reason: String, details: Option<String>) -> Result<Report, ApiError> {
+    rate_limits::enforce(RateLimitedMethod::ReportContent)?;
+    if reason.trim().is_empty() {
+        return Err(ApiError::validation("reason", "must not be empty"));
+    }
     let reporter = caller();
     let now = ic_cdk::api::time() / 1_000_000;
-    let id = REPORT_ID.with(|rid| {
-        let mut rid = rid.borrow_mut();
-        let id = *rid;
-        *rid += 1;
-        id
-    });
     let report = Report {
-        id,
+        id: next_id(IdCounter::Report),
         reporter,
         target_type,
         target_id,
@@ -1634,53 +2507,45 @@ pub fn report_content(target_type: ReportTargetType, target_id: String, reason:
         reviewed_at: None,
         resolution_notes: None,
     };
-    REPORTS.with(|r| r.borrow_mut().push(report.clone()));
-    Some(report)
+    REPORTS.with(|r| r.borrow_mut().insert(report.id, report.clone()));
+    Ok(report)
 }
 
 #[ic_cdk::query]
-pub fn list_reports() -> Vec<Report> {
-    REPORTS.with(|r| r.borrow().clone())
+pub fn list_reports(page: Option<PageRequest>) -> Result<Page<Report>, ApiError> {
+    require_moderator()?;
+    paging::page(REPORTS.with(|r| r.borrow().iter().collect()), page, SortKey::CreatedAt, true)
 }
 
 #[ic_cdk::update]
-pub fn review_report(report_id: u64, status: ReportStatus, resolution_notes: Option<String>) -> bool {
-    let reviewer = caller();
+pub fn review_report(report_id: u64, status: ReportStatus, resolution_notes: Option<String>) -> Result<(), ApiError> {
+    let reviewer = require_moderator()?;
     let now = ic_cdk::api::time() / 1_000_000;
-    if !is_admin(reviewer) {
-        return false;
-    }
-    let mut success = false;
-    let mut target_type = String::new();
-    let mut target_id = String::new();
     let status_for_log = status.clone();
-    REPORTS.with(|r| {
-        let mut r = r.borrow_mut();
-        if let Some(report) = r.iter_mut().find(|rep| rep.id == report_id) {
+    let target = REPORTS.with(|r| {
+        memory::modify(&mut r.borrow_mut(), &report_id, |report| {
             report.status = status;
             report.reviewed_by = Some(reviewer);
             report.reviewed_at = Some(now);
-            report.resolution_notes = resolution_notes.clone();
-            target_type = format!("{:?}", report.target_type);
-            target_id = report.target_id.clone();
-            success = true;
-        }
+            report.resolution_notes = resolution_notes;
+            (format!("{:?}", report.target_type), report.target_id.clone())
+        })
     });
-    if success {
-        log_admin_action(
-            reviewer,
-            "review_report",
-            &target_type,
-            &target_id,
-            Some(format!("Report {} reviewed: {:?}", report_id, status_for_log)),
-        );
-    }
-    success
+    let (target_type, target_id) = target.ok_or_else(|| ApiError::not_found("report", report_id))?;
+    log_admin_action(
+        reviewer,
+        "review_report",
+        &target_type,
+        &target_id,
+        Some(format!("Report {} reviewed: {:?}", report_id, status_for_log)),
+    );
+    Ok(())
 }
 
 // --- Track Licensing/Contracts Endpoints ---
 #[ic_cdk::update]
-pub fn set_track_license(track_id: u64, license_type: LicenseType, terms: Option<String>, contract_text: Option<String>) -> Option<TrackLicense> {
+pub fn set_track_license(track_id: u64, license_type: LicenseType, terms: Option<String>, contract_text: Option<String>) -> Result<TrackLicense, ApiError> {
+    permissions::authorize(track_id, TrackOperation::SetLicense)?;
     let now = ic_cdk::api::time() / 1_000_000;
     let license = TrackLicense {
         track_id,
@@ -1689,88 +2554,65 @@ pub fn set_track_license(track_id: u64, license_type: LicenseType, terms: Option
         contract_text,
         issued_at: now,
     };
-    TRACK_LICENSES.with(|tl| {
-        let mut tl = tl.borrow_mut();
-        // Replace if exists
-        if let Some(existing) = tl.iter_mut().find(|l| l.track_id == track_id) {
-            *existing = license.clone();
-        } else {
-            tl.push(license.clone());
-        }
-    });
-    Some(license)
+    // Replaces any existing license for the track
+    TRACK_LICENSES.with(|tl| tl.borrow_mut().insert(track_id, license.clone()));
+    Ok(license)
 }
 
 #[ic_cdk::query]
 pub fn get_track_license(track_id: u64) -> Option<TrackLicense> {
-    TRACK_LICENSES.with(|tl| tl.borrow().iter().find(|l| l.track_id == track_id).cloned())
+    permissions::visible_track(track_id)?;
+    TRACK_LICENSES.with(|tl| tl.borrow().get(&track_id))
 }
 
-// --- API Rate Limiting (Basic, for demonstration) ---
-fn check_rate_limit(principal: Principal, max_calls: u32, window_secs: u64) -> bool {
-    let now = ic_cdk::api::time() / 1_000_000;
-    let mut allowed = false;
-    RATE_LIMITS.with(|rl| {
-        let mut rl = rl.borrow_mut();
-        if let Some(entry) = rl.iter_mut().find(|e| e.principal == principal) {
-            if now - entry.window_start > window_secs {
-                entry.window_start = now;
-                entry.call_count = 1;
-                allowed = true;
-            } else if entry.call_count < max_calls {
-                entry.call_count += 1;
-                allowed = true;
-            }
-        } else {
-            rl.push(RateLimitEntry {
-                principal,
-                last_call: now,
-                call_count: 1,
-                window_start: now,
-            });
-            allowed = true;
-        }
-    });
-    allowed
+// --- API Rate Limiting Endpoints ---
+// The limit in force for every rate-limited method
+#[ic_cdk::query]
+fn get_rate_limit_policies() -> Vec<(RateLimitedMethod, RateLimitPolicy)> {
+    rate_limits::policies()
+}
+
+// Overrides a method's rate limit; `None` restores the default
+#[ic_cdk::update]
+fn set_rate_limit_policy(method: RateLimitedMethod, policy: Option<RateLimitPolicy>) -> Result<(), ApiError> {
+    let admin = require_admin()?;
+    let details = policy.as_ref().map(|p| format!("{} calls per {}s", p.max_calls, p.window_secs));
+    rate_limits::set_policy(method, policy)?;
+    log_admin_action(admin, "set_rate_limit_policy", "RateLimitPolicy", &format!("{:?}", method), details);
+    Ok(())
 }
 
 // --- Audit Log Endpoints ---
 fn log_admin_action(admin: Principal, action: &str, target_type: &str, target_id: &str, details: Option<String>) {
     let now = ic_cdk::api::time() / 1_000_000;
-    AUDIT_LOG_ID.with(|aid| {
-        let mut aid = aid.borrow_mut();
-        let entry = AuditLogEntry {
-            id: *aid,
-            admin,
-            action: action.to_string(),
-            target_type: target_type.to_string(),
-            target_id: target_id.to_string(),
-            timestamp: now,
-            details,
-        };
-        AUDIT_LOG.with(|log| log.borrow_mut().push(entry));
-        *aid += 1;
-    });
+    let entry = AuditLogEntry {
+        id: next_id(IdCounter::AuditLog),
+        admin,
+        action: action.to_string(),
+        target_type: target_type.to_string(),
+        target_id: target_id.to_string(),
+        timestamp: now,
+        details,
+    };
+    AUDIT_LOG.with(|log| log.borrow_mut().insert(entry.id, entry));
 }
 
 #[ic_cdk::query]
-pub fn list_audit_log() -> Vec<AuditLogEntry> {
-    AUDIT_LOG.with(|log| log.borrow().clone())
+pub fn list_audit_log(page: Option<PageRequest>) -> Result<Page<AuditLogEntry>, ApiError> {
+    require_admin()?;
+    paging::page(AUDIT_LOG.with(|log| log.borrow().iter().collect()), page, SortKey::CreatedAt, true)
 }
 
 // --- Moderation Queue Endpoints ---
 #[ic_cdk::update]
-pub fn flag_content_for_moderation(target_type: ModerationTargetType, target_id: String, reason: String) -> Option<ModerationQueueItem> {
+pub fn flag_content_for_moderation(target_type: ModerationTargetType, target_id: String, reason: String) -> Result<ModerationQueueItem, ApiError> {
+    if reason.trim().is_empty() {
+        return Err(ApiError::validation("reason", "must not be empty"));
+    }
     let flagged_by = Some(caller());
     let now = ic_cdk::api::time() / 1_000_000;
-    let id = MODERATION_QUEUE_ID.with(|mid| {
-        let mut mid = mid.borrow_mut();
-        let id = *mid;
-        *mid += 1;
-        id
-    });
     let item = ModerationQueueItem {
-        id,
+        id: next_id(IdCounter::ModerationQueue),
         target_type,
         target_id,
         flagged_by,
@@ -1781,66 +2623,51 @@ pub fn flag_content_for_moderation(target_type: ModerationTargetType, target_id:
         reviewed_at: None,
         notes: None,
     };
-    MODERATION_QUEUE.with(|q| q.borrow_mut().push(item.clone()));
-    Some(item)
+    MODERATION_QUEUE.with(|q| q.borrow_mut().insert(item.id, item.clone()));
+    Ok(item)
 }
 
 #[ic_cdk::query]
-pub fn list_moderation_queue() -> Vec<ModerationQueueItem> {
-    MODERATION_QUEUE.with(|q| q.borrow().clone())
+pub fn list_moderation_queue(page: Option<PageRequest>) -> Result<Page<ModerationQueueItem>, ApiError> {
+    require_moderator()?;
+    paging::page(MODERATION_QUEUE.with(|q| q.borrow().iter().collect()), page, SortKey::CreatedAt, false)
 }
 
 #[ic_cdk::update]
-pub fn review_moderation_item(item_id: u64, status: ModerationStatus, notes: Option<String>) -> bool {
-    let reviewer = caller();
+pub fn review_moderation_item(item_id: u64, status: ModerationStatus, notes: Option<String>) -> Result<(), ApiError> {
+    let reviewer = require_moderator()?;
     let now = ic_cdk::api::time() / 1_000_000;
-    if !is_admin(reviewer) {
-        return false;
-    }
-    let mut success = false;
-    let mut target_type = String::new();
-    let mut target_id = String::new();
     let status_for_log = status.clone();
-    MODERATION_QUEUE.with(|q| {
-        let mut q = q.borrow_mut();
-        if let Some(item) = q.iter_mut().find(|i| i.id == item_id) {
+    let target = MODERATION_QUEUE.with(|q| {
+        memory::modify(&mut q.borrow_mut(), &item_id, |item| {
             item.status = status;
             item.reviewed_by = Some(reviewer);
             item.reviewed_at = Some(now);
-            item.notes = notes.clone();
-            target_type = format!("{:?}", item.target_type);
-            target_id = item.target_id.clone();
-            success = true;
-        }
+            item.notes = notes;
+            (format!("{:?}", item.target_type), item.target_id.clone())
+        })
     });
-    if success {
-        log_admin_action(
-            reviewer,
-            "review_moderation_item",
-            &target_type,
-            &target_id,
-            Some(format!("Moderation item {} reviewed: {:?}", item_id, status_for_log)),
-        );
-    }
-    success
+    let (target_type, target_id) = target.ok_or_else(|| ApiError::not_found("moderation item", item_id))?;
+    log_admin_action(
+        reviewer,
+        "review_moderation_item",
+        &target_type,
+        &target_id,
+        Some(format!("Moderation item {} reviewed: {:?}", item_id, status_for_log)),
+    );
+    Ok(())
 }
 
 // --- Suspension & Appeals Endpoints ---
 #[ic_cdk::update]
-pub fn suspend_target(target_type: SuspensionTargetType, target_id: String, reason: String, duration_secs: Option<u64>) -> Option<Suspension> {
-    let imposed_by = caller();
-    let now = ic_cdk::api::time() / 1_000_000;
-    if !is_admin(imposed_by) {
-        return None;
+pub fn suspend_target(target_type: SuspensionTargetType, target_id: String, reason: String, duration_secs: Option<u64>) -> Result<Suspension, ApiError> {
+    let imposed_by = require_admin()?;
+    if reason.trim().is_empty() {
+        return Err(ApiError::validation("reason", "must not be empty"));
     }
-    let id = SUSPENSION_ID.with(|sid| {
-        let mut sid = sid.borrow_mut();
-        let id = *sid;
-        *sid += 1;
-        id
-    });
+    let now = ic_cdk::api::time() / 1_000_000;
     let suspension = Suspension {
-        id,
+        id: next_id(IdCounter::Suspension),
         target_type: target_type.clone(),
         target_id: target_id.clone(),
         reason: reason.clone(),
@@ -1852,7 +2679,8 @@ pub fn suspend_target(target_type: SuspensionTargetType, target_id: String, reas
         lifted_at: None,
         notes: None,
     };
-    SUSPENSIONS.with(|s| s.borrow_mut().push(suspension.clone()));
+    SUSPENSIONS.with(|s| s.borrow_mut().insert(suspension.id, suspension.clone()));
+    indexes::index_suspension(&suspension);
     log_admin_action(
         imposed_by,
         "suspend_target",
@@ -1860,60 +2688,57 @@ pub fn suspend_target(target_type: SuspensionTargetType, target_id: String, reas
         &target_id,
         Some(format!("Suspension imposed: {}", reason)),
     );
-    Some(suspension)
+    Ok(suspension)
 }
 
 #[ic_cdk::update]
-pub fn lift_suspension(suspension_id: u64, notes: Option<String>) -> bool {
-    let lifter = caller();
+pub fn lift_suspension(suspension_id: u64, notes: Option<String>) -> Result<(), ApiError> {
+    let lifter = require_admin()?;
     let now = ic_cdk::api::time() / 1_000_000;
-    if !is_admin(lifter) {
-        return false;
-    }
-    let mut success = false;
-    let mut target_type = String::new();
-    let mut target_id = String::new();
-    SUSPENSIONS.with(|s| {
-        let mut s = s.borrow_mut();
-        if let Some(susp) = s.iter_mut().find(|s| s.id == suspension_id && s.status == SuspensionStatus::Active) {
-            susp.status = SuspensionStatus::Lifted;
-            susp.lifted_by = Some(lifter);
-            susp.lifted_at = Some(now);
-            susp.notes = notes.clone();
-            target_type = format!("{:?}", susp.target_type);
-            target_id = susp.target_id.clone();
-            success = true;
-        }
-    });
-    if success {
-        log_admin_action(
-            lifter,
-            "lift_suspension",
-            &target_type,
-            &target_id,
-            Some(format!("Suspension {} lifted", suspension_id)),
-        );
+    let mut susp = SUSPENSIONS
+        .with(|s| s.borrow().get(&suspension_id))
+        .ok_or_else(|| ApiError::not_found("suspension", suspension_id))?;
+    if susp.status != SuspensionStatus::Active {
+        return Err(ApiError::conflict("suspension is not active"));
     }
-    success
+    indexes::unindex_suspension(&susp);
+    susp.status = SuspensionStatus::Lifted;
+    susp.lifted_by = Some(lifter);
+    susp.lifted_at = Some(now);
+    susp.notes = notes;
+    let (target_type, target_id) = (format!("{:?}", susp.target_type), susp.target_id.clone());
+    SUSPENSIONS.with(|s| s.borrow_mut().insert(suspension_id, susp));
+    log_admin_action(
+        lifter,
+        "lift_suspension",
+        &target_type,
+        &target_id,
+        Some(format!("Suspension {} lifted", suspension_id)),
+    );
+    Ok(())
 }
 
 #[ic_cdk::query]
-pub fn list_suspensions() -> Vec<Suspension> {
-    SUSPENSIONS.with(|s| s.borrow().clone())
+pub fn list_suspensions(page: Option<PageRequest>) -> Result<Page<Suspension>, ApiError> {
+    require_admin()?;
+    paging::page(SUSPENSIONS.with(|s| s.borrow().iter().collect()), page, SortKey::CreatedAt, true)
 }
 
 #[ic_cdk::update]
-pub fn submit_suspension_appeal(suspension_id: u64, content: String) -> Option<SuspensionAppeal> {
+pub fn submit_suspension_appeal(suspension_id: u64, content: String) -> Result<SuspensionAppeal, ApiError> {
+    if content.trim().is_empty() {
+        return Err(ApiError::validation("content", "must not be empty"));
+    }
+    let suspension = SUSPENSIONS
+        .with(|s| s.borrow().get(&suspension_id))
+        .ok_or_else(|| ApiError::not_found("suspension", suspension_id))?;
+    if suspension.status != SuspensionStatus::Active {
+        return Err(ApiError::conflict("suspension is not active"));
+    }
     let submitted_by = caller();
     let now = ic_cdk::api::time() / 1_000_000;
-    let id = SUSPENSION_APPEAL_ID.with(|aid| {
-        let mut aid = aid.borrow_mut();
-        let id = *aid;
-        *aid += 1;
-        id
-    });
     let appeal = SuspensionAppeal {
-        id,
+        id: next_id(IdCounter::SuspensionAppeal),
         suspension_id,
         submitted_by,
         submitted_at: now,
@@ -1923,58 +2748,63 @@ pub fn submit_suspension_appeal(suspension_id: u64, content: String) -> Option<S
         reviewed_at: None,
         notes: None,
     };
-    SUSPENSION_APPEALS.with(|a| a.borrow_mut().push(appeal.clone()));
-    Some(appeal)
+    SUSPENSION_APPEALS.with(|a| a.borrow_mut().insert(appeal.id, appeal.clone()));
+    Ok(appeal)
 }
 
 #[ic_cdk::update]
-pub fn review_suspension_appeal(appeal_id: u64, status: AppealStatus, notes: Option<String>) -> bool {
-    let reviewer = caller();
+pub fn review_suspension_appeal(appeal_id: u64, status: AppealStatus, notes: Option<String>) -> Result<(), ApiError> {
+    let reviewer = require_admin()?;
     let now = ic_cdk::api::time() / 1_000_000;
-    if !is_admin(reviewer) {
-        return false;
-    }
-    let mut success = false;
-    let mut suspension_id = 0u64;
     let status_for_log = status.clone();
-    SUSPENSION_APPEALS.with(|a| {
-        let mut a = a.borrow_mut();
-        if let Some(appeal) = a.iter_mut().find(|ap| ap.id == appeal_id) {
+    let suspension_id = SUSPENSION_APPEALS.with(|a| {
+        memory::modify(&mut a.borrow_mut(), &appeal_id, |appeal| {
             appeal.status = status;
             appeal.reviewed_by = Some(reviewer);
             appeal.reviewed_at = Some(now);
-            appeal.notes = notes.clone();
-            suspension_id = appeal.suspension_id;
-            success = true;
-        }
-    });
-    if success {
-        log_admin_action(
-            reviewer,
-            "review_suspension_appeal",
-            "SuspensionAppeal",
-            &suspension_id.to_string(),
-            Some(format!("Appeal {} reviewed: {:?}", appeal_id, status_for_log)),
-        );
+            appeal.notes = notes;
+            appeal.suspension_id
+        })
+    })
+    .ok_or_else(|| ApiError::not_found("suspension appeal", appeal_id))?;
+    log_admin_action(
+        reviewer,
+        "review_suspension_appeal",
+        "SuspensionAppeal",
+        &suspension_id.to_string(),
+        Some(format!("Appeal {} reviewed: {:?}", appeal_id, status_for_log)),
+    );
+    Ok(())
+}
+
+// When a timed suspension runs out, in ms; `None` for an indefinite one
+fn suspension_end(suspension: &Suspension) -> Option<u64> {
+    suspension.duration_secs.map(|secs| suspension.imposed_at.saturating_add(secs.saturating_mul(1000)))
+}
+
+// Fails if the principal is under an active, unexpired user suspension
+fn ensure_not_suspended(principal: Principal) -> Result<(), ApiError> {
+    let now = ic_cdk::api::time() / 1_000_000;
+    let active = indexes::suspensions_of(principal).into_iter().find(|s| suspension_end(s).is_none_or(|end| end > now));
+    match active {
+        Some(s) => Err(ApiError::Suspended { until: suspension_end(&s), reason: s.reason }),
+        None => Ok(()),
     }
-    success
 }
 
 #[ic_cdk::query]
-pub fn list_suspension_appeals() -> Vec<SuspensionAppeal> {
-    SUSPENSION_APPEALS.with(|a| a.borrow().clone())
+pub fn list_suspension_appeals(page: Option<PageRequest>) -> Result<Page<SuspensionAppeal>, ApiError> {
+    require_admin()?;
+    paging::page(SUSPENSION_APPEALS.with(|a| a.borrow().iter().collect()), page, SortKey::CreatedAt, false)
 }
 
 // Simple content check function
 fn check_content_for_banned_words(content: &str) -> Option<String> {
     let content_lower = content.to_lowercase();
     BANNED_KEYWORDS.with(|keywords| {
-        for keyword in keywords.borrow().iter() {
-            if content_lower.contains(keyword) {
-                return Some(format!("Contains banned keyword: {}", keyword));
-            }
-        }
-        None
+        keywords.borrow().keys()
+            .find(|keyword| content_lower.contains(keyword.as_str()))
+            .map(|keyword| format!("Contains banned keyword: {}", keyword))
     })
 }
 
@@ -1983,14 +2813,8 @@ fn auto_flag_content_if_needed(target_type: ModerationTargetType, target_id: Str
     if let Some(reason) = check_content_for_banned_words(content) {
         let flagged_by = None; // Auto-flagged
         let now = ic_cdk::api::time() / 1_000_000;
-        let id = MODERATION_QUEUE_ID.with(|mid| {
-            let mut mid = mid.borrow_mut();
-            let id = *mid;
-            *mid += 1;
-            id
-        });
         let item = ModerationQueueItem {
-            id,
+            id: next_id(IdCounter::ModerationQueue),
             target_type,
             target_id,
             flagged_by,
@@ -2001,126 +2825,90 @@ fn auto_flag_content_if_needed(target_type: ModerationTargetType, target_id: Str
             reviewed_at: None,
             notes: Some("Auto-flagged by system".to_string()),
         };
-        MODERATION_QUEUE.with(|q| q.borrow_mut().push(item));
+        MODERATION_QUEUE.with(|q| q.borrow_mut().insert(item.id, item));
     }
 }
 
 // --- Automated Content Moderation Endpoints ---
 #[ic_cdk::update]
-pub fn add_banned_keyword(keyword: String) -> bool {
-    let admin = caller();
-    if !is_admin(admin) {
-        return false;
+pub fn add_banned_keyword(keyword: String) -> Result<(), ApiError> {
+    let admin = require_admin()?;
+    let keyword_lower = keyword.trim().to_lowercase();
+    if keyword_lower.is_empty() {
+        return Err(ApiError::validation("keyword", "must not be empty"));
     }
-    let keyword_lower = keyword.to_lowercase();
-    BANNED_KEYWORDS.with(|keywords| {
-        let mut keywords = keywords.borrow_mut();
-        if !keywords.contains(&keyword_lower) {
-            keywords.push(keyword_lower);
-            log_admin_action(
-                admin,
-                "add_banned_keyword",
-                "Keyword",
-                &keyword,
-                Some("Banned keyword added".to_string()),
-            );
-            true
-        } else {
-            false
-        }
-    })
+    if BANNED_KEYWORDS.with(|keywords| keywords.borrow_mut().insert(keyword_lower, ()).is_some()) {
+        return Err(ApiError::conflict("keyword is already banned"));
+    }
+    log_admin_action(
+        admin,
+        "add_banned_keyword",
+        "Keyword",
+        &keyword,
+        Some("Banned keyword added".to_string()),
+    );
+    Ok(())
 }
 
 #[ic_cdk::update]
-pub fn remove_banned_keyword(keyword: String) -> bool {
-    let admin = caller();
-    if !is_admin(admin) {
-        return false;
-    }
-    let keyword_lower = keyword.to_lowercase();
-    BANNED_KEYWORDS.with(|keywords| {
-        let mut keywords = keywords.borrow_mut();
-        let len_before = keywords.len();
-        keywords.retain(|k| k != &keyword_lower);
-        let removed = keywords.len() < len_before;
-        if removed {
-            log_admin_action(
-                admin,
-                "remove_banned_keyword",
-                "Keyword",
-                &keyword,
-                Some("Banned keyword removed".to_string()),
-            );
-        }
-        removed
-    })
+pub fn remove_banned_keyword(keyword: String) -> Result<(), ApiError> {
+    let admin = require_admin()?;
+    let keyword_lower = keyword.trim().to_lowercase();
+    BANNED_KEYWORDS
+        .with(|keywords| keywords.borrow_mut().remove(&keyword_lower))
+        .ok_or_else(|| ApiError::not_found("banned keyword", &keyword))?;
+    log_admin_action(
+        admin,
+        "remove_banned_keyword",
+        "Keyword",
+        &keyword,
+        Some("Banned keyword removed".to_string()),
+    );
+    Ok(())
 }
 
 #[ic_cdk::query]
 pub fn list_banned_keywords() -> Vec<String> {
-    BANNED_KEYWORDS.with(|keywords| keywords.borrow().clone())
+    BANNED_KEYWORDS.with(|keywords| keywords.borrow().keys().collect())
 }
 
 // --- Enhanced Version Management ---
+// Brings back a version's metadata and, if it recorded them, its files.
+// Files uploaded since the last version that the target doesn't have are deleted.
 #[ic_cdk::update]
-fn revert_to_version(track_id: u64, version_number: u32) -> Option<Track> {
+fn revert_to_version(track_id: u64, version_number: u32) -> Result<Track, ApiError> {
+    let mut track = permissions::authorize(track_id, TrackOperation::RevertVersion)?;
     let now = ic_cdk::api::time() / 1_000_000;
     let reverter = caller();
     
-    // Check if user has permission to modify this track
-    let has_permission = TRACKS.with(|tracks| {
-        if let Some(track) = tracks.borrow().iter().find(|t| t.id == track_id) {
-            track.contributors.contains(&1) // Simple check - in real app, check actual user ID
-        } else {
-            false
-        }
-    });
+    let target_version = TRACK_VERSIONS
+        .with(|tv| tv.borrow().get(&(track_id, version_number)))
+        .ok_or_else(|| ApiError::not_found("track version", version_number))?;
     
-    if !has_permission {
-        return None;
+    // Create a new version that reverts to the target version
+    let new_version_num = latest_version_number(track_id).unwrap_or(0) + 1;
+    let revert_version = TrackVersion {
+        version: new_version_num,
+        title: target_version.title.clone(),
+        description: target_version.description.clone(),
+        contributors: target_version.contributors.clone(),
+        changed_by: reverter,
+        changed_at: now,
+        change_description: Some(format!("Reverted to version {}", version_number)),
+        files: target_version.files.clone().or_else(|| Some(uploads::snapshot(track_id))),
+    };
+    TRACK_VERSIONS.with(|tv| tv.borrow_mut().insert((track_id, new_version_num), revert_version));
+    if let Some(files) = target_version.files {
+        uploads::restore(track_id, files);
     }
     
-    TRACK_VERSIONS.with(|tv| {
-        let mut tv = tv.borrow_mut();
-        if let Some((_, versions)) = tv.iter_mut().find(|(tid, _)| *tid == track_id) {
-            if let Some(target_version) = versions.iter().find(|v| v.version == version_number) {
-                // Clone the target version data to avoid borrow checker issues
-                let target_title = target_version.title.clone();
-                let target_description = target_version.description.clone();
-                let target_contributors = target_version.contributors.clone();
-                
-                // Create a new version that reverts to the target version
-                let new_version_num = versions.len() as u32 + 1;
-                let revert_version = TrackVersion {
-                    version: new_version_num,
-                    title: target_title.clone(),
-                    description: target_description.clone(),
-                    contributors: target_contributors.clone(),
-                    changed_by: reverter,
-                    changed_at: now,
-                    change_description: Some(format!("Reverted to version {}", version_number)),
-                };
-                versions.push(revert_version);
-                
-                // Update the main track
-                TRACKS.with(|tracks| {
-                    if let Some(track) = tracks.borrow_mut().iter_mut().find(|t| t.id == track_id) {
-                        track.title = target_title;
-                        track.description = target_description;
-                        track.contributors = target_contributors;
-                        track.version = new_version_num;
-                        Some(track.clone())
-                    } else {
-                        None
-                    }
-                })
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    })
+    // Update the main track
+    track.title = target_version.title;
+    track.description = target_version.description;
+    track.contributors = target_version.contributors;
+    track.version = new_version_num;
+    save_track(&track);
+    Ok(track)
 }
 
 #[ic_cdk::query]
@@ -2138,37 +2926,52 @@ pub struct VersionComparison {
     pub title_diff: Option<String>,
     pub description_diff: Option<String>,
     pub contributors_diff: Option<String>,
+    /// `None` if either version predates file tracking.
+    pub file_changes: Option<Vec<FileChange>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum FileChangeKind {
+    Added,
+    Removed,
+    Modified, // same file id, different content
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileChange {
+    pub file_id: u64,
+    pub kind: FileKind,
+    pub filename: String,
+    pub change: FileChangeKind,
 }
 
 #[ic_cdk::query]
 fn compare_versions(track_id: u64, version1: u32, version2: u32) -> Option<VersionComparison> {
+    let track = permissions::visible_track(track_id)?;
+    let viewer = permissions::viewer();
     TRACK_VERSIONS.with(|tv| {
         let tv = tv.borrow();
-        if let Some((_, versions)) = tv.iter().find(|(tid, _)| *tid == track_id) {
-            let v1 = versions.iter().find(|v| v.version == version1);
-            let v2 = versions.iter().find(|v| v.version == version2);
-            
-            if let (Some(ver1), Some(ver2)) = (v1, v2) {
-                let title_changed = ver1.title != ver2.title;
-                let description_changed = ver1.description != ver2.description;
-                let contributors_changed = ver1.contributors != ver2.contributors;
-                
-                Some(VersionComparison {
-                    version1,
-                    version2,
-                    title_changed,
-                    description_changed,
-                    contributors_changed,
-                    title_diff: if title_changed { Some(format!("{} -> {}", ver1.title, ver2.title)) } else { None },
-                    description_diff: if description_changed { Some(format!("{} -> {}", ver1.description, ver2.description)) } else { None },
-                    contributors_diff: if contributors_changed { Some(format!("{:?} -> {:?}", ver1.contributors, ver2.contributors)) } else { None },
-                })
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+        let ver1 = permissions::redact_version(&track, tv.get(&(track_id, version1))?, viewer);
+        let ver2 = permissions::redact_version(&track, tv.get(&(track_id, version2))?, viewer);
+        
+        let title_changed = ver1.title != ver2.title;
+        let description_changed = ver1.description != ver2.description;
+        let contributors_changed = ver1.contributors != ver2.contributors;
+        
+        Some(VersionComparison {
+            version1,
+            version2,
+            title_changed,
+            description_changed,
+            contributors_changed,
+            title_diff: if title_changed { Some(format!("{} -> {}", ver1.title, ver2.title)) } else { None },
+            description_diff: if description_changed { Some(format!("{} -> {}", ver1.description, ver2.description)) } else { None },
+            contributors_diff: if contributors_changed { Some(format!("{:?} -> {:?}", ver1.contributors, ver2.contributors)) } else { None },
+            file_changes: match (&ver1.files, &ver2.files) {
+                (Some(old), Some(new)) => Some(uploads::diff(old, new)),
+                _ => None,
+            },
+        })
     })
 }
 
@@ -2184,7 +2987,7 @@ pub struct TrackPerformanceMetrics {
     pub shares_count: u64,
     pub download_count: u64,
     pub engagement_rate: f64,
-    pub growth_rate: f64,
+    pub growth_rate: f64, // plays over the last 30 days against the 30 before
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -2206,7 +3009,83 @@ pub struct RevenueInsights {
     pub top_earning_tracks: Vec<(u64, u64)>, // (track_id, revenue)
     pub top_earning_artists: Vec<(u64, u64)>, // (artist_id, revenue)
     pub revenue_by_genre: Vec<(String, u64)>,
-    pub monthly_revenue_trend: Vec<(u64, u64)>, // (month_timestamp, revenue)
+    pub monthly_revenue_trend: Vec<(u64, u64)>, // (month start ms, revenue) for the last 12 months
+}
+
+// Whose counters a series covers
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum MetricScope {
+    Platform,
+    Track(u64),
+    Artist(u64), // tracks it contributes to; revenue is its royalty share
+}
+
+// Counters for one scope in one day or month
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct MetricTotals {
+    pub plays: u64,
+    pub downloads: u64,
+    pub revenue: u64,
+    pub comments: u64,
+    pub ratings: u64, // new ratings; changed ones aren't counted again
+    pub active_users: u64, // platform scope only
+    pub registrations: u64, // platform scope only
+}
+
+// MetricTotals before registrations were counted (schema v1)
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MetricTotalsV1 {
+    pub plays: u64,
+    pub downloads: u64,
+    pub revenue: u64,
+    pub comments: u64,
+    pub ratings: u64,
+    pub active_users: u64,
+}
+
+impl From<MetricTotalsV1> for MetricTotals {
+    fn from(totals: MetricTotalsV1) -> Self {
+        MetricTotals {
+            plays: totals.plays,
+            downloads: totals.downloads,
+            revenue: totals.revenue,
+            comments: totals.comments,
+            ratings: totals.ratings,
+            active_users: totals.active_users,
+            registrations: 0,
+        }
+    }
+}
+
+impl migration::Schema for MetricTotals {
+    const MIGRATIONS: &'static [migration::Migration] = &[migration::convert::<MetricTotalsV1, MetricTotals>];
+}
+
+// Change of each counter over the previous period, e.g. 0.25 for +25%; None when it was 0
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MetricGrowth {
+    pub plays: Option<f64>,
+    pub downloads: Option<f64>,
+    pub revenue: Option<f64>,
+    pub comments: Option<f64>,
+    pub ratings: Option<f64>,
+    pub active_users: Option<f64>,
+    pub registrations: Option<f64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MetricPoint {
+    pub start: u64, // period start, ms
+    pub totals: MetricTotals,
+    pub growth: MetricGrowth,
+}
+
+// When a principal was last active, for counting active users once per period
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ActiveDays {
+    pub last_day: u64,
+    pub last_month: u64,
+    pub days: u64, // distinct days with activity
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -2222,48 +3101,63 @@ pub struct PlatformAnalytics {
 }
 
 // --- Advanced Analytics Endpoints ---
+// Per-day or per-month totals for periods overlapping [from, to) (ms), oldest
+// first, including empty ones; at most metrics::MAX_SERIES_POINTS periods
+#[ic_cdk::query]
+fn get_metric_series(scope: MetricScope, granularity: Granularity, from: u64, to: u64) -> Result<Vec<MetricPoint>, ApiError> {
+    match scope {
+        MetricScope::Platform => {}
+        MetricScope::Track(id) => {
+            permissions::visible_track(id).ok_or_else(|| ApiError::not_found("track", id))?;
+        }
+        MetricScope::Artist(id) => {
+            get_artist(id).ok_or_else(|| ApiError::not_found("artist", id))?;
+        }
+    }
+    metrics::series(&scope, granularity, from, to)
+}
+
 #[ic_cdk::query]
 fn get_track_performance_metrics(track_id: u64) -> Option<TrackPerformanceMetrics> {
-    TRACKS.with(|tracks| {
-        if let Some(track) = tracks.borrow().iter().find(|t| t.id == track_id) {
-            let total_plays = track.play_count;
-            let unique_listeners = track.play_count; // Simplified - in real app, track unique listeners
-            let avg_rating = if !track.ratings.is_empty() {
-                let sum: u64 = track.ratings.iter().map(|(_, rating)| *rating as u64).sum();
-                sum as f64 / track.ratings.len() as f64
-            } else {
-                0.0
-            };
-            
-            let total_revenue = track.payments.iter().map(|p| p.amount).sum();
-            let comments_count = track.comments.len() as u64;
-            let shares_count = 0; // Not implemented yet
-            let download_count = 0; // Not implemented yet
-            
-            let engagement_rate = if total_plays > 0 {
-                (comments_count + shares_count) as f64 / total_plays as f64
-            } else {
-                0.0
-            };
-            
-            let growth_rate = 0.0; // Would need historical data
-            
-            Some(TrackPerformanceMetrics {
-                track_id,
-                total_plays,
-                unique_listeners,
-                avg_rating,
-                total_revenue,
-                comments_count,
-                shares_count,
-                download_count,
-                engagement_rate,
-                growth_rate,
-            })
+    if let Some(track) = permissions::visible_track(track_id) {
+        let total_plays = track.play_count;
+        let unique_listeners = track.unique_listeners.unwrap_or(0);
+        let avg_rating = if !track.ratings.is_empty() {
+            let sum: u64 = track.ratings.iter().map(|(_, rating)| *rating as u64).sum();
+            sum as f64 / track.ratings.len() as f64
         } else {
-            None
-        }
-    })
+            0.0
+        };
+
+        let total_revenue = track.payments.iter().map(|p| p.amount).sum();
+        let comments_count = track.comments.len() as u64;
+        let shares_count = referrals::shares_of(track_id);
+        // Counted since the analytics rollups started
+        let download_count = metrics::total(&MetricScope::Track(track_id), |t| t.downloads);
+
+        let engagement_rate = if total_plays > 0 {
+            (comments_count + shares_count) as f64 / total_plays as f64
+        } else {
+            0.0
+        };
+
+        let growth_rate = metrics::trailing_growth(&MetricScope::Track(track_id), 30, |t| t.plays);
+
+        Some(TrackPerformanceMetrics {
+            track_id,
+            total_plays,
+            unique_listeners,
+            avg_rating,
+            total_revenue,
+            comments_count,
+            shares_count,
+            download_count,
+            engagement_rate,
+            growth_rate,
+        })
+    } else {
+        None
+    }
 }
 
 #[ic_cdk::query]
@@ -2274,17 +3168,24 @@ fn get_user_engagement_metrics(user_id: u64) -> Option<UserEngagementMetrics> {
     let mut total_ratings = 0;
     let mut rating_sum = 0;
     
+    // Tracks of every artist profile the user owns, each counted once
+    let principal = indexes::user_by_id(user_id).map(|u| u.principal);
+    let track_ids: std::collections::BTreeSet<u64> = principal
+        .map(indexes::artists_of)
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(indexes::tracks_by_contributor)
+        .collect();
     TRACKS.with(|tracks| {
-        for track in tracks.borrow().iter() {
-            if track.contributors.contains(&user_id) {
-                total_tracks_created += 1;
-                total_plays_received += track.play_count;
-                total_revenue_earned += track.payments.iter().map(|p| p.amount).sum::<u64>();
-                
-                for (_, rating) in &track.ratings {
-                    total_ratings += 1;
-                    rating_sum += *rating as u64;
-                }
+        let tracks = tracks.borrow();
+        for track in track_ids.iter().filter_map(|id| tracks.get(id)) {
+            total_tracks_created += 1;
+            total_plays_received += track.play_count;
+            total_revenue_earned += track.payments.iter().map(|p| p.amount).sum::<u64>();
+            
+            for (_, rating) in &track.ratings {
+                total_ratings += 1;
+                rating_sum += *rating as u64;
             }
         }
     });
@@ -2295,10 +3196,10 @@ fn get_user_engagement_metrics(user_id: u64) -> Option<UserEngagementMetrics> {
         0.0
     };
     
-    // Simplified metrics - in real app, would calculate from activity logs
-    let active_days = 30; // Placeholder
-    let followers_count = 0; // Not implemented yet
-    let following_count = 0; // Not implemented yet
+    let active_days = principal.map(metrics::active_days).unwrap_or(0);
+    let follows = principal.map(follows::counts).unwrap_or(FollowCounts { followers: 0, following: 0 });
+    let followers_count = follows.followers;
+    let following_count = follows.following;
     let engagement_score = if total_tracks_created > 0 {
         (total_plays_received + total_revenue_earned) as f64 / total_tracks_created as f64
     } else {
@@ -2326,7 +3227,7 @@ fn get_revenue_insights() -> RevenueInsights {
     let mut genre_revenues = std::collections::HashMap::new();
     
     TRACKS.with(|tracks| {
-        for track in tracks.borrow().iter() {
+        for track in tracks.borrow().values() {
             let track_revenue = track.payments.iter().map(|p| p.amount).sum::<u64>();
             total_platform_revenue += track_revenue;
             
@@ -2347,17 +3248,17 @@ fn get_revenue_insights() -> RevenueInsights {
     });
     
     // Sort by revenue (descending)
-    track_revenues.sort_by(|a, b| b.1.cmp(&a.1));
+    track_revenues.sort_by_key(|&(_, value)| std::cmp::Reverse(value));
     let top_earning_tracks = track_revenues.into_iter().take(10).collect();
     
     let mut artist_revenue_vec: Vec<(u64, u64)> = artist_revenues.into_iter().collect();
-    artist_revenue_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    artist_revenue_vec.sort_by_key(|&(_, value)| std::cmp::Reverse(value));
     let top_earning_artists = artist_revenue_vec.into_iter().take(10).collect();
     
     let mut genre_revenue_vec: Vec<(String, u64)> = genre_revenues.into_iter().collect();
-    genre_revenue_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    genre_revenue_vec.sort_by_key(|(_, value)| std::cmp::Reverse(*value));
     
-    let monthly_revenue_trend = vec![(ic_cdk::api::time() / 1_000_000, total_platform_revenue)]; // Simplified
+    let monthly_revenue_trend = metrics::recent_months(&MetricScope::Platform, 12, |t| t.revenue);
     
     RevenueInsights {
         total_platform_revenue,
@@ -2379,7 +3280,7 @@ fn get_platform_analytics() -> PlatformAnalytics {
     let mut user_activity = std::collections::HashMap::new();
     
     TRACKS.with(|tracks| {
-        for track in tracks.borrow().iter() {
+        for track in tracks.borrow().values() {
             total_tracks += 1;
             total_plays += track.play_count;
             total_revenue += track.payments.iter().map(|p| p.amount).sum::<u64>();
@@ -2406,17 +3307,17 @@ fn get_platform_analytics() -> PlatformAnalytics {
     };
     
     let mut genre_vec: Vec<(String, u64)> = genre_counts.into_iter().collect();
-    genre_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    genre_vec.sort_by_key(|(_, value)| std::cmp::Reverse(*value));
     let most_popular_genres = genre_vec.into_iter().take(10).collect();
     
     let mut user_activity_vec: Vec<(u64, u64)> = user_activity.into_iter().collect();
-    user_activity_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    user_activity_vec.sort_by_key(|&(_, value)| std::cmp::Reverse(value));
     let most_active_users = user_activity_vec.into_iter().take(10).collect();
     
     PlatformAnalytics {
         total_tracks,
-        total_users: 100, // Placeholder - would count actual users
-        total_artists: 50, // Placeholder - would count actual artists
+        total_users: USERS.with(|u| u.borrow().len()),
+        total_artists: ARTISTS.with(|a| a.borrow().len()),
         total_plays,
         total_revenue,
         avg_track_rating,
@@ -2425,6 +3326,41 @@ fn get_platform_analytics() -> PlatformAnalytics {
     }
 }
 
+// Activity today and this month (UTC), storage and cycles, for admins
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AdminDashboard {
+    pub daily_active_users: u64,
+    pub monthly_active_users: u64,
+    pub registrations_today: u64,
+    pub registrations_this_month: u64,
+    pub total_users: u64,
+    pub total_artists: u64,
+    pub track_files: u64,
+    pub track_file_bytes: u64, // sizes of the catalog's files; shared content counts once per file
+    pub stored_bytes: u64, // content actually held, shared content once
+    pub cycle_balance: u128,
+}
+
+#[ic_cdk::query]
+fn get_admin_dashboard() -> Result<AdminDashboard, ApiError> {
+    require_admin()?;
+    let today = metrics::platform_now(Granularity::Day);
+    let month = metrics::platform_now(Granularity::Month);
+    let (track_files, track_file_bytes) = uploads::catalog_size();
+    Ok(AdminDashboard {
+        daily_active_users: today.active_users,
+        monthly_active_users: month.active_users,
+        registrations_today: today.registrations,
+        registrations_this_month: month.registrations,
+        total_users: USERS.with(|u| u.borrow().len()),
+        total_artists: ARTISTS.with(|a| a.borrow().len()),
+        track_files,
+        track_file_bytes,
+        stored_bytes: uploads::stored_bytes(),
+        cycle_balance: ic_cdk::api::canister_balance128(),
+    })
+}
+
 // --- Collaborative Workflow Management ---
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum WorkflowStatus {
@@ -2475,17 +3411,14 @@ pub struct WorkflowTemplate {
 
 // --- Collaborative Workflow Management Endpoints ---
 #[ic_cdk::update]
-fn create_workflow_step(track_id: u64, step_name: String, assigned_to: Vec<u64>, due_date: Option<u64>, notes: Option<String>) -> Option<WorkflowStep> {
-    let step_id = WORKFLOW_STEP_ID.with(|id| {
-        let mut id_mut = id.borrow_mut();
-        let current_id = *id_mut;
-        *id_mut += 1;
-        current_id
-    });
-    
-    let now = ic_cdk::api::time() / 1_000_000;
+fn create_workflow_step(track_id: u64, step_name: String, assigned_to: Vec<u64>, due_date: Option<u64>, notes: Option<String>) -> Result<WorkflowStep, ApiError> {
+    require_user()?;
+    if step_name.trim().is_empty() {
+        return Err(ApiError::validation("step_name", "must not be empty"));
+    }
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
     let step = WorkflowStep {
-        id: step_id,
+        id: next_id(IdCounter::WorkflowStep),
         track_id,
         step_name,
         status: WorkflowStatus::Planning,
@@ -2497,50 +3430,54 @@ fn create_workflow_step(track_id: u64, step_name: String, assigned_to: Vec<u64>,
     };
     
     WORKFLOW_STEPS.with(|steps| {
-        steps.borrow_mut().push(step.clone());
+        steps.borrow_mut().insert(step.id, step.clone());
     });
     
-    Some(step)
+    Ok(step)
 }
 
 #[ic_cdk::update]
-fn update_workflow_step_status(step_id: u64, status: WorkflowStatus, notes: Option<String>) -> Option<WorkflowStep> {
+fn update_workflow_step_status(step_id: u64, status: WorkflowStatus, notes: Option<String>) -> Result<WorkflowStep, ApiError> {
+    let step = WORKFLOW_STEPS
+        .with(|steps| steps.borrow().get(&step_id))
+        .ok_or_else(|| ApiError::not_found("workflow step", step_id))?;
+    permissions::authorize(step.track_id, TrackOperation::ManageWorkflow)?;
     let now = ic_cdk::api::time() / 1_000_000;
     WORKFLOW_STEPS.with(|steps| {
-        if let Some(step) = steps.borrow_mut().iter_mut().find(|s| s.id == step_id) {
-            step.status = status.clone();
-            if let Some(ref new_notes) = notes {
-                step.notes = Some(new_notes.clone());
-            }
+        memory::modify(&mut steps.borrow_mut(), &step_id, |step| {
             if matches!(status, WorkflowStatus::Published) {
                 step.completed_at = Some(now);
             }
-            Some(step.clone())
-        } else {
-            None
-        }
+            step.status = status;
+            if notes.is_some() {
+                step.notes = notes;
+            }
+            step.clone()
+        })
     })
+    .ok_or_else(|| ApiError::not_found("workflow step", step_id))
 }
 
 #[ic_cdk::query]
 fn get_track_workflow_steps(track_id: u64) -> Vec<WorkflowStep> {
+    if permissions::visible_track(track_id).is_none() {
+        return vec![];
+    }
     WORKFLOW_STEPS.with(|steps| {
-        steps.borrow().iter().filter(|s| s.track_id == track_id).cloned().collect()
+        steps.borrow().values().filter(|s| s.track_id == track_id).collect()
     })
 }
 
 #[ic_cdk::update]
-fn create_collaboration_session(track_id: u64, session_name: String, participants: Vec<u64>, notes: Option<String>) -> Option<CollaborationSession> {
-    let session_id = SESSION_ID.with(|id| {
-        let mut id_mut = id.borrow_mut();
-        let current_id = *id_mut;
-        *id_mut += 1;
-        current_id
-    });
-    
+fn create_collaboration_session(track_id: u64, session_name: String, participants: Vec<u64>, notes: Option<String>) -> Result<CollaborationSession, ApiError> {
+    require_user()?;
+    if session_name.trim().is_empty() {
+        return Err(ApiError::validation("session_name", "must not be empty"));
+    }
+    permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
     let now = ic_cdk::api::time() / 1_000_000;
     let session = CollaborationSession {
-        id: session_id,
+        id: next_id(IdCounter::Session),
         track_id,
         session_name,
         participants,
@@ -2551,46 +3488,52 @@ fn create_collaboration_session(track_id: u64, session_name: String, participant
     };
     
     COLLABORATION_SESSIONS.with(|sessions| {
-        sessions.borrow_mut().push(session.clone());
+        sessions.borrow_mut().insert(session.id, session.clone());
     });
     
-    Some(session)
+    Ok(session)
 }
 
 #[ic_cdk::update]
-fn end_collaboration_session(session_id: u64, notes: Option<String>) -> Option<CollaborationSession> {
+fn end_collaboration_session(session_id: u64, notes: Option<String>) -> Result<CollaborationSession, ApiError> {
+    let session = COLLABORATION_SESSIONS
+        .with(|sessions| sessions.borrow().get(&session_id))
+        .ok_or_else(|| ApiError::not_found("collaboration session", session_id))?;
+    permissions::authorize(session.track_id, TrackOperation::ManageWorkflow)?;
     let now = ic_cdk::api::time() / 1_000_000;
     COLLABORATION_SESSIONS.with(|sessions| {
-        if let Some(session) = sessions.borrow_mut().iter_mut().find(|s| s.id == session_id) {
+        memory::modify(&mut sessions.borrow_mut(), &session_id, |session| {
             session.end_time = Some(now);
-            if let Some(ref new_notes) = notes {
-                session.notes = Some(new_notes.clone());
+            if notes.is_some() {
+                session.notes = notes;
             }
-            Some(session.clone())
-        } else {
-            None
-        }
+            session.clone()
+        })
     })
+    .ok_or_else(|| ApiError::not_found("collaboration session", session_id))
 }
 
 #[ic_cdk::query]
 fn get_track_collaboration_sessions(track_id: u64) -> Vec<CollaborationSession> {
+    if permissions::visible_track(track_id).is_none() {
+        return vec![];
+    }
     COLLABORATION_SESSIONS.with(|sessions| {
-        sessions.borrow().iter().filter(|s| s.track_id == track_id).cloned().collect()
+        sessions.borrow().values().filter(|s| s.track_id == track_id).collect()
     })
 }
 
 #[ic_cdk::update]
-fn create_workflow_template(name: String, description: String, steps: Vec<String>, estimated_duration_days: u32, genre_specific: bool, target_genre: Option<String>) -> Option<WorkflowTemplate> {
-    let template_id = TEMPLATE_ID.with(|id| {
-        let mut id_mut = id.borrow_mut();
-        let current_id = *id_mut;
-        *id_mut += 1;
-        current_id
-    });
-    
+fn create_workflow_template(name: String, description: String, steps: Vec<String>, estimated_duration_days: u32, genre_specific: bool, target_genre: Option<String>) -> Result<WorkflowTemplate, ApiError> {
+    require_user()?;
+    if name.trim().is_empty() {
+        return Err(ApiError::validation("name", "must not be empty"));
+    }
+    if steps.is_empty() {
+        return Err(ApiError::validation("steps", "at least one step is required"));
+    }
     let template = WorkflowTemplate {
-        id: template_id,
+        id: next_id(IdCounter::Template),
         name,
         description,
         steps,
@@ -2600,87 +3543,351 @@ fn create_workflow_template(name: String, description: String, steps: Vec<String
     };
     
     WORKFLOW_TEMPLATES.with(|templates| {
-        templates.borrow_mut().push(template.clone());
+        templates.borrow_mut().insert(template.id, template.clone());
     });
     
-    Some(template)
+    Ok(template)
 }
 
 #[ic_cdk::query]
 fn get_workflow_templates() -> Vec<WorkflowTemplate> {
-    WORKFLOW_TEMPLATES.with(|templates| templates.borrow().clone())
+    WORKFLOW_TEMPLATES.with(|templates| templates.borrow().values().collect())
 }
 
 #[ic_cdk::query]
 fn get_workflow_templates_by_genre(genre: String) -> Vec<WorkflowTemplate> {
     WORKFLOW_TEMPLATES.with(|templates| {
-        templates.borrow().iter()
+        templates.borrow().values()
             .filter(|t| !t.genre_specific || t.target_genre.as_ref() == Some(&genre))
-            .cloned()
             .collect()
     })
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum FileKind {
+    Master,
+    Stem,
+    Artwork,
+    Project, // DAW project archive
+    Lyrics,
+    Other,
+}
+
+impl FileKind {
+    /// Work-in-progress material only the track's collaborators may fetch.
+    pub fn is_internal(&self) -> bool {
+        matches!(self, FileKind::Stem | FileKind::Project)
+    }
+}
+
+// Where an upload goes: a new file of the given kind, or over an existing file
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum FileTarget {
+    New(FileKind),
+    Replace(u64), // file_id; the replaced file keeps its id and kind
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct TrackFile {
+    pub track_id: u64,
+    pub file_id: u64,
+    pub kind: FileKind,
+    pub filename: String,
+    pub content_type: String,
+    /// Content of files sent in one message; empty for chunked uploads, read those with `get_track_file_chunk`.
+    pub data: Vec<u8>,
+    pub uploaded_by: Principal,
+    pub uploaded_at: u64,
+    pub content_id: Option<u64>, // key of the content in FILE_CHUNKS; None when stored inline
+    pub size: Option<u64>,
+    pub chunk_size: Option<u32>,
+    pub sha256: Option<Vec<u8>>,
+    pub audio: Option<audio::AudioMetadata>, // parsed from the file's headers on upload
+}
+
+// The one-file-per-track record, stored under the track id alone
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TrackFileV1 {
     pub track_id: u64,
     pub filename: String,
     pub content_type: String,
     pub data: Vec<u8>,
     pub uploaded_by: Principal,
     pub uploaded_at: u64,
+    pub content_id: Option<u64>,
+    pub size: Option<u64>,
+    pub chunk_size: Option<u32>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadSession {
+    pub id: u64,
+    pub track_id: u64,
+    pub owner: Principal,
+    pub filename: String,
+    pub content_type: String,
+    pub total_size: u64,
+    pub chunk_size: u32, // every chunk but the last is exactly this long
+    pub chunk_count: u32,
+    pub received_chunks: u32,
+    pub created_at: u64,
+    pub expires_at: u64, // pushed back by every chunk
+    pub target: Option<FileTarget>, // None for sessions begun before the catalog: a new Master
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadStatus {
+    pub session: UploadSession,
+    pub missing_chunks: Vec<u32>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageUsage {
+    pub used_bytes: u64, // committed files plus open upload sessions
+    pub quota_bytes: Option<u64>, // None for the default quota
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageQuota {
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+}
+
+// Bytes stored once in FILE_CHUNKS and shared by every file with the same hash
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoredContent {
+    pub sha256: Vec<u8>,
+    pub size: u64,
+    pub chunk_size: u32,
+    pub holders: u64, // tracks whose catalog or versions refer to it
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileVerification {
+    pub file_id: u64,
+    pub size: u64,
+    pub stored_bytes: u64,
+    pub expected_sha256: Option<Vec<u8>>,
+    pub actual_sha256: Vec<u8>,
+    pub missing_chunks: Vec<u32>,
+    /// Every chunk is present and the bytes hash to the recorded SHA-256.
+    pub intact: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DuplicateFile {
+    pub track_id: u64,
+    pub file_id: u64,
+    pub filename: String,
+    pub uploaded_by: Principal,
+    pub uploaded_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DuplicateContent {
+    pub sha256: Vec<u8>,
+    pub size: u64,
+    pub files: Vec<DuplicateFile>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum WaveformStatus {
+    Pending,
+    Ready,
+    Failed { reason: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Waveform {
+    pub status: WaveformStatus,
+    pub chunk_size: u32, // of the content it's computed from
+    pub size: u64,
+    pub frames_per_peak: u64, // 0 until the first batch runs
+    pub peaks: Vec<(i16, i16)>, // (min, max) across all channels, in file order
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WaveformPeaks {
+    pub status: WaveformStatus,
+    pub frames_per_peak: u64,
+    /// Empty until the status is Ready.
+    pub peaks: Vec<(i16, i16)>,
+}
+
+// Stores a file small enough to fit in one message. Larger files go through begin_upload.
 #[ic_cdk::update]
-pub fn upload_track_file(track_id: u64, filename: String, content_type: String, data: Vec<u8>) -> Result<(), String> {
+pub fn upload_track_file(track_id: u64, target: FileTarget, filename: String, content_type: String, data: Vec<u8>) -> Result<TrackFile, ApiError> {
     let caller = ic_cdk::api::caller();
-    // Check file size
-    if data.len() > MAX_FILE_SIZE {
-        return Err("File too large (max 10MB)".to_string());
-    }
-    // Only owner or collaborator can upload
-    let track = get_track(track_id).ok_or("Track not found")?;
-    let user_id = get_user_id_by_principal(caller).ok_or("User not found")?;
-    let is_owner = track.contributors.get(0) == Some(&user_id);
-    let is_collab = track.contributors.contains(&user_id);
-    if !is_owner && !is_collab {
-        return Err("Not authorized to upload file for this track".to_string());
+    permissions::authorize_file_target(track_id, &target)?;
+    if data.is_empty() {
+        return Err(ApiError::validation("data", "file is empty"));
     }
-    let now = ic_cdk::api::time() / 1_000_000;
-    let file = TrackFile {
-        track_id,
-        filename,
-        content_type,
-        data,
-        uploaded_by: caller,
-        uploaded_at: now,
-    };
-    TRACK_FILES.with(|files| files.borrow_mut().insert(track_id, file));
+    if data.len() as u64 > uploads::MAX_CHUNK_SIZE as u64 {
+        return Err(ApiError::validation("data", "file too large for one message, use begin_upload"));
+    }
+    uploads::store_inline(track_id, target, caller, filename, content_type, data)
+}
+
+// Starts a resumable upload of `total_size` bytes sent in `chunk_size` pieces.
+// The full size is charged against the caller's storage quota up front.
+#[ic_cdk::update]
+fn begin_upload(track_id: u64, target: FileTarget, filename: String, content_type: String, total_size: u64, chunk_size: u32) -> Result<UploadSession, ApiError> {
+    permissions::authorize_file_target(track_id, &target)?;
+    uploads::begin(track_id, target, caller(), filename, content_type, total_size, chunk_size)
+}
+
+#[ic_cdk::update]
+fn upload_chunk(upload_id: u64, index: u32, data: Vec<u8>) -> Result<(), ApiError> {
+    let session = uploads::open_session(upload_id, caller())?;
+    uploads::put_chunk(session, index, data)
+}
+
+// Lets a client resume: which chunks of the upload are still missing
+#[ic_cdk::query]
+fn get_upload_status(upload_id: u64) -> Result<UploadStatus, ApiError> {
+    let session = UPLOAD_SESSIONS
+        .with(|s| s.borrow().get(&upload_id))
+        .filter(|s| s.owner == caller())
+        .ok_or_else(|| ApiError::not_found("upload", upload_id))?;
+    Ok(uploads::status(session))
+}
+
+// Verifies the SHA-256 of the uploaded bytes and stores them as the session's target file
+#[ic_cdk::update]
+fn commit_upload(upload_id: u64, sha256: Vec<u8>) -> Result<TrackFile, ApiError> {
+    let session = uploads::open_session(upload_id, caller())?;
+    // The caller's role may have changed since the upload began
+    permissions::authorize_file_target(session.track_id, &uploads::target_of(&session))?;
+    uploads::commit(session, sha256)
+}
+
+#[ic_cdk::update]
+fn abort_upload(upload_id: u64) -> Result<(), ApiError> {
+    let session = uploads::open_session(upload_id, caller())?;
+    uploads::abort(&session);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_storage_usage() -> StorageQuota {
+    let usage = uploads::usage_of(caller());
+    StorageQuota {
+        used_bytes: usage.used_bytes,
+        quota_bytes: usage.quota_bytes.unwrap_or(uploads::DEFAULT_STORAGE_QUOTA),
+    }
+}
+
+// Overrides a user's storage quota; `None` restores the default
+#[ic_cdk::update]
+fn set_storage_quota(user: Principal, quota_bytes: Option<u64>) -> Result<(), ApiError> {
+    let admin = require_admin()?;
+    uploads::set_quota(user, quota_bytes);
+    log_admin_action(admin, "set_storage_quota", "User", &user.to_text(), quota_bytes.map(|q| format!("Quota set to {} bytes", q)));
+    Ok(())
+}
+
+// The track's files the caller may see, without their content; fetch that
+// with get_track_file or get_track_file_chunk
+#[ic_cdk::query]
+fn list_track_files(track_id: u64) -> Vec<TrackFile> {
+    permissions::visible_files(track_id)
+        .into_iter()
+        .map(|file| TrackFile { data: vec![], ..file })
+        .collect()
+}
+
+#[ic_cdk::query]
+pub fn get_track_file(track_id: u64, file_id: u64) -> Option<TrackFile> {
+    permissions::visible_file(track_id, file_id)
+}
+
+// Chunk `index` of a file; inline files are a single chunk 0
+#[ic_cdk::query]
+fn get_track_file_chunk(track_id: u64, file_id: u64, index: u32) -> Option<Vec<u8>> {
+    let file = permissions::visible_file(track_id, file_id)?;
+    uploads::read_chunk(&file, index)
+}
+
+// Rehashes a file's stored bytes to check them against the recorded SHA-256
+#[ic_cdk::query]
+fn verify_track_file(track_id: u64, file_id: u64) -> Result<FileVerification, ApiError> {
+    let file = permissions::visible_file(track_id, file_id).ok_or_else(|| ApiError::not_found("file", file_id))?;
+    uploads::verify(&file)
+}
+
+// Identical content uploaded by more than one user, e.g. someone re-uploading another artist's master
+#[ic_cdk::query]
+fn list_duplicate_files() -> Result<Vec<DuplicateContent>, ApiError> {
+    require_admin()?;
+    Ok(uploads::duplicates())
+}
+
+// Min/max peaks of a WAV file for drawing its waveform, merged down to at most `resolution` buckets
+#[ic_cdk::query]
+fn get_track_waveform(track_id: u64, file_id: u64, resolution: u32) -> Result<WaveformPeaks, ApiError> {
+    let file = permissions::visible_file(track_id, file_id).ok_or_else(|| ApiError::not_found("file", file_id))?;
+    waveforms::peaks(&file, resolution)
+}
+
+// Uploaders may delete their own files; other people's need the Owner role
+#[ic_cdk::update]
+fn delete_track_file(track_id: u64, file_id: u64) -> Result<(), ApiError> {
+    permissions::authorize_file_target(track_id, &FileTarget::Replace(file_id))?;
+    let file = TRACK_FILES
+        .with(|files| files.borrow_mut().remove(&(track_id, file_id)))
+        .ok_or_else(|| ApiError::not_found("file", file_id))?;
+    uploads::discard_file(&file);
+    uploads::refresh_duration(track_id);
     Ok(())
 }
 
+// Serves `/tracks/{id}/audio` to browsers through the HTTP gateway (see http.rs)
 #[ic_cdk::query]
-pub fn get_track_file(track_id: u64) -> Option<TrackFile> {
-    TRACK_FILES.with(|files| files.borrow().get(&track_id).map(|f| f.clone()))
+fn http_request(req: http::HttpRequest) -> http::HttpResponse {
+    http::serve(req)
 }
 
-// Helper to get user id by principal
+#[ic_cdk::query]
+fn http_streaming_callback(token: http::StreamingCallbackToken) -> http::StreamingCallbackHttpResponse {
+    http::stream(token)
+}
+
+// --- Identity ---
+// Numeric user id of a registered principal
 fn get_user_id_by_principal(principal: Principal) -> Option<u64> {
-    // This is a stub. Replace with your actual user lookup logic.
-    // For now, just return Some(1) for demo purposes.
-    Some(1)
+    USERS.with(|users| users.borrow().get(&principal).map(|u| u.id))
 }
 
-impl Storable for TrackFile {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
-    }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
-    }
+// User id of the caller, refusing unregistered and suspended principals
+fn require_user() -> Result<u64, ApiError> {
+    let principal = caller();
+    let user_id = get_user_id_by_principal(principal)
+        .ok_or_else(|| ApiError::unauthorized("caller is not a registered user"))?;
+    ensure_not_suspended(principal)?;
+    Ok(user_id)
+}
+
+// Ids of every artist profile registered by the principal, lowest first
+fn artist_ids_of(principal: Principal) -> Vec<u64> {
+    indexes::artists_of(principal)
 }
 
-impl BoundedStorable for TrackFile {
-    const MAX_SIZE: u32 = 10 * 1024 * 1024 + 1024; // 10MB + metadata buffer
-    const IS_FIXED_SIZE: bool = false;
-}
\ No newline at end of file
+// The principal's first registered artist profile, used when it acts as an artist
+fn primary_artist_id(principal: Principal) -> Option<u64> {
+    artist_ids_of(principal).first().copied()
+}
+
+fn owns_artist(principal: Principal, artist_id: u64) -> bool {
+    get_artist(artist_id).is_some_and(|a| a.user_principal == principal)
+}
+
+#[ic_cdk::query]
+fn whoami() -> Identity {
+    let principal = caller();
+    Identity {
+        principal,
+        user_id: get_user_id_by_principal(principal),
+        artist_ids: artist_ids_of(principal),
+    }
+}
@@ -0,0 +1,118 @@
+// Stable memory layout.
+//
+// Every collection gets its own virtual memory from a single MemoryManager so
+// that all canister state lives in stable memory and survives upgrades. The ids
+// below are part of the persisted layout: only ever append new ones, never
+// renumber or reuse an id.
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+pub const ID_COUNTERS: MemoryId = MemoryId::new(0);
+pub const ARTISTS: MemoryId = MemoryId::new(1);
+pub const TRACKS: MemoryId = MemoryId::new(2);
+pub const TRACK_VERSIONS: MemoryId = MemoryId::new(3);
+pub const ACTIVITY_LOG: MemoryId = MemoryId::new(4);
+pub const COLLAB_REQUESTS: MemoryId = MemoryId::new(5);
+pub const TASKS: MemoryId = MemoryId::new(6);
+pub const USERS: MemoryId = MemoryId::new(7);
+pub const USER_ACTIVITY_LOG: MemoryId = MemoryId::new(8);
+pub const NOTIFICATIONS: MemoryId = MemoryId::new(9);
+pub const FOLLOWED_ARTISTS: MemoryId = MemoryId::new(10);
+pub const FOLLOWED_TRACKS: MemoryId = MemoryId::new(11);
+pub const PLAYLISTS: MemoryId = MemoryId::new(12);
+pub const PLAY_COUNTS: MemoryId = MemoryId::new(13);
+pub const MESSAGES: MemoryId = MemoryId::new(14);
+pub const REPORTS: MemoryId = MemoryId::new(15);
+pub const TRACK_LICENSES: MemoryId = MemoryId::new(16);
+// 17 held RATE_LIMITS keyed by principal alone; it was never written and is not reused
+pub const AUDIT_LOG: MemoryId = MemoryId::new(18);
+pub const MODERATION_QUEUE: MemoryId = MemoryId::new(19);
+pub const SUSPENSIONS: MemoryId = MemoryId::new(20);
+pub const SUSPENSION_APPEALS: MemoryId = MemoryId::new(21);
+pub const BANNED_KEYWORDS: MemoryId = MemoryId::new(22);
+pub const WORKFLOW_STEPS: MemoryId = MemoryId::new(23);
+pub const COLLABORATION_SESSIONS: MemoryId = MemoryId::new(24);
+pub const WORKFLOW_TEMPLATES: MemoryId = MemoryId::new(25);
+pub const TRACK_FILES: MemoryId = MemoryId::new(26);
+pub const SCHEMA_VERSIONS: MemoryId = MemoryId::new(27);
+pub const CONFIG: MemoryId = MemoryId::new(28);
+pub const ROYALTY_ENTRIES: MemoryId = MemoryId::new(29);
+pub const ARTIST_ROYALTY_ENTRIES: MemoryId = MemoryId::new(30);
+pub const WITHDRAWALS: MemoryId = MemoryId::new(31);
+pub const UPLOAD_SESSIONS: MemoryId = MemoryId::new(32);
+pub const FILE_CHUNKS: MemoryId = MemoryId::new(33);
+pub const STORAGE_USAGE: MemoryId = MemoryId::new(34);
+pub const TRACK_FILE_CATALOG: MemoryId = MemoryId::new(35);
+pub const WAVEFORMS: MemoryId = MemoryId::new(36);
+pub const WAVEFORM_QUEUE: MemoryId = MemoryId::new(37);
+pub const STORED_CONTENTS: MemoryId = MemoryId::new(38);
+pub const CONTENT_HASHES: MemoryId = MemoryId::new(39);
+pub const SEARCH_POSTINGS: MemoryId = MemoryId::new(40);
+pub const SEARCH_DOCS: MemoryId = MemoryId::new(41);
+pub const TRACKS_BY_CONTRIBUTOR: MemoryId = MemoryId::new(42);
+pub const TRACKS_BY_TAG: MemoryId = MemoryId::new(43);
+pub const TRACKS_BY_GENRE: MemoryId = MemoryId::new(44);
+pub const USER_PRINCIPALS: MemoryId = MemoryId::new(45);
+pub const USERS_BY_USERNAME: MemoryId = MemoryId::new(46);
+pub const ARTISTS_BY_OWNER: MemoryId = MemoryId::new(47);
+pub const PLAYLISTS_BY_OWNER: MemoryId = MemoryId::new(48);
+pub const RATE_LIMIT_WINDOWS: MemoryId = MemoryId::new(49);
+pub const RATE_LIMIT_POLICIES: MemoryId = MemoryId::new(50);
+pub const PLAYS_BY_HOUR: MemoryId = MemoryId::new(51);
+pub const PLAYS_BY_DAY: MemoryId = MemoryId::new(52);
+pub const METRICS: MemoryId = MemoryId::new(53);
+pub const ACTIVE_DAYS: MemoryId = MemoryId::new(54);
+pub const ARTIST_FOLLOWERS: MemoryId = MemoryId::new(55);
+pub const TRACK_FOLLOWERS: MemoryId = MemoryId::new(56);
+pub const FOLLOW_COUNTS: MemoryId = MemoryId::new(57);
+pub const REFERRAL_CODES: MemoryId = MemoryId::new(58);
+pub const REFERRALS_BY_SHARER: MemoryId = MemoryId::new(59);
+pub const TRACK_SHARES: MemoryId = MemoryId::new(60);
+pub const REFERRER_BALANCES: MemoryId = MemoryId::new(61);
+pub const REFERRAL_WITHDRAWALS: MemoryId = MemoryId::new(62);
+pub const CONTENT_CHARGES: MemoryId = MemoryId::new(63);
+pub const SUSPENSIONS_BY_USER: MemoryId = MemoryId::new(64);
+pub const TRACK_PAYOUT_ENTRIES: MemoryId = MemoryId::new(65);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Read-modify-write helper, since stable maps hand out copies rather than references.
+pub fn modify<K, V, R>(map: &mut StableBTreeMap<K, V, Memory>, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let mut value = map.get(key)?;
+    let result = f(&mut value);
+    map.insert(key.clone(), value);
+    Some(result)
+}
+
+// Stores a type as its schema-versioned Candid encoding (see migration.rs),
+// with no size bound.
+macro_rules! candid_storable {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl ic_stable_structures::Storable for $t {
+                fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                    std::borrow::Cow::Owned($crate::migration::encode(self))
+                }
+                fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                    $crate::migration::decode(&bytes)
+                }
+                const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+            }
+        )*
+    };
+}
+pub(crate) use candid_storable;
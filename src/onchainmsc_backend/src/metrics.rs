@@ -0,0 +1,478 @@
+// Rolled-up analytics.
+//
+// Every play, download, payment, comment, new rating and registration adds to a day bucket and
+// a month bucket (calendar months, UTC) of each scope it belongs to: the
+// platform, the track and the track's contributing artists. An artist's revenue
+// is its royalty share, not the whole payment. METRICS is keyed by
+// (scope, id, granularity, period start in ms), so a series is one range scan.
+//
+// ACTIVE_DAYS remembers the last day and month each principal did something,
+// so the platform's `active_users` counts a principal once per period, and
+// `days` counts the days they were active.
+//
+// `backfill` rebuilds everything that has timestamps when METRICS_VERSION
+// changes: plays from PLAYS_BY_DAY, payments, royalty credits, and activity and
+// registrations from the user activity log. Downloads, comments and ratings before that are not counted.
+// Registrations were counted after the first rollups were built, so
+// `backfill_registrations` adds them once to rollups that predate them.
+use crate::{
+    update_config, ActiveDays, ApiError, EntryReference, Granularity, LedgerAccount, MetricGrowth, MetricPoint,
+    MetricScope, MetricTotals, Track, ACTIVE_DAYS, CONFIG, METRICS, PLAYS_BY_DAY, ROYALTY_ENTRIES, TRACKS,
+    USER_ACTIVITY_LOG,
+};
+use crate::plays::{DAY_MS, HOUR_MS};
+use candid::Principal;
+use ic_cdk::api::caller;
+use ic_stable_structures::{storable::Bound, Storable};
+use std::borrow::Cow;
+
+/// Bump when bucketing changes; the aggregates are rebuilt on upgrade.
+const METRICS_VERSION: u32 = 1;
+/// Caps how many periods one series query covers.
+pub const MAX_SERIES_POINTS: usize = 400;
+
+const PLATFORM: (u8, u64) = (0, 0);
+
+/// A (scope, id) pair's bucket for one period; ordered so one scope's periods are contiguous.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SeriesKey {
+    scope: u8,
+    id: u64,
+    granularity: u8,
+    start: u64,
+}
+
+impl Storable for SeriesKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(18);
+        bytes.push(self.scope);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.push(self.granularity);
+        bytes.extend_from_slice(&self.start.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        SeriesKey {
+            scope: bytes[0],
+            id: u64::from_be_bytes(bytes[1..9].try_into().expect("8-byte scope id")),
+            granularity: bytes[9],
+            start: u64::from_be_bytes(bytes[10..18].try_into().expect("8-byte period start")),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 18, is_fixed_size: true };
+}
+
+/// Event counters kept per scope.
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Plays,
+    Downloads,
+    Comments,
+    Ratings,
+}
+
+fn now_ms() -> u64 {
+    ic_cdk::api::time() / 1_000_000
+}
+
+fn scope_key(scope: &MetricScope) -> (u8, u64) {
+    match scope {
+        MetricScope::Platform => PLATFORM,
+        MetricScope::Track(id) => (1, *id),
+        MetricScope::Artist(id) => (2, *id),
+    }
+}
+
+fn granularity_code(granularity: Granularity) -> u8 {
+    match granularity {
+        Granularity::Hour => 0,
+        Granularity::Day => 1,
+        Granularity::Month => 2,
+    }
+}
+
+// Days since 1970-01-01 <-> proleptic Gregorian (year, month, day), after
+// Howard Hinnant's `days_from_civil`/`civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + (month <= 2) as i64, month)
+}
+
+fn days_from_civil(year: i64, month: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Start of the period containing `t`, all in ms.
+pub fn period_start(granularity: Granularity, t: u64) -> u64 {
+    match granularity {
+        Granularity::Hour => t - t % HOUR_MS,
+        Granularity::Day => t - t % DAY_MS,
+        Granularity::Month => {
+            let (year, month) = civil_from_days((t / DAY_MS) as i64);
+            days_from_civil(year, month) as u64 * DAY_MS
+        }
+    }
+}
+
+fn next_period(granularity: Granularity, start: u64) -> u64 {
+    match granularity {
+        Granularity::Hour => start + HOUR_MS,
+        Granularity::Day => start + DAY_MS,
+        Granularity::Month => {
+            let (year, month) = civil_from_days((start / DAY_MS) as i64);
+            let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+            days_from_civil(year, month) as u64 * DAY_MS
+        }
+    }
+}
+
+fn previous_period(granularity: Granularity, start: u64) -> Option<u64> {
+    start.checked_sub(1).map(|t| period_start(granularity, t))
+}
+
+fn key(scope: (u8, u64), granularity: Granularity, start: u64) -> SeriesKey {
+    SeriesKey { scope: scope.0, id: scope.1, granularity: granularity_code(granularity), start }
+}
+
+/// Applies `f` to the scope's day and month buckets containing `at`.
+fn add(scope: (u8, u64), at: u64, f: impl Fn(&mut MetricTotals)) {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        for granularity in [Granularity::Day, Granularity::Month] {
+            let key = key(scope, granularity, period_start(granularity, at));
+            let mut totals = m.get(&key).unwrap_or_default();
+            f(&mut totals);
+            m.insert(key, totals);
+        }
+    });
+}
+
+fn scopes_of(track: &Track) -> Vec<(u8, u64)> {
+    let mut scopes = vec![PLATFORM, (1, track.id)];
+    scopes.extend(track.contributors.iter().map(|artist_id| (2, *artist_id)));
+    scopes
+}
+
+fn count(totals: &mut MetricTotals, counter: Counter, n: u64) {
+    match counter {
+        Counter::Plays => totals.plays += n,
+        Counter::Downloads => totals.downloads += n,
+        Counter::Comments => totals.comments += n,
+        Counter::Ratings => totals.ratings += n,
+    }
+}
+
+/// Counts one event on the track, its artists and the platform, and marks the caller active.
+pub fn track_event(track: &Track, counter: Counter) {
+    let now = now_ms();
+    for scope in scopes_of(track) {
+        add(scope, now, |t| count(t, counter, 1));
+    }
+    mark_active(caller(), now);
+}
+
+/// Adds a track payment to the track's and the platform's revenue.
+pub fn payment(track_id: u64, amount: u64, at: u64) {
+    add(PLATFORM, at, |t| t.revenue += amount);
+    add((1, track_id), at, |t| t.revenue += amount);
+}
+
+/// Counts a new user on the platform.
+pub fn registration(at: u64) {
+    add(PLATFORM, at, |t| t.registrations += 1);
+}
+
+/// Adds an artist's royalty share of a payment to its revenue.
+pub fn artist_revenue(artist_id: u64, amount: u64, at: u64) {
+    add((2, artist_id), at, |t| t.revenue += amount);
+}
+
+/// Counts the principal as active on the day and month of `at`.
+pub fn mark_active(principal: Principal, at: u64) {
+    if principal == Principal::anonymous() {
+        return;
+    }
+    let day = period_start(Granularity::Day, at);
+    let month = period_start(Granularity::Month, at);
+    let mut active = ACTIVE_DAYS
+        .with(|a| a.borrow().get(&principal))
+        .unwrap_or(ActiveDays { last_day: 0, last_month: 0, days: 0 });
+    if active.last_day >= day {
+        return;
+    }
+    active.last_day = day;
+    active.days += 1;
+    let new_month = active.last_month < month;
+    active.last_month = month;
+    ACTIVE_DAYS.with(|a| a.borrow_mut().insert(principal, active));
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        let periods = [(Granularity::Day, day, true), (Granularity::Month, month, new_month)];
+        for (granularity, start, first) in periods {
+            if first {
+                let key = key(PLATFORM, granularity, start);
+                let mut totals = m.get(&key).unwrap_or_default();
+                totals.active_users += 1;
+                m.insert(key, totals);
+            }
+        }
+    });
+}
+
+/// Days the principal has been active on.
+pub fn active_days(principal: Principal) -> u64 {
+    ACTIVE_DAYS.with(|a| a.borrow().get(&principal)).map(|a| a.days).unwrap_or(0)
+}
+
+/// The platform's totals for the day or month containing now.
+pub fn platform_now(granularity: Granularity) -> MetricTotals {
+    totals_at(PLATFORM, granularity, period_start(granularity, now_ms()))
+}
+
+fn totals_at(scope: (u8, u64), granularity: Granularity, start: u64) -> MetricTotals {
+    METRICS.with(|m| m.borrow().get(&key(scope, granularity, start))).unwrap_or_default()
+}
+
+fn change(previous: u64, current: u64) -> Option<f64> {
+    (previous > 0).then(|| (current as f64 - previous as f64) / previous as f64)
+}
+
+fn growth(previous: &MetricTotals, current: &MetricTotals) -> MetricGrowth {
+    MetricGrowth {
+        plays: change(previous.plays, current.plays),
+        downloads: change(previous.downloads, current.downloads),
+        revenue: change(previous.revenue, current.revenue),
+        comments: change(previous.comments, current.comments),
+        ratings: change(previous.ratings, current.ratings),
+        active_users: change(previous.active_users, current.active_users),
+        registrations: change(previous.registrations, current.registrations),
+    }
+}
+
+/// One point per period overlapping [from, to), oldest first, each with its
+/// change over the period before it.
+pub fn series(scope: &MetricScope, granularity: Granularity, from: u64, to: u64) -> Result<Vec<MetricPoint>, ApiError> {
+    if granularity == Granularity::Hour {
+        return Err(ApiError::validation("granularity", "series are kept per day and per month"));
+    }
+    let scope = scope_key(scope);
+    let mut starts = vec![];
+    let mut start = period_start(granularity, from);
+    while start < to {
+        if starts.len() == MAX_SERIES_POINTS {
+            return Err(ApiError::validation("to", &format!("range covers more than {} periods", MAX_SERIES_POINTS)));
+        }
+        starts.push(start);
+        start = next_period(granularity, start);
+    }
+    let mut previous = starts
+        .first()
+        .and_then(|first| previous_period(granularity, *first))
+        .map(|start| totals_at(scope, granularity, start))
+        .unwrap_or_default();
+    Ok(starts
+        .into_iter()
+        .map(|start| {
+            let totals = totals_at(scope, granularity, start);
+            let point = MetricPoint { start, growth: growth(&previous, &totals), totals: totals.clone() };
+            previous = totals;
+            point
+        })
+        .collect())
+}
+
+fn sum_days(scope: (u8, u64), from: u64, to: u64, pick: fn(&MetricTotals) -> u64) -> u64 {
+    let range = key(scope, Granularity::Day, from)..key(scope, Granularity::Day, to);
+    METRICS.with(|m| m.borrow().range(range).map(|(_, totals)| pick(&totals)).sum())
+}
+
+/// Change of a counter over the last `days` days (today included) against the `days` before; 0.0 without earlier data.
+pub fn trailing_growth(scope: &MetricScope, days: u64, pick: fn(&MetricTotals) -> u64) -> f64 {
+    let scope = scope_key(scope);
+    let end = period_start(Granularity::Day, now_ms()) + DAY_MS;
+    let middle = end.saturating_sub(days * DAY_MS);
+    let start = middle.saturating_sub(days * DAY_MS);
+    change(sum_days(scope, start, middle, pick), sum_days(scope, middle, end, pick)).unwrap_or(0.0)
+}
+
+/// A counter summed over every month the scope has data for.
+pub fn total(scope: &MetricScope, pick: fn(&MetricTotals) -> u64) -> u64 {
+    let scope = scope_key(scope);
+    let range = key(scope, Granularity::Month, 0)..=key(scope, Granularity::Month, u64::MAX);
+    METRICS.with(|m| m.borrow().range(range).map(|(_, totals)| pick(&totals)).sum())
+}
+
+/// The last `months` calendar months (this one included) of a counter, oldest first.
+pub fn recent_months(scope: &MetricScope, months: usize, pick: fn(&MetricTotals) -> u64) -> Vec<(u64, u64)> {
+    let mut start = period_start(Granularity::Month, now_ms());
+    for _ in 1..months {
+        start = previous_period(Granularity::Month, start).unwrap_or(0);
+    }
+    series(scope, Granularity::Month, start, now_ms() + 1)
+        .map(|points| points.iter().map(|p| (p.start, pick(&p.totals))).collect())
+        .unwrap_or_default()
+}
+
+/// Drops a deleted track's series; the platform and artist totals keep its history.
+pub fn remove_track(track_id: u64) {
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        let first = SeriesKey { scope: 1, id: track_id, granularity: 0, start: 0 };
+        let last = SeriesKey { scope: 1, id: track_id, granularity: u8::MAX, start: u64::MAX };
+        let keys: Vec<SeriesKey> = m.range(first..=last).map(|(k, _)| k).collect();
+        for key in &keys {
+            m.remove(key);
+        }
+    });
+}
+
+/// Rebuilds the aggregates from timestamped history if they were built by another METRICS_VERSION (or never).
+pub fn backfill() {
+    if CONFIG.with(|c| c.borrow().get().metrics_version) == Some(METRICS_VERSION) {
+        return;
+    }
+    METRICS.with(|m| {
+        let mut m = m.borrow_mut();
+        let keys: Vec<SeriesKey> = m.keys().collect();
+        for key in &keys {
+            m.remove(key);
+        }
+    });
+    ACTIVE_DAYS.with(|a| {
+        let mut a = a.borrow_mut();
+        let keys: Vec<Principal> = a.keys().collect();
+        for key in &keys {
+            a.remove(key);
+        }
+    });
+    let days: Vec<((u64, u64), u64)> = PLAYS_BY_DAY.with(|b| b.borrow().iter().map(|(k, b)| (k, b.plays)).collect());
+    for ((track_id, day), plays) in &days {
+        let scopes = match TRACKS.with(|t| t.borrow().get(track_id)) {
+            Some(track) => scopes_of(&track),
+            None => vec![PLATFORM],
+        };
+        for scope in scopes {
+            add(scope, *day, |t| t.plays += plays);
+        }
+    }
+    let tracks: Vec<Track> = TRACKS.with(|t| t.borrow().values().collect());
+    for track in &tracks {
+        for p in &track.payments {
+            payment(track.id, p.amount, p.timestamp);
+        }
+    }
+    ROYALTY_ENTRIES.with(|e| {
+        for entry in e.borrow().values() {
+            if let (LedgerAccount::Artist(artist_id), EntryReference::Payment { .. }) = (&entry.credit, &entry.reference) {
+                artist_revenue(*artist_id, entry.amount, entry.timestamp);
+            }
+        }
+    });
+    // The log is appended in time order, which mark_active relies on
+    USER_ACTIVITY_LOG.with(|log| {
+        for activity in log.borrow().values() {
+            mark_active(activity.principal, activity.timestamp);
+            if activity.action == "register_user" {
+                registration(activity.timestamp);
+            }
+        }
+    });
+    update_config(|c| {
+        c.metrics_version = Some(METRICS_VERSION);
+        c.registrations_backfilled = Some(true);
+    });
+    ic_cdk::println!("Rebuilt analytics from {} play days and {} tracks' payments", days.len(), tracks.len());
+}
+
+/// Adds the logged registrations to rollups built before registrations were counted, once.
+pub fn backfill_registrations() {
+    if CONFIG.with(|c| c.borrow().get().registrations_backfilled) == Some(true) {
+        return;
+    }
+    let mut count = 0;
+    USER_ACTIVITY_LOG.with(|log| {
+        for activity in log.borrow().values().filter(|a| a.action == "register_user") {
+            registration(activity.timestamp);
+            count += 1;
+        }
+    });
+    update_config(|c| c.registrations_backfilled = Some(true));
+    ic_cdk::println!("Added {} logged registrations to the analytics", count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(days: u64) -> u64 {
+        days * DAY_MS
+    }
+
+    #[test]
+    fn civil_round_trip() {
+        for (year, month, days) in [(1970, 1, 0), (1969, 12, -31), (2023, 12, 19_692), (2024, 1, 19_723), (2000, 3, 11_017), (2100, 3, 47_541)] {
+            assert_eq!(days_from_civil(year, month), days, "{}-{}", year, month);
+            assert_eq!(civil_from_days(days), (year, month), "{}", days);
+        }
+        // The last day before the epoch is still December 1969
+        assert_eq!(civil_from_days(-1), (1969, 12));
+    }
+
+    #[test]
+    fn leap_year_february() {
+        // 2024 and 2000 are leap years; 2100 is not
+        assert_eq!(days_from_civil(2024, 3) - days_from_civil(2024, 2), 29);
+        assert_eq!(days_from_civil(2000, 3) - days_from_civil(2000, 2), 29);
+        assert_eq!(days_from_civil(2100, 3) - days_from_civil(2100, 2), 28);
+        assert_eq!(days_from_civil(2023, 3) - days_from_civil(2023, 2), 28);
+        assert_eq!(civil_from_days(19_782), (2024, 2));
+        assert_eq!(civil_from_days(19_783), (2024, 3));
+    }
+
+    #[test]
+    fn month_periods() {
+        // 2024-02-29 12:00 falls in February
+        let t = day(19_782) + 12 * HOUR_MS;
+        assert_eq!(period_start(Granularity::Month, t), day(19_754));
+        assert_eq!(next_period(Granularity::Month, day(19_754)), day(19_783));
+        // December rolls over into January of the next year
+        assert_eq!(period_start(Granularity::Month, day(19_722) + DAY_MS - 1), day(19_692));
+        assert_eq!(next_period(Granularity::Month, day(19_692)), day(19_723));
+        assert_eq!(period_start(Granularity::Month, day(19_723)), day(19_723));
+    }
+
+    #[test]
+    fn epoch_periods() {
+        for granularity in [Granularity::Hour, Granularity::Day, Granularity::Month] {
+            assert_eq!(period_start(granularity, 0), 0);
+        }
+        assert_eq!(next_period(Granularity::Hour, 0), HOUR_MS);
+        assert_eq!(next_period(Granularity::Day, 0), DAY_MS);
+        assert_eq!(next_period(Granularity::Month, 0), day(31));
+        assert_eq!(period_start(Granularity::Month, day(31) - 1), 0);
+        assert_eq!(previous_period(Granularity::Month, 0), None);
+        assert_eq!(previous_period(Granularity::Month, day(31)), Some(0));
+    }
+
+    #[test]
+    fn hour_and_day_periods() {
+        let t = day(19_723) + 5 * HOUR_MS + 1234;
+        assert_eq!(period_start(Granularity::Hour, t), day(19_723) + 5 * HOUR_MS);
+        assert_eq!(period_start(Granularity::Day, t), day(19_723));
+        assert_eq!(next_period(Granularity::Day, day(19_722)), day(19_723));
+    }
+}
@@ -0,0 +1,240 @@
+// Schema versioning for records kept in stable memory.
+//
+// Every record is stored as a 4 byte header (b"SV" + little-endian u16 schema
+// version) followed by its Candid encoding. Records written before the header
+// existed are bare Candid and count as version 1.
+//
+// Adding an `Option` field needs no migration, Candid decodes a missing
+// optional field as `None`. For any other layout change to a stored type `Foo`:
+//   1. copy the current definition to a `FooV<n>` struct next to it,
+//   2. change `Foo`,
+//   3. append `migration::convert::<FooV<n>, Foo>` (with a `From` impl) to
+//      `Foo::MIGRATIONS`; earlier steps must keep targeting their own `FooV<n>`.
+// The schema version is `MIGRATIONS.len() + 1`, so step 3 bumps it and the next
+// `post_upgrade` rewrites every stored `Foo` under the new layout. Until then
+// reads upgrade old records on the fly.
+use crate::memory::{self, Memory};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+const MAGIC: &[u8; 2] = b"SV";
+const HEADER_LEN: usize = 4;
+
+/// Upgrades a Candid payload by exactly one schema version.
+pub type Migration = fn(&[u8]) -> Vec<u8>;
+
+pub trait Schema: CandidType + for<'de> Deserialize<'de> {
+    /// `MIGRATIONS[i]` upgrades a payload from version `i + 1` to `i + 2`.
+    const MIGRATIONS: &'static [Migration] = &[];
+    const VERSION: u16 = Self::MIGRATIONS.len() as u16 + 1;
+}
+
+// Stored types that have never changed layout.
+macro_rules! initial_schema {
+    ($($t:ty),* $(,)?) => {
+        $( impl $crate::migration::Schema for $t {} )*
+    };
+}
+pub(crate) use initial_schema;
+
+thread_local! {
+    // Collection name -> schema version its records were last rewritten at.
+    static SCHEMA_VERSIONS: RefCell<StableBTreeMap<String, u16, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::SCHEMA_VERSIONS)));
+}
+
+pub fn encode<T: Schema>(value: &T) -> Vec<u8> {
+    let payload = candid::encode_one(value).unwrap();
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+pub fn decode<T: Schema>(bytes: &[u8]) -> T {
+    let (version, payload) = match bytes.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 2 => (u16::from_le_bytes([rest[0], rest[1]]), &rest[2..]),
+        _ => (1, bytes),
+    };
+    if version == 0 || version > T::VERSION {
+        ic_cdk::trap(&format!(
+            "{} record has schema version {}, this build only knows up to {}",
+            std::any::type_name::<T>(),
+            version,
+            T::VERSION
+        ));
+    }
+    let mut payload = Cow::Borrowed(payload);
+    for step in &T::MIGRATIONS[version as usize - 1..] {
+        payload = Cow::Owned(step(&payload));
+    }
+    candid::decode_one(&payload).unwrap_or_else(|e| {
+        ic_cdk::trap(&format!(
+            "failed to decode {} record (stored at schema version {}): {}",
+            std::any::type_name::<T>(),
+            version,
+            e
+        ))
+    })
+}
+
+/// Migration step that decodes an `Old` payload and re-encodes it as `New`.
+pub fn convert<Old, New>(bytes: &[u8]) -> Vec<u8>
+where
+    Old: CandidType + for<'de> Deserialize<'de>,
+    New: CandidType + From<Old>,
+{
+    let old: Old = candid::decode_one(bytes).unwrap_or_else(|e| {
+        ic_cdk::trap(&format!("failed to decode {} during migration: {}", std::any::type_name::<Old>(), e))
+    });
+    candid::encode_one(New::from(old)).unwrap()
+}
+
+/// A stored collection of versioned records, as seen by the upgrade hooks.
+pub struct Collection {
+    name: &'static str,
+    version: u16,
+    rewrite: Box<dyn Fn() -> u64>,
+}
+
+pub fn collection<K, V>(name: &'static str, store: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>) -> Collection
+where
+    K: Storable + Ord + Clone + 'static,
+    V: Schema + Storable + 'static,
+{
+    Collection {
+        name,
+        version: V::VERSION,
+        rewrite: Box::new(move || {
+            store.with(|map| {
+                let mut map = map.borrow_mut();
+                let keys: Vec<K> = map.keys().collect();
+                for key in &keys {
+                    if let Some(value) = map.get(key) {
+                        map.insert(key.clone(), value);
+                    }
+                }
+                keys.len() as u64
+            })
+        }),
+    }
+}
+
+/// Records the current schema versions on a fresh install.
+pub fn stamp(collections: &[Collection]) {
+    SCHEMA_VERSIONS.with(|versions| {
+        let mut versions = versions.borrow_mut();
+        for c in collections {
+            versions.insert(c.name.to_string(), c.version);
+        }
+    });
+}
+
+/// Rewrites every collection whose stored records predate its current schema.
+pub fn run(collections: &[Collection]) {
+    for c in collections {
+        let stored = SCHEMA_VERSIONS.with(|versions| versions.borrow().get(&c.name.to_string())).unwrap_or(1);
+        if stored >= c.version {
+            continue;
+        }
+        let count = (c.rewrite)();
+        SCHEMA_VERSIONS.with(|versions| versions.borrow_mut().insert(c.name.to_string(), c.version));
+        ic_cdk::println!("Migrated {} {} records from schema v{} to v{}", count, c.name, stored, c.version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+    struct SongV1 {
+        title: String,
+    }
+
+    #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+    struct SongV2 {
+        title: String,
+        plays: u64,
+    }
+
+    #[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+    struct Song {
+        title: String,
+        plays: u64,
+        artist: String,
+    }
+
+    impl From<SongV1> for SongV2 {
+        fn from(song: SongV1) -> Self {
+            SongV2 { title: song.title, plays: 0 }
+        }
+    }
+
+    impl From<SongV2> for Song {
+        fn from(song: SongV2) -> Self {
+            Song { title: song.title, plays: song.plays, artist: "unknown".to_string() }
+        }
+    }
+
+    impl Schema for SongV2 {
+        const MIGRATIONS: &'static [Migration] = &[convert::<SongV1, SongV2>];
+    }
+
+    impl Schema for Song {
+        const MIGRATIONS: &'static [Migration] = &[convert::<SongV1, SongV2>, convert::<SongV2, Song>];
+    }
+
+    fn song() -> Song {
+        Song { title: "Intro".to_string(), plays: 7, artist: "Someone".to_string() }
+    }
+
+    #[test]
+    fn encode_writes_the_header() {
+        let bytes = encode(&song());
+        assert_eq!(Song::VERSION, 3);
+        assert_eq!(&bytes[..HEADER_LEN], b"SV\x03\x00");
+        assert_eq!(&bytes[HEADER_LEN..], candid::encode_one(song()).unwrap());
+    }
+
+    #[test]
+    fn current_records_round_trip() {
+        assert_eq!(decode::<Song>(&encode(&song())), song());
+    }
+
+    #[test]
+    fn headerless_records_are_version_1() {
+        let legacy = candid::encode_one(SongV1 { title: "Intro".to_string() }).unwrap();
+        let expected = Song { title: "Intro".to_string(), plays: 0, artist: "unknown".to_string() };
+        assert_eq!(decode::<Song>(&legacy), expected);
+    }
+
+    #[test]
+    fn older_versions_run_every_later_step() {
+        // Written by a build whose current layout was SongV2
+        let v2 = encode(&SongV2 { title: "Intro".to_string(), plays: 3 });
+        assert_eq!(&v2[..HEADER_LEN], b"SV\x02\x00");
+        let expected = Song { title: "Intro".to_string(), plays: 3, artist: "unknown".to_string() };
+        assert_eq!(decode::<Song>(&v2), expected);
+    }
+
+    #[test]
+    fn legacy_users_get_an_id() {
+        let legacy = crate::UserV1 {
+            principal: candid::Principal::anonymous(),
+            username: "listener".to_string(),
+            bio: None,
+            avatar_url: None,
+            role: crate::UserRole::User,
+        };
+        let user: crate::User = decode(&candid::encode_one(legacy).unwrap());
+        assert_eq!(user.username, "listener");
+        assert!(user.id > 0);
+        let again: crate::User = decode(&encode(&user));
+        assert_eq!(again.id, user.id);
+    }
+}
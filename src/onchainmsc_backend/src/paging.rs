@@ -0,0 +1,261 @@
+// Cursor pagination shared by the list and search queries.
+//
+// A list is sorted by (sort value, id), ids breaking ties, and a page is the next
+// `limit` items after the cursor's position in that order. The cursor encodes
+// the sort key, the direction and the last item's (value, id), so a page stays
+// correct when items are added or removed between calls; it's hex so clients
+// treat it as opaque. Lists are still read in full for each page, which bounds
+// the reply, not the work.
+use crate::{
+    Activity, ApiError, Artist, AuditLogEntry, ModerationQueueItem, Page, PageRequest, Report, SearchHit,
+    SearchResult, SortKey, Suspension, SuspensionAppeal, Track, User,
+};
+
+pub const DEFAULT_PAGE_LIMIT: u32 = 25;
+pub const MAX_PAGE_LIMIT: u32 = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortValue {
+    Number(u64),
+    Text(String),
+}
+
+/// Items a list query can sort.
+pub trait Sortable {
+    fn supports(key: &SortKey) -> bool;
+    /// Only called with keys `supports` accepts.
+    fn sort_value(&self, key: &SortKey) -> SortValue;
+}
+
+fn key_code(key: &SortKey) -> char {
+    match key {
+        SortKey::CreatedAt => 'c',
+        SortKey::PlayCount => 'p',
+        SortKey::Rating => 'r',
+        SortKey::Title => 't',
+        SortKey::Relevance => 's',
+    }
+}
+
+fn encode_cursor(key: &SortKey, descending: bool, value: &SortValue, id: u64) -> String {
+    let value = match value {
+        SortValue::Number(n) => format!("n{}", n),
+        SortValue::Text(text) => format!("t{}", text),
+    };
+    let plain = format!("{}|{}|{}|{}", key_code(key), descending as u8, value, id);
+    plain.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str, key: &SortKey, descending: bool) -> Result<(SortValue, u64), ApiError> {
+    let invalid = || ApiError::validation("cursor", "not a cursor returned by this query");
+    let bytes: Vec<u8> = (0..cursor.len())
+        .step_by(2)
+        .map(|i| {
+            // from_str_radix would also take a sign, as in "+f"
+            let pair = cursor.get(i..i + 2).filter(|pair| pair.bytes().all(|b| b.is_ascii_hexdigit()))?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    let plain = String::from_utf8(bytes).map_err(|_| invalid())?;
+    let (rest, id) = plain.rsplit_once('|').ok_or_else(invalid)?;
+    let mut parts = rest.splitn(3, '|');
+    let (Some(code), Some(desc), Some(value)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    if code.chars().ne([key_code(key)]) || desc != (descending as u8).to_string() {
+        return Err(ApiError::validation("cursor", "was issued for a different sort order"));
+    }
+    let value = match value.split_at_checked(1) {
+        Some(("n", n)) => SortValue::Number(n.parse().map_err(|_| invalid())?),
+        Some(("t", text)) => SortValue::Text(text.to_string()),
+        _ => return Err(invalid()),
+    };
+    Ok((value, id.parse().map_err(|_| invalid())?))
+}
+
+/// One page of `items` (each with its id) in the order `request` asks for.
+/// Without a sort key, `default_key` applies; without a direction, `default_descending`.
+pub fn page<T: Sortable>(
+    items: Vec<(u64, T)>,
+    request: Option<PageRequest>,
+    default_key: SortKey,
+    default_descending: bool,
+) -> Result<Page<T>, ApiError> {
+    let request = request.unwrap_or_default();
+    let key = request.sort.unwrap_or(default_key);
+    if !T::supports(&key) {
+        return Err(ApiError::validation("sort", &format!("this list can't be sorted by {:?}", key)));
+    }
+    let descending = request.descending.unwrap_or(default_descending);
+    let limit = request.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT) as usize;
+    let after = request.cursor.as_deref().map(|cursor| decode_cursor(cursor, &key, descending)).transpose()?;
+
+    let total = items.len() as u64;
+    let mut sorted: Vec<(SortValue, u64, T)> =
+        items.into_iter().map(|(id, item)| (item.sort_value(&key), id, item)).collect();
+    sorted.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    if descending {
+        sorted.reverse();
+    }
+    let start = match &after {
+        None => 0,
+        Some((value, id)) => sorted.partition_point(|(v, i, _)| {
+            let position = (v, *i).cmp(&(value, *id));
+            if descending { position.is_ge() } else { position.is_le() }
+        }),
+    };
+    let rest = sorted.len() - start.min(sorted.len());
+    let mut window: Vec<(SortValue, u64, T)> = sorted.into_iter().skip(start).take(limit).collect();
+    let next_cursor = match window.last() {
+        Some((value, id, _)) if rest > limit => Some(encode_cursor(&key, descending, value, *id)),
+        _ => None,
+    };
+    Ok(Page { items: window.drain(..).map(|(_, _, item)| item).collect(), next_cursor, total })
+}
+
+fn average_rating(track: &Track) -> u64 {
+    // Hundredths of a star, so 4.25 sorts above 4.2
+    let sum: u64 = track.ratings.iter().map(|(_, r)| *r as u64).sum();
+    (sum * 100).checked_div(track.ratings.len() as u64).unwrap_or(0)
+}
+
+fn title_value(title: &str) -> SortValue {
+    SortValue::Text(title.to_lowercase())
+}
+
+// Tracks, artists and users have no creation time; their ids are assigned in creation order
+impl Sortable for Track {
+    fn supports(key: &SortKey) -> bool {
+        *key != SortKey::Relevance
+    }
+    fn sort_value(&self, key: &SortKey) -> SortValue {
+        match key {
+            SortKey::PlayCount => SortValue::Number(self.play_count),
+            SortKey::Rating => SortValue::Number(average_rating(self)),
+            SortKey::Title => title_value(&self.title),
+            SortKey::CreatedAt | SortKey::Relevance => SortValue::Number(self.id),
+        }
+    }
+}
+
+impl Sortable for Artist {
+    fn supports(key: &SortKey) -> bool {
+        matches!(key, SortKey::CreatedAt | SortKey::Title)
+    }
+    fn sort_value(&self, key: &SortKey) -> SortValue {
+        match key {
+            SortKey::Title => title_value(&self.name),
+            _ => SortValue::Number(self.id),
+        }
+    }
+}
+
+impl Sortable for User {
+    fn supports(key: &SortKey) -> bool {
+        matches!(key, SortKey::CreatedAt | SortKey::Title)
+    }
+    fn sort_value(&self, key: &SortKey) -> SortValue {
+        match key {
+            SortKey::Title => title_value(&self.username),
+            _ => SortValue::Number(self.id),
+        }
+    }
+}
+
+/// Records only sortable by when they happened.
+macro_rules! sortable_by_time {
+    ($($ty:ty => $field:ident),* $(,)?) => {
+        $(
+            impl Sortable for $ty {
+                fn supports(key: &SortKey) -> bool {
+                    *key == SortKey::CreatedAt
+                }
+                fn sort_value(&self, _key: &SortKey) -> SortValue {
+                    SortValue::Number(self.$field)
+                }
+            }
+        )*
+    };
+}
+
+sortable_by_time!(
+    Report => created_at,
+    AuditLogEntry => timestamp,
+    ModerationQueueItem => created_at,
+    Suspension => imposed_at,
+    SuspensionAppeal => submitted_at,
+    Activity => timestamp,
+);
+
+/// Search hits rank by score; other keys sort by the matched document, and
+/// artists and users count as zero plays and no rating.
+impl Sortable for SearchHit {
+    fn supports(_key: &SortKey) -> bool {
+        true
+    }
+    fn sort_value(&self, key: &SortKey) -> SortValue {
+        match (key, &self.result) {
+            (SortKey::Relevance, _) => SortValue::Number(self.score as u64),
+            (_, SearchResult::Track(track)) => track.sort_value(key),
+            (SortKey::PlayCount | SortKey::Rating, _) => SortValue::Number(0),
+            (_, SearchResult::Artist(artist)) => artist.sort_value(key),
+            (_, SearchResult::User(user)) => user.sort_value(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(plain: &str) -> String {
+        plain.bytes().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for value in [SortValue::Number(42), SortValue::Text("a|b ünïcode".to_string())] {
+            let cursor = encode_cursor(&SortKey::Title, true, &value, 7);
+            assert_eq!(decode_cursor(&cursor, &SortKey::Title, true), Ok((value, 7)));
+        }
+    }
+
+    #[test]
+    fn cursors_are_tied_to_their_sort_order() {
+        let cursor = encode_cursor(&SortKey::PlayCount, false, &SortValue::Number(3), 1);
+        assert!(decode_cursor(&cursor, &SortKey::Rating, false).is_err());
+        assert!(decode_cursor(&cursor, &SortKey::PlayCount, true).is_err());
+    }
+
+    #[test]
+    fn odd_length_cursors_are_rejected() {
+        let cursor = encode_cursor(&SortKey::CreatedAt, false, &SortValue::Number(3), 1);
+        assert!(decode_cursor(&cursor[..cursor.len() - 1], &SortKey::CreatedAt, false).is_err());
+    }
+
+    #[test]
+    fn non_hex_cursors_are_rejected() {
+        let cursor = encode_cursor(&SortKey::CreatedAt, false, &SortValue::Number(3), 1);
+        for bad in [cursor.replacen(&cursor[..2], "zz", 1), cursor.replacen(&cursor[..2], "+6", 1), "not a cursor".to_string()] {
+            assert!(decode_cursor(&bad, &SortKey::CreatedAt, false).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn non_ascii_cursors_are_rejected() {
+        // "é" is two bytes, so pairs would split it
+        assert!(decode_cursor("é6", &SortKey::CreatedAt, false).is_err());
+        assert!(decode_cursor("6é", &SortKey::CreatedAt, false).is_err());
+        assert!(decode_cursor("ééé", &SortKey::CreatedAt, false).is_err());
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        for plain in ["c|0|n3", "c|0|n3|x", "c|0|x3|1", "c|0|nabc|1", "c|0||1"] {
+            assert!(decode_cursor(&hex(plain), &SortKey::CreatedAt, false).is_err(), "{:?}", plain);
+        }
+        // Valid hex that isn't UTF-8
+        assert!(decode_cursor("ff", &SortKey::CreatedAt, false).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+// Track permission matrix: the minimum TrackRole each track operation needs,
+// the guard every track-mutating endpoint calls before touching the track, and
+// the visibility rules every read path filters through.
+use crate::{
+    get_user_id_by_principal, require_user, ApiError, FileTarget, Track, TrackFile, TrackRole, TrackVersion,
+    TrackVisibility, TRACKS, TRACK_FILES,
+};
+use ic_cdk::api::caller;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackOperation {
+    UpdateMetadata,
+    SetContributors,
+    AddVersion,
+    RevertVersion,
+    ManageTags,
+    SetGenre,
+    UploadFile,
+    ManageFiles,
+    ManageWorkflow,
+    SetSplits,
+    SetVisibility,
+    SetLicense,
+    SetDownloadable,
+    SetReferralShare,
+    InviteUser,
+    AssignRole,
+    Delete,
+}
+
+impl TrackOperation {
+    pub fn required_role(self) -> TrackRole {
+        use TrackOperation::*;
+        match self {
+            UpdateMetadata | AddVersion | RevertVersion | ManageTags | SetGenre | UploadFile
+            | ManageWorkflow => TrackRole::Collaborator,
+            SetContributors | SetSplits | SetVisibility | SetLicense | SetDownloadable | SetReferralShare | InviteUser
+            | AssignRole | Delete | ManageFiles => TrackRole::Owner,
+        }
+    }
+}
+
+impl TrackRole {
+    fn level(&self) -> u8 {
+        match self {
+            TrackRole::Viewer => 0,
+            TrackRole::Collaborator => 1,
+            TrackRole::Owner => 2,
+        }
+    }
+
+    /// Whether holding this role satisfies a requirement of `required`.
+    pub fn grants(&self, required: &TrackRole) -> bool {
+        self.level() >= required.level()
+    }
+}
+
+pub fn role_of(track: &Track, user_id: u64) -> Option<TrackRole> {
+    track.roles.iter().find(|(id, _)| *id == user_id).map(|(_, role)| role.clone())
+}
+
+/// Loads the track and checks that the caller holds the role `op` requires.
+pub fn authorize(track_id: u64, op: TrackOperation) -> Result<Track, ApiError> {
+    let track = TRACKS
+        .with(|tracks| tracks.borrow().get(&track_id))
+        .ok_or_else(|| ApiError::not_found("track", track_id))?;
+    let user_id = require_user()?;
+    let required = op.required_role();
+    let actual = role_of(&track, user_id);
+    if actual.as_ref().is_some_and(|role| role.grants(&required)) {
+        Ok(track)
+    } else {
+        Err(ApiError::InsufficientRole { required, actual })
+    }
+}
+
+/// Whether `viewer` (a user id, `None` for anonymous or unregistered callers)
+/// may see the track. Any role grants access; invitees also see InviteOnly tracks.
+pub fn can_view(track: &Track, viewer: Option<u64>) -> bool {
+    match track.visibility {
+        TrackVisibility::Public => true,
+        TrackVisibility::Private => viewer.is_some_and(|id| role_of(track, id).is_some()),
+        TrackVisibility::InviteOnly => {
+            viewer.is_some_and(|id| role_of(track, id).is_some() || track.invited.contains(&id))
+        }
+    }
+}
+
+/// User id of the caller, if registered.
+pub fn viewer() -> Option<u64> {
+    get_user_id_by_principal(caller())
+}
+
+/// Loads the track if the caller may see it; hidden tracks look like missing ones.
+pub fn visible_track(track_id: u64) -> Option<Track> {
+    let track = TRACKS.with(|tracks| tracks.borrow().get(&track_id))?;
+    can_view(&track, viewer()).then_some(track)
+}
+
+/// Every track the caller may see that matches `filter`.
+pub fn visible_tracks(filter: impl Fn(&Track) -> bool) -> Vec<Track> {
+    let viewer = viewer();
+    TRACKS.with(|tracks| {
+        tracks.borrow().values().filter(|t| can_view(t, viewer) && filter(t)).collect()
+    })
+}
+
+/// Like `visible_tracks`, but only looks at the given ids (e.g. from an index in indexes.rs).
+pub fn visible_tracks_among(ids: Vec<u64>, filter: impl Fn(&Track) -> bool) -> Vec<Track> {
+    let viewer = viewer();
+    TRACKS.with(|tracks| {
+        let tracks = tracks.borrow();
+        ids.iter().filter_map(|id| tracks.get(id)).filter(|t| can_view(t, viewer) && filter(t)).collect()
+    })
+}
+
+/// Checks the caller may write `target`: uploading needs Collaborator, and
+/// replacing or deleting someone else's file needs Owner.
+pub fn authorize_file_target(track_id: u64, target: &FileTarget) -> Result<Track, ApiError> {
+    let track = authorize(track_id, TrackOperation::UploadFile)?;
+    if let FileTarget::Replace(file_id) = target {
+        let file = TRACK_FILES
+            .with(|files| files.borrow().get(&(track_id, *file_id)))
+            .ok_or_else(|| ApiError::not_found("file", file_id))?;
+        if file.uploaded_by != caller() {
+            return authorize(track_id, TrackOperation::ManageFiles);
+        }
+    }
+    Ok(track)
+}
+
+/// Whether `viewer` may fetch `file` of a track they can see. Stems and
+/// project archives are limited to collaborators.
+pub fn can_read_file(track: &Track, file: &TrackFile, viewer: Option<u64>) -> bool {
+    !file.kind.is_internal()
+        || viewer.is_some_and(|id| role_of(track, id).is_some_and(|role| role.grants(&TrackRole::Collaborator)))
+}
+
+/// Loads a file if the caller may fetch it; hidden files look like missing ones.
+pub fn visible_file(track_id: u64, file_id: u64) -> Option<TrackFile> {
+    let track = visible_track(track_id)?;
+    let file = TRACK_FILES.with(|files| files.borrow().get(&(track_id, file_id)))?;
+    can_read_file(&track, &file, viewer()).then_some(file)
+}
+
+/// Every file of the track the caller may fetch, oldest first.
+pub fn visible_files(track_id: u64) -> Vec<TrackFile> {
+    let Some(track) = visible_track(track_id) else {
+        return vec![];
+    };
+    let viewer = viewer();
+    TRACK_FILES.with(|files| {
+        files
+            .borrow()
+            .range((track_id, 0)..=(track_id, u64::MAX))
+            .map(|(_, file)| file)
+            .filter(|file| can_read_file(&track, file, viewer))
+            .collect()
+    })
+}
+
+/// Drops the files `viewer` may not fetch from a version's file set.
+pub fn redact_version(track: &Track, mut version: TrackVersion, viewer: Option<u64>) -> TrackVersion {
+    if let Some(files) = &mut version.files {
+        files.retain(|file| can_read_file(track, file, viewer));
+    }
+    version
+}
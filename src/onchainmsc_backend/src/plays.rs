@@ -0,0 +1,186 @@
+// Play events.
+//
+// Every play goes through `record`. A principal's play of a track counts at most
+// once per PLAY_COOLDOWN_MS; replays inside the cooldown are accepted and
+// dropped. A counted play bumps the principal's PLAY_COUNTS entry, the track's
+// `play_count`, and the track's hourly and daily buckets. The first play by a
+// principal also bumps the track's `unique_listeners` and the buckets'
+// `new_listeners`. Anonymous callers all share one principal and can't be told
+// apart, so their plays are accepted and not counted.
+//
+// Buckets are keyed by (track_id, start of the hour or day in ms). Empty
+// periods are not stored. Hourly buckets are dropped by a timer after
+// HOURLY_RETENTION_MS; daily ones are kept.
+use crate::{
+    memory, metrics, permissions, ApiError, Granularity, PlayBucket, PlayDownloadCount, PLAYS_BY_DAY, PLAYS_BY_HOUR,
+    PLAY_COUNTS, TRACKS,
+};
+use candid::Principal;
+use ic_cdk::api::caller;
+use std::collections::BTreeMap;
+
+pub const PLAY_COOLDOWN_MS: u64 = 30 * 60 * 1000;
+pub const HOUR_MS: u64 = 60 * 60 * 1000;
+pub const DAY_MS: u64 = 24 * HOUR_MS;
+pub const HOURLY_RETENTION_MS: u64 = 31 * DAY_MS;
+/// Caps how many buckets one history query returns.
+pub const MAX_HISTORY_BUCKETS: usize = 1000;
+
+fn now_ms() -> u64 {
+    ic_cdk::api::time() / 1_000_000
+}
+
+fn bump(granularity: Granularity, track_id: u64, now: u64, new_listener: bool) {
+    let update = |bucket: Option<PlayBucket>| {
+        let mut bucket = bucket.unwrap_or(PlayBucket { plays: 0, new_listeners: 0 });
+        bucket.plays += 1;
+        bucket.new_listeners += new_listener as u64;
+        bucket
+    };
+    match granularity {
+        Granularity::Hour => PLAYS_BY_HOUR.with(|b| {
+            let mut b = b.borrow_mut();
+            let key = (track_id, now - now % HOUR_MS);
+            let bucket = update(b.get(&key));
+            b.insert(key, bucket);
+        }),
+        Granularity::Day => PLAYS_BY_DAY.with(|b| {
+            let mut b = b.borrow_mut();
+            let key = (track_id, now - now % DAY_MS);
+            let bucket = update(b.get(&key));
+            b.insert(key, bucket);
+        }),
+        Granularity::Month => unreachable!("monthly plays are summed from the daily buckets"),
+    }
+}
+
+/// Counts a play of the track by the caller unless they played it within the
+/// cooldown; returns whether it counted.
+pub fn record(track_id: u64) -> Result<bool, ApiError> {
+    let principal = caller();
+    let track = permissions::visible_track(track_id).ok_or_else(|| ApiError::not_found("track", track_id))?;
+    if principal == Principal::anonymous() {
+        return Ok(false);
+    }
+    let now = now_ms();
+    let mut entry = PLAY_COUNTS
+        .with(|pc| pc.borrow().get(&(principal, track_id)))
+        .unwrap_or(PlayDownloadCount { principal, track_id, play_count: 0, download_count: 0, last_played_at: None });
+    if entry.last_played_at.is_some_and(|last| now < last.saturating_add(PLAY_COOLDOWN_MS)) {
+        return Ok(false);
+    }
+    let new_listener = entry.play_count == 0;
+    entry.play_count += 1;
+    entry.last_played_at = Some(now);
+    PLAY_COUNTS.with(|pc| pc.borrow_mut().insert((principal, track_id), entry));
+    TRACKS.with(|tracks| {
+        memory::modify(&mut tracks.borrow_mut(), &track_id, |track| {
+            track.play_count += 1;
+            if new_listener {
+                track.unique_listeners = Some(track.unique_listeners.unwrap_or(0) + 1);
+            }
+        })
+    });
+    bump(Granularity::Hour, track_id, now, new_listener);
+    bump(Granularity::Day, track_id, now, new_listener);
+    metrics::track_event(&track, metrics::Counter::Plays);
+    Ok(true)
+}
+
+/// The track's non-empty buckets starting in [from, to), oldest first, keyed by
+/// start time. Months are summed from the daily buckets.
+pub fn history(track_id: u64, granularity: Granularity, from: u64, to: u64) -> Vec<(u64, PlayBucket)> {
+    if from >= to {
+        return vec![];
+    }
+    let range = (track_id, from)..(track_id, to);
+    let collect = |(key, bucket): ((u64, u64), PlayBucket)| (key.1, bucket);
+    match granularity {
+        Granularity::Hour => {
+            PLAYS_BY_HOUR.with(|b| b.borrow().range(range).take(MAX_HISTORY_BUCKETS).map(collect).collect())
+        }
+        Granularity::Day => {
+            PLAYS_BY_DAY.with(|b| b.borrow().range(range).take(MAX_HISTORY_BUCKETS).map(collect).collect())
+        }
+        Granularity::Month => {
+            let mut months: Vec<(u64, PlayBucket)> = vec![];
+            PLAYS_BY_DAY.with(|b| {
+                for (start, day) in b.borrow().range(range).map(collect) {
+                    let month = metrics::period_start(Granularity::Month, start);
+                    match months.last_mut() {
+                        Some((last, bucket)) if *last == month => {
+                            bucket.plays += day.plays;
+                            bucket.new_listeners += day.new_listeners;
+                        }
+                        _ => months.push((month, day)),
+                    }
+                }
+            });
+            // The month `from` falls in only counts if it starts at `from`
+            months.retain(|(start, _)| *start >= from);
+            months.truncate(MAX_HISTORY_BUCKETS);
+            months
+        }
+    }
+}
+
+/// Drops the buckets of a deleted track.
+pub fn remove_track(track_id: u64) {
+    PLAYS_BY_HOUR.with(|b| {
+        let mut b = b.borrow_mut();
+        let keys: Vec<(u64, u64)> = b.range((track_id, 0)..=(track_id, u64::MAX)).map(|(k, _)| k).collect();
+        for key in &keys {
+            b.remove(key);
+        }
+    });
+    PLAYS_BY_DAY.with(|b| {
+        let mut b = b.borrow_mut();
+        let keys: Vec<(u64, u64)> = b.range((track_id, 0)..=(track_id, u64::MAX)).map(|(k, _)| k).collect();
+        for key in &keys {
+            b.remove(key);
+        }
+    });
+}
+
+/// Sets `unique_listeners` on tracks that predate it from the PLAY_COUNTS entries with a play.
+pub fn count_listeners() {
+    let missing = TRACKS.with(|t| t.borrow().values().any(|track| track.unique_listeners.is_none()));
+    if !missing {
+        return;
+    }
+    let mut listeners: BTreeMap<u64, u64> = BTreeMap::new();
+    PLAY_COUNTS.with(|pc| {
+        for entry in pc.borrow().values().filter(|e| e.play_count > 0) {
+            *listeners.entry(entry.track_id).or_default() += 1;
+        }
+    });
+    TRACKS.with(|tracks| {
+        let mut tracks = tracks.borrow_mut();
+        let ids: Vec<u64> = tracks.iter().filter(|(_, t)| t.unique_listeners.is_none()).map(|(id, _)| id).collect();
+        for id in &ids {
+            memory::modify(&mut tracks, id, |track| {
+                track.unique_listeners = Some(listeners.get(id).copied().unwrap_or(0));
+            });
+        }
+    });
+}
+
+pub fn prune_hourly() {
+    let cutoff = now_ms().saturating_sub(HOURLY_RETENTION_MS);
+    let expired: Vec<(u64, u64)> =
+        PLAYS_BY_HOUR.with(|b| b.borrow().keys().filter(|(_, start)| *start < cutoff).collect());
+    PLAYS_BY_HOUR.with(|b| {
+        let mut b = b.borrow_mut();
+        for key in &expired {
+            b.remove(key);
+        }
+    });
+    if !expired.is_empty() {
+        ic_cdk::println!("Pruned {} hourly play buckets", expired.len());
+    }
+}
+
+/// Drops hourly play buckets older than HOURLY_RETENTION_MS, once an hour.
+pub fn start_pruning() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_millis(HOUR_MS), prune_hourly);
+}
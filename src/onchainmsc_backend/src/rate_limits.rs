@@ -0,0 +1,135 @@
+// Per-method rate limits on the write endpoints people can spam.
+//
+// Each limited endpoint calls `enforce` before doing anything else. A caller
+// gets `max_calls` calls per fixed window of `window_secs`, counted separately
+// for each method in RATE_LIMITS under (principal, method); the window starts
+// at the first call after the previous one ran out. Admins are never limited.
+//
+// DEFAULT_POLICIES applies unless an admin stored an override in
+// RATE_LIMIT_POLICIES. Entries whose window has run out carry no information,
+// so a timer drops them.
+use crate::{is_admin, ApiError, RateLimitEntry, RateLimitPolicy, RateLimitedMethod, RATE_LIMITS, RATE_LIMIT_POLICIES};
+use ic_cdk::api::caller;
+
+pub const PRUNE_INTERVAL_SECS: u64 = 10 * 60;
+
+const DEFAULT_POLICIES: [(RateLimitedMethod, RateLimitPolicy); 5] = [
+    (RateLimitedMethod::SendMessage, RateLimitPolicy { max_calls: 20, window_secs: 60 }),
+    (RateLimitedMethod::AddComment, RateLimitPolicy { max_calls: 10, window_secs: 60 }),
+    (RateLimitedMethod::ReportContent, RateLimitPolicy { max_calls: 10, window_secs: 60 * 60 }),
+    (RateLimitedMethod::RateTrack, RateLimitPolicy { max_calls: 30, window_secs: 60 }),
+    (RateLimitedMethod::RecordPlay, RateLimitPolicy { max_calls: 60, window_secs: 60 }),
+];
+
+fn now_ms() -> u64 {
+    ic_cdk::api::time() / 1_000_000
+}
+
+fn method_code(method: RateLimitedMethod) -> u8 {
+    match method {
+        RateLimitedMethod::SendMessage => 0,
+        RateLimitedMethod::AddComment => 1,
+        RateLimitedMethod::ReportContent => 2,
+        RateLimitedMethod::RateTrack => 3,
+        RateLimitedMethod::RecordPlay => 4,
+    }
+}
+
+fn method_of(code: u8) -> Option<RateLimitedMethod> {
+    DEFAULT_POLICIES.iter().map(|(method, _)| *method).find(|method| method_code(*method) == code)
+}
+
+pub fn policy(method: RateLimitedMethod) -> RateLimitPolicy {
+    RATE_LIMIT_POLICIES.with(|p| p.borrow().get(&method_code(method))).unwrap_or_else(|| {
+        let (_, default) = DEFAULT_POLICIES.iter().find(|(m, _)| *m == method).expect("every method has a default policy");
+        default.clone()
+    })
+}
+
+/// The policy in force for every limited method.
+pub fn policies() -> Vec<(RateLimitedMethod, RateLimitPolicy)> {
+    DEFAULT_POLICIES.iter().map(|(method, _)| (*method, policy(*method))).collect()
+}
+
+/// Overrides the method's policy; `None` restores the default.
+pub fn set_policy(method: RateLimitedMethod, policy: Option<RateLimitPolicy>) -> Result<(), ApiError> {
+    match policy {
+        Some(policy) => {
+            if policy.max_calls == 0 {
+                return Err(ApiError::validation("max_calls", "must be at least 1"));
+            }
+            if policy.window_secs == 0 {
+                return Err(ApiError::validation("window_secs", "must be at least 1"));
+            }
+            RATE_LIMIT_POLICIES.with(|p| p.borrow_mut().insert(method_code(method), policy));
+        }
+        None => {
+            RATE_LIMIT_POLICIES.with(|p| p.borrow_mut().remove(&method_code(method)));
+        }
+    }
+    Ok(())
+}
+
+fn window_end(entry: &RateLimitEntry, policy: &RateLimitPolicy) -> u64 {
+    entry.window_start.saturating_add(policy.window_secs.saturating_mul(1000))
+}
+
+/// Counts a call to `method` by the caller, or fails with RateLimited if they
+/// have used up the current window.
+pub fn enforce(method: RateLimitedMethod) -> Result<(), ApiError> {
+    let principal = caller();
+    if is_admin(principal) {
+        return Ok(());
+    }
+    let policy = policy(method);
+    let key = (principal, method_code(method));
+    let now = now_ms();
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        let entry = match limits.get(&key) {
+            Some(entry) if now < window_end(&entry, &policy) => {
+                if entry.call_count >= policy.max_calls {
+                    let wait_ms = window_end(&entry, &policy) - now;
+                    return Err(ApiError::RateLimited { retry_after_secs: wait_ms.div_ceil(1000) });
+                }
+                RateLimitEntry { last_call: now, call_count: entry.call_count + 1, ..entry }
+            }
+            _ => RateLimitEntry { principal, last_call: now, call_count: 1, window_start: now },
+        };
+        limits.insert(key, entry);
+        Ok(())
+    })
+}
+
+/// Drops entries whose window has run out under the current policy.
+pub fn prune_expired() {
+    let now = now_ms();
+    let policies = policies();
+    let expired: Vec<_> = RATE_LIMITS.with(|limits| {
+        limits
+            .borrow()
+            .iter()
+            .filter(|((_, code), entry)| {
+                // Entries for methods this build no longer limits are stale too
+                method_of(*code)
+                    .and_then(|method| policies.iter().find(|(m, _)| *m == method))
+                    .is_none_or(|(_, policy)| now >= window_end(entry, policy))
+            })
+            .map(|(key, _)| key)
+            .collect()
+    });
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        for key in &expired {
+            limits.remove(key);
+        }
+    });
+    if !expired.is_empty() {
+        ic_cdk::println!("Pruned {} expired rate limit entries", expired.len());
+    }
+}
+
+/// Clears out rate limit windows that have run out, every PRUNE_INTERVAL_SECS.
+pub fn start_pruning() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(PRUNE_INTERVAL_SECS), prune_expired);
+}
@@ -0,0 +1,122 @@
+// Track shares and referral attribution.
+//
+// `share` gives the caller a referral code for a track, one per (sharer,
+// track): sharing the same track again returns the same code. Clients pass the
+// code to `record_play` and `distribute_payment`; counted plays and payments
+// made through it add to the code's stats. A sharer using their own code is
+// accepted but not attributed.
+//
+// A track's owners can set `referral_share_pct`. A payment through a code then
+// credits that share of the amount to the sharer's referral balance in the
+// royalty ledger (see royalties.rs), and the artists split the rest.
+use crate::{
+    memory, next_id, ApiError, IdCounter, ReferralCode, REFERRALS_BY_SHARER, REFERRAL_CODES, TRACK_SHARES,
+};
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+/// The largest share of a payment a track can give its referrers.
+pub const MAX_REFERRAL_SHARE_PCT: u8 = 50;
+/// Hex digits in a code.
+const CODE_LEN: usize = 12;
+
+fn now_ms() -> u64 {
+    ic_cdk::api::time() / 1_000_000
+}
+
+pub fn get(code: &str) -> Option<ReferralCode> {
+    REFERRAL_CODES.with(|c| c.borrow().get(&code.to_string()))
+}
+
+// Hashing in the sharer and track keeps codes from being sequential
+fn new_code(sharer: Principal, track_id: u64) -> String {
+    loop {
+        let mut hasher = Sha256::new();
+        hasher.update(next_id(IdCounter::Referral).to_be_bytes());
+        hasher.update(sharer.as_slice());
+        hasher.update(track_id.to_be_bytes());
+        let hex: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        let code = hex[..CODE_LEN].to_string();
+        if get(&code).is_none() {
+            return code;
+        }
+    }
+}
+
+/// The sharer's code for the track, created on first use.
+pub fn share(sharer: Principal, track_id: u64) -> ReferralCode {
+    if let Some(existing) = REFERRALS_BY_SHARER.with(|r| r.borrow().get(&(sharer, track_id))).and_then(|code| get(&code)) {
+        return existing;
+    }
+    let referral = ReferralCode {
+        code: new_code(sharer, track_id),
+        track_id,
+        sharer,
+        created_at: now_ms(),
+        plays: 0,
+        purchases: 0,
+        revenue: 0,
+        earned: 0,
+    };
+    REFERRAL_CODES.with(|c| c.borrow_mut().insert(referral.code.clone(), referral.clone()));
+    REFERRALS_BY_SHARER.with(|r| r.borrow_mut().insert((sharer, track_id), referral.code.clone()));
+    TRACK_SHARES.with(|s| s.borrow_mut().insert((track_id, sharer), ()));
+    referral
+}
+
+/// The code's record if `user` acting on `track_id` should be attributed to it;
+/// `None` when the user is the code's own sharer.
+pub fn resolve(code: &str, track_id: u64, user: Principal) -> Result<Option<ReferralCode>, ApiError> {
+    let referral = get(code).ok_or_else(|| ApiError::not_found("referral code", code))?;
+    if referral.track_id != track_id {
+        return Err(ApiError::validation("referral", "code belongs to another track"));
+    }
+    Ok((referral.sharer != user).then_some(referral))
+}
+
+pub fn count_play(code: &str) {
+    REFERRAL_CODES.with(|c| memory::modify(&mut c.borrow_mut(), &code.to_string(), |r| r.plays += 1));
+}
+
+/// Counts a payment of `amount` made through the code, `earned` of which went to its sharer.
+pub fn count_purchase(code: &str, amount: u64, earned: u64) {
+    REFERRAL_CODES.with(|c| {
+        memory::modify(&mut c.borrow_mut(), &code.to_string(), |r| {
+            r.purchases += 1;
+            r.revenue += amount;
+            r.earned += earned;
+        })
+    });
+}
+
+/// The sharer's codes, by track id.
+pub fn codes_of(sharer: Principal) -> Vec<ReferralCode> {
+    let codes: Vec<String> = REFERRALS_BY_SHARER
+        .with(|r| r.borrow().range((sharer, 0)..=(sharer, u64::MAX)).map(|(_, code)| code).collect());
+    codes.iter().filter_map(|code| get(code)).collect()
+}
+
+/// How many principals have shared the track.
+pub fn shares_of(track_id: u64) -> u64 {
+    TRACK_SHARES.with(|s| {
+        s.borrow()
+            .range((track_id, Principal::management_canister())..)
+            .take_while(|((id, _), _)| *id == track_id)
+            .count() as u64
+    })
+}
+
+pub fn validate_share_pct(pct: u8) -> Result<(), ApiError> {
+    if pct == 0 || pct > MAX_REFERRAL_SHARE_PCT {
+        return Err(ApiError::validation(
+            "pct",
+            &format!("must be between 1 and {}", MAX_REFERRAL_SHARE_PCT),
+        ));
+    }
+    Ok(())
+}
+
+/// The referrer's part of `amount` at `pct` percent, rounded down.
+pub fn referrer_cut(amount: u64, pct: u8) -> u64 {
+    (amount as u128 * pct as u128 / 100) as u64
+}
@@ -0,0 +1,479 @@
+// Royalty split rules, how a payment is divided between split holders, and the
+// append-only royalty ledger that records every balance change.
+//
+// Shares are computed in u128 so `amount * pct` cannot overflow, and the
+// tokens lost to integer division are handed out one at a time to the splits
+// with the largest fractional remainder (ties go to the earlier split), so the
+// shares of a payment always add up to exactly the amount paid.
+//
+// Every change to `Artist.royalty_balance` goes through `credit_artist` or
+// `debit_artist`, which post a two-sided entry against the royalty pool in
+// the same message. An artist's balance therefore always equals the credits
+// minus the debits on their account, which `reconcile` verifies.
+//
+// TRACK_PAYOUT_ENTRIES indexes the payment entries by (track, timestamp), so a
+// track's payouts over a period are a range scan rather than a pass over the ledger.
+//
+// Referrers (see referrals.rs) hold balances in REFERRER_BALANCES the same way,
+// changed only by `credit_referrer` and `debit_referrer`.
+use crate::{
+    next_id, update_config, ApiError, EntryReference, IdCounter, LedgerAccount, RoyaltyEntry, Split, Track, TrackRole,
+    ARTISTS, ARTIST_ROYALTY_ENTRIES, CONFIG, REFERRER_BALANCES, ROYALTY_ENTRIES, TRACK_PAYOUT_ENTRIES,
+};
+use candid::Principal;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub fn validate_splits(splits: &[Split]) -> Result<(), ApiError> {
+    if splits.is_empty() {
+        return Err(ApiError::validation("splits", "at least one split is required"));
+    }
+    let mut seen = BTreeSet::new();
+    let mut total: u32 = 0;
+    for split in splits {
+        if split.pct == 0 {
+            return Err(ApiError::validation("splits", &format!("artist {} has a zero share", split.id)));
+        }
+        if !seen.insert(split.id) {
+            return Err(ApiError::validation("splits", &format!("artist {} appears more than once", split.id)));
+        }
+        if !ARTISTS.with(|artists| artists.borrow().contains_key(&split.id)) {
+            return Err(ApiError::not_found("artist", split.id));
+        }
+        total += split.pct as u32;
+    }
+    if total != 100 {
+        return Err(ApiError::validation("splits", &format!("shares add up to {}%, expected 100%", total)));
+    }
+    Ok(())
+}
+
+/// Divides `amount` between `splits` in proportion to their percentages.
+/// Returns `(artist_id, share)` pairs in split order; the shares sum to `amount`.
+pub fn allocate(amount: u64, splits: &[Split]) -> Vec<(u64, u64)> {
+    let total: u128 = splits.iter().map(|s| s.pct as u128).sum();
+    if total == 0 {
+        return vec![];
+    }
+    let mut shares: Vec<(u64, u64, u128)> = splits
+        .iter()
+        .map(|s| {
+            let exact = amount as u128 * s.pct as u128;
+            (s.id, (exact / total) as u64, exact % total)
+        })
+        .collect();
+    // Each share loses less than one token to rounding, so this is below splits.len()
+    let mut remainder = amount - shares.iter().map(|(_, share, _)| share).sum::<u64>();
+    let mut order: Vec<usize> = (0..shares.len()).collect();
+    order.sort_by(|&a, &b| shares[b].2.cmp(&shares[a].2).then(a.cmp(&b)));
+    for i in order {
+        if remainder == 0 {
+            break;
+        }
+        shares[i].1 += 1;
+        remainder -= 1;
+    }
+    shares.into_iter().map(|(id, share, _)| (id, share)).collect()
+}
+
+/// User ids holding the Owner role on the track.
+pub fn owners(track: &Track) -> Vec<u64> {
+    track.roles.iter().filter(|(_, role)| *role == TrackRole::Owner).map(|(id, _)| *id).collect()
+}
+
+/// Applies the pending split proposal once every current owner has approved it.
+pub fn apply_if_approved(track: &mut Track) {
+    let approved = track
+        .pending_splits
+        .as_ref()
+        .is_some_and(|p| owners(track).iter().all(|owner| p.approvals.contains(owner)));
+    if approved {
+        track.splits = track.pending_splits.take().map(|p| p.splits);
+    }
+}
+
+fn index_payout(entry: &RoyaltyEntry) {
+    if let EntryReference::Payment { track_id, .. } = entry.reference {
+        TRACK_PAYOUT_ENTRIES.with(|index| index.borrow_mut().insert((track_id, entry.timestamp, entry.id), ()));
+    }
+}
+
+fn post(debit: LedgerAccount, credit: LedgerAccount, amount: u64, reference: EntryReference) -> u64 {
+    let entry = RoyaltyEntry {
+        id: next_id(IdCounter::RoyaltyEntry),
+        timestamp: ic_cdk::api::time() / 1_000_000,
+        debit,
+        credit,
+        amount,
+        reference,
+    };
+    for artist_id in [&entry.debit, &entry.credit].into_iter().filter_map(LedgerAccount::artist_id) {
+        ARTIST_ROYALTY_ENTRIES.with(|index| index.borrow_mut().insert((artist_id, entry.id), ()));
+    }
+    index_payout(&entry);
+    ROYALTY_ENTRIES.with(|entries| entries.borrow_mut().insert(entry.id, entry.clone()));
+    entry.id
+}
+
+/// Moves `amount` from the pool to the artist and records why.
+pub fn credit_artist(artist_id: u64, amount: u64, reference: EntryReference) -> Result<u64, ApiError> {
+    ARTISTS.with(|artists| {
+        let mut artists = artists.borrow_mut();
+        let mut artist = artists.get(&artist_id).ok_or_else(|| ApiError::not_found("artist", artist_id))?;
+        artist.royalty_balance = artist.royalty_balance.checked_add(amount)
+            .ok_or_else(|| ApiError::validation("amount", "royalty balance would overflow"))?;
+        artists.insert(artist_id, artist);
+        Ok(())
+    })?;
+    Ok(post(LedgerAccount::Pool, LedgerAccount::Artist(artist_id), amount, reference))
+}
+
+/// Moves `amount` from the artist back to the pool, failing if the balance is too low.
+pub fn debit_artist(artist_id: u64, amount: u64, reference: EntryReference) -> Result<u64, ApiError> {
+    ARTISTS.with(|artists| {
+        let mut artists = artists.borrow_mut();
+        let mut artist = artists.get(&artist_id).ok_or_else(|| ApiError::not_found("artist", artist_id))?;
+        artist.royalty_balance = artist.royalty_balance.checked_sub(amount)
+            .ok_or_else(|| ApiError::validation("amount", "exceeds the royalty balance"))?;
+        artists.insert(artist_id, artist);
+        Ok(())
+    })?;
+    Ok(post(LedgerAccount::Artist(artist_id), LedgerAccount::Pool, amount, reference))
+}
+
+/// Fails if crediting `shares` (artist id, amount) and the referrer's cut would
+/// overflow a balance, so a payment can be refused before any tokens move.
+pub fn check_credits(shares: &[(u64, u64)], referrer: Option<(Principal, u64)>) -> Result<(), ApiError> {
+    for (artist_id, share) in shares {
+        let artist = ARTISTS.with(|artists| artists.borrow().get(artist_id)).ok_or_else(|| ApiError::not_found("artist", artist_id))?;
+        artist.royalty_balance.checked_add(*share)
+            .ok_or_else(|| ApiError::validation("amount", "royalty balance would overflow"))?;
+    }
+    if let Some((referrer, cut)) = referrer {
+        referrer_balance(referrer)
+            .checked_add(cut)
+            .ok_or_else(|| ApiError::validation("amount", "referral balance would overflow"))?;
+    }
+    Ok(())
+}
+
+pub fn referrer_balance(referrer: Principal) -> u64 {
+    REFERRER_BALANCES.with(|b| b.borrow().get(&referrer)).unwrap_or(0)
+}
+
+/// Moves `amount` from the pool to the referrer and records why.
+pub fn credit_referrer(referrer: Principal, amount: u64, reference: EntryReference) -> Result<u64, ApiError> {
+    let balance = referrer_balance(referrer)
+        .checked_add(amount)
+        .ok_or_else(|| ApiError::validation("amount", "referral balance would overflow"))?;
+    REFERRER_BALANCES.with(|b| b.borrow_mut().insert(referrer, balance));
+    Ok(post(LedgerAccount::Pool, LedgerAccount::Referrer(referrer), amount, reference))
+}
+
+/// Moves `amount` from the referrer back to the pool, failing if the balance is too low.
+pub fn debit_referrer(referrer: Principal, amount: u64, reference: EntryReference) -> Result<u64, ApiError> {
+    let balance = referrer_balance(referrer)
+        .checked_sub(amount)
+        .ok_or_else(|| ApiError::validation("amount", "exceeds the referral balance"))?;
+    REFERRER_BALANCES.with(|b| b.borrow_mut().insert(referrer, balance));
+    Ok(post(LedgerAccount::Referrer(referrer), LedgerAccount::Pool, amount, reference))
+}
+
+/// Signed effect of an entry on one artist's balance.
+fn effect_on(entry: &RoyaltyEntry, artist_id: u64) -> i128 {
+    let mut effect = 0;
+    if entry.credit.artist_id() == Some(artist_id) {
+        effect += entry.amount as i128;
+    }
+    if entry.debit.artist_id() == Some(artist_id) {
+        effect -= entry.amount as i128;
+    }
+    effect
+}
+
+/// One artist's entries in `[start, end)` (milliseconds), paged by entry id.
+pub fn statement(artist_id: u64, start: Option<u64>, end: Option<u64>, after: Option<u64>, limit: usize) -> crate::RoyaltyStatement {
+    let in_period = |t: u64| start.is_none_or(|s| t >= s) && end.is_none_or(|e| t < e);
+    let mut opening: i128 = 0;
+    let mut credits: u64 = 0;
+    let mut debits: u64 = 0;
+    let mut page = Vec::new();
+    let mut next_after = None;
+    let first = after.map_or(0, |id| id.saturating_add(1));
+    ARTIST_ROYALTY_ENTRIES.with(|index| {
+        ROYALTY_ENTRIES.with(|entries| {
+            let entries = entries.borrow();
+            for ((_, entry_id), _) in index.borrow().range((artist_id, 0)..=(artist_id, u64::MAX)) {
+                let Some(entry) = entries.get(&entry_id) else { continue };
+                let effect = effect_on(&entry, artist_id);
+                if start.is_some_and(|s| entry.timestamp < s) {
+                    opening += effect;
+                    continue;
+                }
+                if !in_period(entry.timestamp) {
+                    continue;
+                }
+                if effect >= 0 {
+                    credits += effect as u64;
+                } else {
+                    debits += effect.unsigned_abs() as u64;
+                }
+                if entry_id >= first {
+                    if page.len() < limit {
+                        page.push(entry);
+                    } else if next_after.is_none() {
+                        next_after = page.last().map(|e: &RoyaltyEntry| e.id);
+                    }
+                }
+            }
+        })
+    });
+    crate::RoyaltyStatement {
+        artist_id,
+        opening_balance: opening.max(0) as u64,
+        total_credits: credits,
+        total_debits: debits,
+        closing_balance: (opening + credits as i128 - debits as i128).max(0) as u64,
+        entries: page,
+        next_after,
+    }
+}
+
+/// Totals credited per artist from payments on one track within `[start, end)`.
+pub fn track_payouts(track_id: u64, start: Option<u64>, end: Option<u64>) -> Vec<(u64, u64)> {
+    let mut payouts: BTreeMap<u64, u64> = BTreeMap::new();
+    let first = (track_id, start.unwrap_or(0), 0);
+    TRACK_PAYOUT_ENTRIES.with(|index| {
+        ROYALTY_ENTRIES.with(|entries| {
+            let entries = entries.borrow();
+            for ((_, _, entry_id), _) in index
+                .borrow()
+                .range(first..=(track_id, u64::MAX, u64::MAX))
+                .take_while(|((_, timestamp, _), _)| end.is_none_or(|e| *timestamp < e))
+            {
+                let Some(entry) = entries.get(&entry_id) else { continue };
+                if let Some(artist_id) = entry.credit.artist_id() {
+                    *payouts.entry(artist_id).or_insert(0) += entry.amount;
+                }
+            }
+        });
+    });
+    payouts.into_iter().collect()
+}
+
+/// Recomputes every artist's balance from the ledger and compares it with the stored one.
+pub fn reconcile() -> crate::ReconciliationReport {
+    let mut ledger_balances: BTreeMap<u64, i128> = BTreeMap::new();
+    let mut referrer_ledger_balances: BTreeMap<Principal, i128> = BTreeMap::new();
+    let mut total_entries = 0;
+    let mut pool_debits: u128 = 0;
+    let mut pool_credits: u128 = 0;
+    ROYALTY_ENTRIES.with(|entries| {
+        for entry in entries.borrow().values() {
+            total_entries += 1;
+            for (account, sign) in [(&entry.credit, 1i128), (&entry.debit, -1i128)] {
+                match account {
+                    LedgerAccount::Artist(artist_id) => {
+                        *ledger_balances.entry(*artist_id).or_insert(0) += sign * entry.amount as i128
+                    }
+                    LedgerAccount::Pool if sign > 0 => pool_credits += entry.amount as u128,
+                    LedgerAccount::Pool => pool_debits += entry.amount as u128,
+                    LedgerAccount::Referrer(referrer) => {
+                        *referrer_ledger_balances.entry(*referrer).or_insert(0) += sign * entry.amount as i128
+                    }
+                }
+            }
+        }
+    });
+    let mut mismatches = Vec::new();
+    let mut artists_checked = 0;
+    ARTISTS.with(|artists| {
+        let artists = artists.borrow();
+        for artist in artists.values() {
+            artists_checked += 1;
+            let ledger_balance = ledger_balances.remove(&artist.id).unwrap_or(0);
+            if ledger_balance != artist.royalty_balance as i128 {
+                mismatches.push(crate::BalanceMismatch { artist_id: artist.id, stored_balance: Some(artist.royalty_balance), ledger_balance });
+            }
+        }
+    });
+    // Entries left over belong to deleted artists; only a non-zero balance is a problem
+    for (artist_id, ledger_balance) in ledger_balances {
+        if ledger_balance != 0 {
+            mismatches.push(crate::BalanceMismatch { artist_id, stored_balance: None, ledger_balance });
+        }
+    }
+    // A referrer without a stored balance has a balance of zero
+    let mut referrer_mismatches = Vec::new();
+    let mut referrers_checked = 0;
+    REFERRER_BALANCES.with(|balances| {
+        for (referrer, stored_balance) in balances.borrow().iter() {
+            referrers_checked += 1;
+            let ledger_balance = referrer_ledger_balances.remove(&referrer).unwrap_or(0);
+            if ledger_balance != stored_balance as i128 {
+                referrer_mismatches.push(crate::ReferrerMismatch { referrer, stored_balance, ledger_balance });
+            }
+        }
+    });
+    for (referrer, ledger_balance) in referrer_ledger_balances {
+        if ledger_balance != 0 {
+            referrer_mismatches.push(crate::ReferrerMismatch { referrer, stored_balance: 0, ledger_balance });
+        }
+    }
+    crate::ReconciliationReport {
+        artists_checked,
+        referrers_checked,
+        total_entries,
+        // Tokens the pool owes to artists and referrers: what it paid out minus what came back
+        pool_liability: (pool_debits as i128 - pool_credits as i128).max(0) as u64,
+        mismatches,
+        referrer_mismatches,
+    }
+}
+
+/// Books balances that predate the ledger as opening entries. Runs once, while the ledger is empty.
+pub fn open_ledger() {
+    if ROYALTY_ENTRIES.with(|entries| !entries.borrow().is_empty()) {
+        return;
+    }
+    let balances: Vec<(u64, u64)> = ARTISTS.with(|artists| {
+        artists.borrow().values().filter(|a| a.royalty_balance > 0).map(|a| (a.id, a.royalty_balance)).collect()
+    });
+    for (artist_id, balance) in balances {
+        post(LedgerAccount::Pool, LedgerAccount::Artist(artist_id), balance, EntryReference::OpeningBalance);
+    }
+}
+
+/// Indexes the payment entries posted before TRACK_PAYOUT_ENTRIES existed, once. Runs on upgrade.
+pub fn index_payouts() {
+    if CONFIG.with(|c| c.borrow().get().payouts_indexed) == Some(true) {
+        return;
+    }
+    let mut count = 0;
+    ROYALTY_ENTRIES.with(|entries| {
+        for entry in entries.borrow().values() {
+            index_payout(&entry);
+            count += 1;
+        }
+    });
+    update_config(|c| c.payouts_indexed = Some(true));
+    ic_cdk::println!("Indexed track payouts from {} ledger entries", count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Artist, SplitProposal, TrackVisibility};
+
+    fn split(id: u64, pct: u8) -> Split {
+        Split { id, pct }
+    }
+
+    fn add_artist(id: u64) {
+        let artist = Artist {
+            id,
+            name: format!("artist {}", id),
+            bio: String::new(),
+            social: None,
+            royalty_balance: 0,
+            profile_image_url: None,
+            links: None,
+            user_principal: Principal::anonymous(),
+        };
+        ARTISTS.with(|artists| artists.borrow_mut().insert(id, artist));
+    }
+
+    fn track(roles: Vec<(u64, TrackRole)>, pending: Option<SplitProposal>) -> Track {
+        Track {
+            id: 1,
+            title: "track".to_string(),
+            description: String::new(),
+            contributors: vec![],
+            version: 1,
+            splits: Some(vec![split(1, 100)]),
+            comments: vec![],
+            payments: vec![],
+            visibility: TrackVisibility::Public,
+            invited: vec![],
+            roles,
+            ratings: vec![],
+            tags: vec![],
+            genre: None,
+            play_count: 0,
+            downloadable: false,
+            pending_splits: pending,
+            duration_ms: None,
+            unique_listeners: None,
+            referral_share_pct: None,
+        }
+    }
+
+    #[test]
+    fn allocate_shares_sum_to_the_amount() {
+        let splits = [split(1, 33), split(2, 33), split(3, 34)];
+        for amount in [0, 1, 2, 99, 100, 101, 12_345, u64::MAX] {
+            let shares = allocate(amount, &splits);
+            assert_eq!(shares.iter().map(|(_, s)| *s as u128).sum::<u128>(), amount as u128);
+        }
+    }
+
+    #[test]
+    fn allocate_is_proportional_when_it_divides_evenly() {
+        let shares = allocate(1_000, &[split(1, 50), split(2, 30), split(3, 20)]);
+        assert_eq!(shares, vec![(1, 500), (2, 300), (3, 200)]);
+    }
+
+    #[test]
+    fn allocate_gives_the_remainder_to_the_largest_fractions() {
+        // 10 * 33% = 3.3, 10 * 33% = 3.3, 10 * 34% = 3.4: the one leftover token goes to the 3.4
+        assert_eq!(allocate(10, &[split(1, 33), split(2, 33), split(3, 34)]), vec![(1, 3), (2, 3), (3, 4)]);
+        // Equal fractions go to the earlier split
+        assert_eq!(allocate(1, &[split(1, 50), split(2, 50)]), vec![(1, 1), (2, 0)]);
+    }
+
+    #[test]
+    fn allocate_rescales_splits_that_no_longer_add_up() {
+        // A deleted artist's split is dropped before allocating; the rest share everything
+        assert_eq!(allocate(90, &[split(1, 20), split(2, 40)]), vec![(1, 30), (2, 60)]);
+        assert!(allocate(100, &[]).is_empty());
+    }
+
+    #[test]
+    fn validate_splits_accepts_shares_adding_up_to_100() {
+        add_artist(1);
+        add_artist(2);
+        assert!(validate_splits(&[split(1, 60), split(2, 40)]).is_ok());
+    }
+
+    #[test]
+    fn validate_splits_rejects_bad_splits() {
+        add_artist(1);
+        add_artist(2);
+        assert!(validate_splits(&[]).is_err());
+        assert!(validate_splits(&[split(1, 60), split(2, 39)]).is_err());
+        assert!(validate_splits(&[split(1, 100), split(2, 0)]).is_err());
+        assert!(validate_splits(&[split(1, 50), split(1, 50)]).is_err());
+        assert!(matches!(validate_splits(&[split(1, 50), split(99, 50)]), Err(ApiError::NotFound { .. })));
+    }
+
+    #[test]
+    fn pending_splits_apply_only_once_every_owner_approved() {
+        let roles = vec![(10, TrackRole::Owner), (11, TrackRole::Owner), (12, TrackRole::Collaborator)];
+        let proposal = SplitProposal { splits: vec![split(1, 50), split(2, 50)], proposed_by: 10, approvals: vec![10, 12] };
+        let mut pending = track(roles, Some(proposal));
+        apply_if_approved(&mut pending);
+        assert_eq!(pending.splits.as_ref().map(|s| s.len()), Some(1));
+        assert!(pending.pending_splits.is_some());
+
+        pending.pending_splits.as_mut().unwrap().approvals.push(11);
+        apply_if_approved(&mut pending);
+        assert_eq!(pending.splits.as_ref().map(|s| s.len()), Some(2));
+        assert!(pending.pending_splits.is_none());
+    }
+
+    #[test]
+    fn active_splits_stay_without_a_proposal() {
+        let mut active = track(vec![(10, TrackRole::Owner)], None);
+        apply_if_approved(&mut active);
+        assert_eq!(active.splits.as_ref().map(|s| s.len()), Some(1));
+    }
+}
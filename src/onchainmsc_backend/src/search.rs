@@ -0,0 +1,314 @@
+// Full-text search over tracks, artists and users.
+//
+// Each document's fields are split into lowercase alphanumeric terms.
+// SEARCH_POSTINGS has one entry per (term, document) holding the term's weight in
+// that document: the sum of the weights of the fields it appears in, so a title
+// match outranks a tag match, which outranks a description match. SEARCH_DOCS
+// keeps the terms each document was indexed under so they can be taken out again
+// when it changes. Writers call `index_*`/`remove` whenever they save or delete a
+// document; `rebuild` recreates everything when INDEX_VERSION changes.
+//
+// A query matches documents containing every one of its terms, either exactly or
+// as a prefix (an exact match counts double). Matching starts from the term with the
+// fewest postings and checks each of its documents for the other terms, so results are
+// never cut short; if even that term is too common, the query is rejected. Track filters are checked against
+// the matching tracks themselves, and setting any of them leaves out artists and users.
+// Hits are paged like any other list (see paging.rs), by relevance unless asked otherwise.
+use crate::{
+    indexes, permissions, update_config, ApiError, Artist, LicenseType, SearchHit, SearchKind, SearchQuery, SearchResult,
+    Track, User, ARTISTS, CONFIG, SEARCH_DOCS, SEARCH_POSTINGS, TRACKS, TRACK_LICENSES, USERS,
+};
+use ic_stable_structures::{storable::Bound, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+/// Bump when tokenizing or weighting changes; the index is rebuilt on upgrade.
+const INDEX_VERSION: u32 = 1;
+/// Longer terms are cut to this many bytes, so keys stay bounded.
+const MAX_TERM_BYTES: usize = 32;
+/// Query terms shorter than this only match exactly.
+const MIN_PREFIX_LEN: usize = 2;
+/// A query whose rarest term expands to more postings than this is rejected as too broad.
+const MAX_TERM_POSTINGS: usize = 10_000;
+
+const TITLE_WEIGHT: u32 = 8;
+const TAG_WEIGHT: u32 = 4;
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+fn kind_code(kind: &SearchKind) -> u8 {
+    match kind {
+        SearchKind::Track => 0,
+        SearchKind::Artist => 1,
+        SearchKind::User => 2,
+    }
+}
+
+/// A term's entry for one document; ordered by term so prefixes are a range scan.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PostingKey {
+    term: String,
+    kind: u8,
+    id: u64,
+}
+
+impl Storable for PostingKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.term.as_bytes().to_vec();
+        bytes.push(self.kind);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (term, rest) = bytes.split_at(bytes.len() - 9);
+        PostingKey {
+            term: String::from_utf8(term.to_vec()).expect("search terms are UTF-8"),
+            kind: rest[0],
+            id: u64::from_be_bytes(rest[1..].try_into().expect("8-byte document id")),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_TERM_BYTES as u32 + 9, is_fixed_size: false };
+}
+
+fn truncate(term: &str) -> &str {
+    let mut end = term.len().min(MAX_TERM_BYTES);
+    while !term.is_char_boundary(end) {
+        end -= 1;
+    }
+    &term[..end]
+}
+
+/// Lowercase alphanumeric words of `text`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| truncate(&word.to_lowercase()).to_string())
+        .collect()
+}
+
+fn add_field(terms: &mut BTreeMap<String, u32>, text: &str, weight: u32) {
+    let mut seen: Vec<String> = tokenize(text);
+    seen.sort();
+    seen.dedup();
+    for term in seen {
+        *terms.entry(term).or_default() += weight;
+    }
+}
+
+/// Replaces the document's postings with `terms`.
+fn put(kind: SearchKind, id: u64, terms: BTreeMap<String, u32>) {
+    remove(kind.clone(), id);
+    let code = kind_code(&kind);
+    SEARCH_POSTINGS.with(|postings| {
+        let mut postings = postings.borrow_mut();
+        for (term, weight) in &terms {
+            postings.insert(PostingKey { term: term.clone(), kind: code, id }, *weight);
+        }
+    });
+    let indexed = terms.into_keys().collect::<Vec<_>>().join(" ");
+    SEARCH_DOCS.with(|docs| docs.borrow_mut().insert((code, id), indexed));
+}
+
+pub fn remove(kind: SearchKind, id: u64) {
+    let code = kind_code(&kind);
+    let Some(indexed) = SEARCH_DOCS.with(|docs| docs.borrow_mut().remove(&(code, id))) else {
+        return;
+    };
+    SEARCH_POSTINGS.with(|postings| {
+        let mut postings = postings.borrow_mut();
+        for term in indexed.split(' ').filter(|t| !t.is_empty()) {
+            postings.remove(&PostingKey { term: term.to_string(), kind: code, id });
+        }
+    });
+}
+
+pub fn index_track(track: &Track) {
+    let mut terms = BTreeMap::new();
+    add_field(&mut terms, &track.title, TITLE_WEIGHT);
+    add_field(&mut terms, &track.tags.join(" "), TAG_WEIGHT);
+    add_field(&mut terms, track.genre.as_deref().unwrap_or(""), TAG_WEIGHT);
+    add_field(&mut terms, &track.description, DESCRIPTION_WEIGHT);
+    put(SearchKind::Track, track.id, terms);
+}
+
+pub fn index_artist(artist: &Artist) {
+    let mut terms = BTreeMap::new();
+    add_field(&mut terms, &artist.name, TITLE_WEIGHT);
+    add_field(&mut terms, &artist.bio, DESCRIPTION_WEIGHT);
+    put(SearchKind::Artist, artist.id, terms);
+}
+
+pub fn index_user(user: &User) {
+    let mut terms = BTreeMap::new();
+    add_field(&mut terms, &user.username, TITLE_WEIGHT);
+    add_field(&mut terms, user.bio.as_deref().unwrap_or(""), DESCRIPTION_WEIGHT);
+    put(SearchKind::User, user.id, terms);
+}
+
+/// Reindexes everything if the index was built by another INDEX_VERSION (or never).
+pub fn rebuild() {
+    if CONFIG.with(|c| c.borrow().get().search_index_version) == Some(INDEX_VERSION) {
+        return;
+    }
+    SEARCH_POSTINGS.with(|postings| {
+        let mut postings = postings.borrow_mut();
+        let keys: Vec<PostingKey> = postings.keys().collect();
+        for key in &keys {
+            postings.remove(key);
+        }
+    });
+    SEARCH_DOCS.with(|docs| {
+        let mut docs = docs.borrow_mut();
+        let keys: Vec<(u8, u64)> = docs.keys().collect();
+        for key in &keys {
+            docs.remove(key);
+        }
+    });
+    let tracks: Vec<Track> = TRACKS.with(|t| t.borrow().values().collect());
+    tracks.iter().for_each(index_track);
+    let artists: Vec<Artist> = ARTISTS.with(|a| a.borrow().values().collect());
+    artists.iter().for_each(index_artist);
+    let users: Vec<User> = USERS.with(|u| u.borrow().values().collect());
+    users.iter().for_each(index_user);
+    update_config(|c| c.search_index_version = Some(INDEX_VERSION));
+    ic_cdk::println!("Rebuilt the search index: {} tracks, {} artists, {} users", tracks.len(), artists.len(), users.len());
+}
+
+/// Whether the indexed term `indexed` matches the query term `term`.
+fn term_matches(indexed: &str, term: &str) -> bool {
+    indexed == term || (term.chars().count() >= MIN_PREFIX_LEN && indexed.starts_with(term))
+}
+
+fn term_score(indexed: &str, term: &str, weight: u32) -> u32 {
+    if indexed == term { weight * 2 } else { weight }
+}
+
+/// How many postings `term` expands to, counting no further than MAX_TERM_POSTINGS + 1.
+fn posting_count(term: &str) -> usize {
+    let start = PostingKey { term: term.to_string(), kind: 0, id: 0 };
+    SEARCH_POSTINGS.with(|postings| {
+        postings
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| term_matches(&key.term, term))
+            .take(MAX_TERM_POSTINGS + 1)
+            .count()
+    })
+}
+
+/// Score of every document matching `term`, keyed by (kind, id).
+fn matches(term: &str) -> BTreeMap<(u8, u64), u32> {
+    let mut scores: BTreeMap<(u8, u64), u32> = BTreeMap::new();
+    let start = PostingKey { term: term.to_string(), kind: 0, id: 0 };
+    SEARCH_POSTINGS.with(|postings| {
+        for (key, weight) in postings.borrow().range(start..).take_while(|(key, _)| term_matches(&key.term, term)) {
+            let best = scores.entry((key.kind, key.id)).or_default();
+            *best = (*best).max(term_score(&key.term, term, weight));
+        }
+    });
+    scores
+}
+
+/// Score of `term` in one document, from the terms it was indexed under; None if it doesn't match.
+fn score_in(kind: u8, id: u64, indexed: &[String], term: &str) -> Option<u32> {
+    SEARCH_POSTINGS.with(|postings| {
+        let postings = postings.borrow();
+        indexed
+            .iter()
+            .filter(|t| term_matches(t, term))
+            .filter_map(|t| postings.get(&PostingKey { term: t.clone(), kind, id }).map(|w| term_score(t, term, w)))
+            .max()
+    })
+}
+
+fn kind_selected(query: &SearchQuery, kind: SearchKind) -> bool {
+    let track_filters = query.genre.is_some() || query.tag.is_some() || query.contributor.is_some() || query.license_type.is_some();
+    if track_filters && kind != SearchKind::Track {
+        return false;
+    }
+    query.kinds.as_ref().is_none_or(|kinds| kinds.is_empty() || kinds.contains(&kind))
+}
+
+fn license_of(track_id: u64) -> Option<LicenseType> {
+    TRACK_LICENSES.with(|l| l.borrow().get(&track_id)).map(|l| l.license_type)
+}
+
+fn track_passes(query: &SearchQuery, track: &Track) -> bool {
+    query.genre.as_ref().is_none_or(|g| track.genre.as_ref().is_some_and(|tg| tg.eq_ignore_ascii_case(g)))
+        && query.tag.as_ref().is_none_or(|tag| track.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+        && query.contributor.is_none_or(|artist_id| track.contributors.contains(&artist_id))
+        && query.license_type.as_ref().is_none_or(|wanted| license_of(track.id).as_ref() == Some(wanted))
+}
+
+/// Tracks that can pass the filters: those under the most selective filter's index, or all of them.
+fn filtered_tracks(query: &SearchQuery) -> Vec<u64> {
+    if let Some(artist_id) = query.contributor {
+        indexes::tracks_by_contributor(artist_id)
+    } else if let Some(tag) = &query.tag {
+        indexes::tracks_with_tag(tag)
+    } else if let Some(genre) = &query.genre {
+        indexes::tracks_in_genre(genre)
+    } else {
+        TRACKS.with(|t| t.borrow().keys().collect())
+    }
+}
+
+/// Loads a matched document if the caller may see it and it passes the filters.
+fn load(query: &SearchQuery, kind: u8, id: u64, viewer: Option<u64>) -> Option<SearchResult> {
+    match kind {
+        0 if kind_selected(query, SearchKind::Track) => {
+            let track = TRACKS.with(|t| t.borrow().get(&id))?;
+            (permissions::can_view(&track, viewer) && track_passes(query, &track)).then(|| SearchResult::Track(Box::new(track)))
+        }
+        1 if kind_selected(query, SearchKind::Artist) => ARTISTS.with(|a| a.borrow().get(&id)).map(SearchResult::Artist),
+        2 if kind_selected(query, SearchKind::User) => indexes::user_by_id(id).map(SearchResult::User),
+        _ => None,
+    }
+}
+
+/// Every visible hit with an id unique across kinds, in no particular order.
+pub fn search(query: &SearchQuery) -> Result<Vec<(u64, SearchHit)>, ApiError> {
+    let terms = tokenize(&query.text);
+    let viewer = permissions::viewer();
+    let mut ranked: Vec<((u8, u64), u32)> = if terms.is_empty() {
+        // Filters alone: every visible track that passes them, all scoring zero
+        if !kind_selected(query, SearchKind::Track) {
+            vec![]
+        } else {
+            filtered_tracks(query).into_iter().map(|id| ((0, id), 0)).collect()
+        }
+    } else {
+        // Start from the term with the fewest postings and probe each of its documents for the rest
+        let counts: Vec<usize> = terms.iter().map(|term| posting_count(term)).collect();
+        let (seed, &count) = counts.iter().enumerate().min_by_key(|(_, count)| **count).expect("terms is not empty");
+        if count > MAX_TERM_POSTINGS {
+            return Err(ApiError::validation("text", "matches too many documents; add a longer or rarer word"));
+        }
+        let mut scores = matches(&terms[seed]);
+        let others: Vec<&String> = terms.iter().enumerate().filter(|(i, _)| *i != seed).map(|(_, t)| t).collect();
+        if !others.is_empty() {
+            scores.retain(|&(kind, id), score| {
+                let indexed: Vec<String> = SEARCH_DOCS
+                    .with(|docs| docs.borrow().get(&(kind, id)))
+                    .map(|terms| terms.split(' ').filter(|t| !t.is_empty()).map(str::to_string).collect())
+                    .unwrap_or_default();
+                for term in &others {
+                    match score_in(kind, id, &indexed, term) {
+                        Some(more) => *score += more,
+                        None => return false,
+                    }
+                }
+                true
+            });
+        }
+        scores.into_iter().collect()
+    };
+    Ok(ranked
+        .drain(..)
+        .filter_map(|((kind, id), score)| {
+            let result = load(query, kind, id, viewer)?;
+            Some(((kind as u64) << 56 | id, SearchHit { score, result }))
+        })
+        .collect())
+}
@@ -0,0 +1,811 @@
+// Resumable chunked uploads of track files.
+//
+// A client begins a session with the file's total size and a chunk size, sends
+// the numbered chunks in any order (re-sending one overwrites it), then commits
+// with the SHA-256 of the whole file. Chunks go straight into FILE_CHUNKS under
+// the session id, and committing only writes the TrackFile record that points at
+// them, so nothing is copied. Sessions idle for longer than UPLOAD_SESSION_TTL_MS
+// are dropped by a timer.
+//
+// Chunks are hashed as they arrive, in index order, into a running SHA-256 kept
+// in heap memory, so commit only hashes what hasn't been yet. Each message stops
+// hashing after HASH_BATCH_INSTRUCTIONS; a commit that runs out first fails with
+// Conflict and the client commits again. Re-sending an already hashed chunk with
+// different bytes, or an upgrade, starts the hash over from the first chunk.
+//
+// Storage is charged to the uploader when the session begins and released when
+// the session is abandoned or the file is replaced or deleted.
+//
+// A track holds any number of files in TRACK_FILES under (track_id, file_id).
+// Replacing a file keeps its id and kind. Each TrackVersion records the file
+// set current at that version, so replaced or deleted content is kept (and stays
+// charged to its uploader) until neither the catalog nor any version refers to it.
+//
+// Content is addressed by its SHA-256: a file whose bytes are already stored
+// points at the existing content and the new copy is dropped. STORED_CONTENTS
+// counts the tracks holding each content, and the chunks are freed when the last
+// one lets go. Quotas still charge each track's uploader for the full size.
+// CONTENT_CHARGES records who that is for each (track, content): the first to
+// upload it to the track. Later uploads of the same bytes to the track aren't
+// charged, and when the track lets go the recorded user gets the space back.
+use crate::{
+    audio, memory, next_id, waveforms, ApiError, DuplicateContent, DuplicateFile, FileChange, FileChangeKind,
+    FileKind, FileTarget, FileVerification, IdCounter, StorageUsage, StoredContent, TrackFile, UploadSession,
+    UploadStatus, CONTENT_CHARGES, CONTENT_HASHES, FILE_CHUNKS, LEGACY_TRACK_FILES, STORAGE_USAGE, STORED_CONTENTS, TRACKS,
+    TRACK_FILES, TRACK_VERSIONS, UPLOAD_SESSIONS,
+};
+use candid::Principal;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Chunks must fit in one ingress message alongside the other arguments.
+pub const MAX_CHUNK_SIZE: u32 = 1_900_000;
+/// Keeps a file under a few thousand chunks, so a session's status is cheap to list.
+pub const MIN_CHUNK_SIZE: u32 = 64 * 1024;
+pub const MAX_UPLOAD_SIZE: u64 = 512 * 1024 * 1024;
+pub const DEFAULT_STORAGE_QUOTA: u64 = 2 * 1024 * 1024 * 1024;
+pub const UPLOAD_SESSION_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+pub const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+/// Hashing more than this would run past the query instruction limit.
+pub const MAX_VERIFY_BYTES: u64 = 64 * 1024 * 1024;
+/// Leaves room under the per-message instruction limit for the rest of the call.
+const HASH_BATCH_INSTRUCTIONS: u64 = 4_000_000_000;
+
+// The SHA-256 of a session's chunks before `next_chunk`
+struct RunningHash {
+    next_chunk: u32,
+    hasher: Sha256,
+}
+
+thread_local! {
+    // Rebuilt from the chunks when missing, so it doesn't need stable memory
+    static RUNNING_HASHES: RefCell<BTreeMap<u64, RunningHash>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn now_ms() -> u64 {
+    ic_cdk::api::time() / 1_000_000
+}
+
+pub fn usage_of(user: Principal) -> StorageUsage {
+    STORAGE_USAGE
+        .with(|usage| usage.borrow().get(&user))
+        .unwrap_or(StorageUsage { used_bytes: 0, quota_bytes: None })
+}
+
+fn quota_of(usage: &StorageUsage) -> u64 {
+    usage.quota_bytes.unwrap_or(DEFAULT_STORAGE_QUOTA)
+}
+
+/// Charges `bytes` to the user, failing if that would exceed their quota.
+fn reserve(user: Principal, bytes: u64) -> Result<(), ApiError> {
+    let mut usage = usage_of(user);
+    let quota = quota_of(&usage);
+    if usage.used_bytes.saturating_add(bytes) > quota {
+        return Err(ApiError::validation(
+            "total_size",
+            &format!("storage quota exceeded: {} of {} bytes used", usage.used_bytes, quota),
+        ));
+    }
+    usage.used_bytes += bytes;
+    STORAGE_USAGE.with(|u| u.borrow_mut().insert(user, usage));
+    Ok(())
+}
+
+fn release(user: Principal, bytes: u64) {
+    STORAGE_USAGE.with(|usage| {
+        memory::modify(&mut usage.borrow_mut(), &user, |u| u.used_bytes = u.used_bytes.saturating_sub(bytes))
+    });
+}
+
+pub fn set_quota(user: Principal, quota_bytes: Option<u64>) {
+    let mut usage = usage_of(user);
+    usage.quota_bytes = quota_bytes;
+    STORAGE_USAGE.with(|u| u.borrow_mut().insert(user, usage));
+}
+
+fn chunk_count(total_size: u64, chunk_size: u32) -> u32 {
+    total_size.div_ceil(chunk_size as u64) as u32
+}
+
+/// Size of chunk `index` of a file laid out in `chunk_size` pieces.
+pub fn expected_chunk_len(total_size: u64, chunk_size: u32, index: u32) -> u64 {
+    let start = index as u64 * chunk_size as u64;
+    (total_size - start).min(chunk_size as u64)
+}
+
+fn remove_chunks(content_id: u64) {
+    FILE_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<(u64, u32)> = chunks.range((content_id, 0)..=(content_id, u32::MAX)).map(|(k, _)| k).collect();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+pub fn begin(
+    track_id: u64,
+    target: FileTarget,
+    owner: Principal,
+    filename: String,
+    content_type: String,
+    total_size: u64,
+    chunk_size: u32,
+) -> Result<UploadSession, ApiError> {
+    if total_size == 0 || total_size > MAX_UPLOAD_SIZE {
+        return Err(ApiError::validation("total_size", &format!("must be between 1 and {} bytes", MAX_UPLOAD_SIZE)));
+    }
+    // A file smaller than the minimum goes in one chunk of its own size
+    if chunk_size < MIN_CHUNK_SIZE.min(total_size as u32) || chunk_size > MAX_CHUNK_SIZE {
+        return Err(ApiError::validation(
+            "chunk_size",
+            &format!("must be between {} and {} bytes, or the whole file", MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+        ));
+    }
+    reserve(owner, total_size)?;
+    let now = now_ms();
+    let session = UploadSession {
+        id: next_id(IdCounter::Upload),
+        track_id,
+        owner,
+        filename,
+        content_type,
+        total_size,
+        chunk_size,
+        chunk_count: chunk_count(total_size, chunk_size),
+        received_chunks: 0,
+        created_at: now,
+        expires_at: now + UPLOAD_SESSION_TTL_MS,
+        target: Some(target),
+    };
+    UPLOAD_SESSIONS.with(|s| s.borrow_mut().insert(session.id, session.clone()));
+    Ok(session)
+}
+
+/// The caller's open session, refusing other users' and expired ones.
+pub fn open_session(upload_id: u64, caller: Principal) -> Result<UploadSession, ApiError> {
+    let session = UPLOAD_SESSIONS
+        .with(|s| s.borrow().get(&upload_id))
+        .filter(|s| s.owner == caller)
+        .ok_or_else(|| ApiError::not_found("upload", upload_id))?;
+    if session.expires_at <= now_ms() {
+        abort(&session);
+        return Err(ApiError::not_found("upload", upload_id));
+    }
+    Ok(session)
+}
+
+pub fn put_chunk(mut session: UploadSession, index: u32, data: Vec<u8>) -> Result<(), ApiError> {
+    if index >= session.chunk_count {
+        return Err(ApiError::validation("index", &format!("upload has {} chunks", session.chunk_count)));
+    }
+    let expected = expected_chunk_len(session.total_size, session.chunk_size, index);
+    if data.len() as u64 != expected {
+        return Err(ApiError::validation("data", &format!("chunk {} must be {} bytes", index, expected)));
+    }
+    let previous = FILE_CHUNKS.with(|chunks| chunks.borrow_mut().insert((session.id, index), data.clone()));
+    match previous {
+        None => session.received_chunks += 1,
+        Some(previous) if previous != data => forget_hashed(session.id, index),
+        Some(_) => {}
+    }
+    advance_hash(&session);
+    // Each chunk keeps a slow upload alive
+    session.expires_at = now_ms() + UPLOAD_SESSION_TTL_MS;
+    UPLOAD_SESSIONS.with(|s| s.borrow_mut().insert(session.id, session));
+    Ok(())
+}
+
+pub fn status(session: UploadSession) -> UploadStatus {
+    let missing_chunks = FILE_CHUNKS.with(|chunks| {
+        let chunks = chunks.borrow();
+        (0..session.chunk_count).filter(|i| !chunks.contains_key(&(session.id, *i))).collect()
+    });
+    UploadStatus { session, missing_chunks }
+}
+
+/// Checks the assembled file against `sha256` and turns it into the track's file.
+pub fn commit(session: UploadSession, sha256: Vec<u8>) -> Result<TrackFile, ApiError> {
+    if session.received_chunks < session.chunk_count {
+        return Err(ApiError::validation(
+            "upload_id",
+            &format!("{} of {} chunks received", session.received_chunks, session.chunk_count),
+        ));
+    }
+    let Some(digest) = advance_hash(&session) else {
+        return Err(ApiError::Conflict("the upload is still being hashed; commit again".to_string()));
+    };
+    if digest != sha256 {
+        // The session stays open so the client can re-send chunks and try again
+        return Err(ApiError::validation("sha256", "checksum does not match the uploaded data"));
+    }
+    let mut file = TrackFile {
+        track_id: session.track_id,
+        file_id: 0,
+        kind: FileKind::Other,
+        filename: session.filename.clone(),
+        content_type: session.content_type.clone(),
+        data: vec![],
+        uploaded_by: session.owner,
+        uploaded_at: now_ms(),
+        content_id: Some(session.id),
+        size: Some(session.total_size),
+        chunk_size: Some(session.chunk_size),
+        sha256: Some(digest),
+        audio: None,
+    };
+    let size = session.total_size;
+    file.audio = audio::inspect(&file.content_type, size, &|start, end| read_range(&file, start, end))?;
+    let file = install(file, target_of(&session))?;
+    UPLOAD_SESSIONS.with(|s| s.borrow_mut().remove(&session.id));
+    RUNNING_HASHES.with(|h| h.borrow_mut().remove(&session.id));
+    Ok(file)
+}
+
+/// Feeds the session's chunks, in order, into its running hash until one is
+/// missing or the batch runs out. Returns the digest once every chunk is in.
+fn advance_hash(session: &UploadSession) -> Option<Vec<u8>> {
+    RUNNING_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        let running = hashes.entry(session.id).or_insert_with(|| RunningHash { next_chunk: 0, hasher: Sha256::new() });
+        while running.next_chunk < session.chunk_count
+            && ic_cdk::api::instruction_counter() < HASH_BATCH_INSTRUCTIONS
+        {
+            let Some(chunk) = FILE_CHUNKS.with(|chunks| chunks.borrow().get(&(session.id, running.next_chunk))) else {
+                break;
+            };
+            running.hasher.update(&chunk);
+            running.next_chunk += 1;
+        }
+        (running.next_chunk == session.chunk_count).then(|| running.hasher.clone().finalize().to_vec())
+    })
+}
+
+/// Drops the running hash if it already covers chunk `index`, whose bytes changed.
+fn forget_hashed(upload_id: u64, index: u32) {
+    RUNNING_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        if hashes.get(&upload_id).is_some_and(|h| h.next_chunk > index) {
+            hashes.remove(&upload_id);
+        }
+    });
+}
+
+pub fn target_of(session: &UploadSession) -> FileTarget {
+    session.target.clone().unwrap_or(FileTarget::New(FileKind::Master))
+}
+
+/// Stores `file` at `target`, assigning its id and kind, and frees a replaced file's content.
+/// `file` must point at freshly written chunks, which are dropped if the content is already stored.
+fn install(mut file: TrackFile, target: FileTarget) -> Result<TrackFile, ApiError> {
+    let replaced = match target {
+        FileTarget::New(kind) => {
+            file.file_id = next_id(IdCounter::TrackFile);
+            file.kind = kind;
+            None
+        }
+        FileTarget::Replace(file_id) => {
+            let old = TRACK_FILES
+                .with(|files| files.borrow().get(&(file.track_id, file_id)))
+                .ok_or_else(|| ApiError::not_found("file", file_id))?;
+            file.file_id = file_id;
+            file.kind = old.kind.clone();
+            Some(old)
+        }
+    };
+    share_content(&mut file);
+    TRACK_FILES.with(|files| files.borrow_mut().insert((file.track_id, file.file_id), file.clone()));
+    if let Some(old) = replaced {
+        discard_file(&old);
+    }
+    refresh_duration(file.track_id);
+    waveforms::queue(&file);
+    Ok(file)
+}
+
+/// Copies the newest Master file's duration onto the track.
+pub fn refresh_duration(track_id: u64) {
+    let duration_ms = TRACK_FILES.with(|files| {
+        files
+            .borrow()
+            .range((track_id, 0)..=(track_id, u64::MAX))
+            .map(|(_, file)| file)
+            .filter(|file| file.kind == FileKind::Master)
+            .last()
+            .and_then(|file| file.audio.and_then(|audio| audio.duration_ms))
+    });
+    TRACKS.with(|tracks| memory::modify(&mut tracks.borrow_mut(), &track_id, |track| track.duration_ms = duration_ms));
+}
+
+/// Drops an unfinished session, its chunks and its storage reservation.
+pub fn abort(session: &UploadSession) {
+    UPLOAD_SESSIONS.with(|s| s.borrow_mut().remove(&session.id));
+    RUNNING_HASHES.with(|h| h.borrow_mut().remove(&session.id));
+    remove_chunks(session.id);
+    release(session.owner, session.total_size);
+}
+
+/// Whether the track's catalog or any of its versions still refers to `content_id`.
+fn content_referenced(track_id: u64, content_id: u64) -> bool {
+    let in_catalog = TRACK_FILES.with(|files| {
+        files.borrow().range((track_id, 0)..=(track_id, u64::MAX)).any(|(_, f)| f.content_id == Some(content_id))
+    });
+    in_catalog
+        || TRACK_VERSIONS.with(|tv| {
+            tv.borrow().range((track_id, 0)..=(track_id, u32::MAX)).any(|(_, v)| {
+                v.files.as_ref().is_some_and(|files| files.iter().any(|f| f.content_id == Some(content_id)))
+            })
+        })
+}
+
+fn content_key(sha256: &[u8]) -> Option<[u8; 32]> {
+    sha256.try_into().ok()
+}
+
+/// Points a new file at the stored copy of its bytes, if there is one, and drops
+/// the chunks just written. Otherwise registers them as new content.
+fn share_content(file: &mut TrackFile) {
+    let (Some(fresh), Some(chunk_size), Some(key)) =
+        (file.content_id, file.chunk_size, file.sha256.as_deref().and_then(content_key))
+    else {
+        return;
+    };
+    let size = file_size(file);
+    let existing = CONTENT_HASHES
+        .with(|hashes| hashes.borrow().get(&key))
+        .and_then(|content_id| STORED_CONTENTS.with(|c| c.borrow().get(&content_id)).map(|c| (content_id, c)));
+    match existing {
+        Some((content_id, stored)) if stored.size == size => {
+            remove_chunks(fresh);
+            // The uploader was charged for the upload; a track already holding the content isn't charged twice
+            if content_referenced(file.track_id, content_id) {
+                release(file.uploaded_by, size);
+            } else {
+                STORED_CONTENTS.with(|c| memory::modify(&mut c.borrow_mut(), &content_id, |c| c.holders += 1));
+                charge(file.track_id, content_id, file.uploaded_by);
+            }
+            file.content_id = Some(content_id);
+            file.chunk_size = Some(stored.chunk_size);
+        }
+        _ => {
+            register_content(fresh, key, size, chunk_size, 1);
+            charge(file.track_id, fresh, file.uploaded_by);
+        }
+    }
+}
+
+fn charge(track_id: u64, content_id: u64, user: Principal) {
+    CONTENT_CHARGES.with(|c| c.borrow_mut().insert((track_id, content_id), user));
+}
+
+fn register_content(content_id: u64, key: [u8; 32], size: u64, chunk_size: u32, holders: u64) {
+    let content = StoredContent { sha256: key.to_vec(), size, chunk_size, holders };
+    STORED_CONTENTS.with(|c| c.borrow_mut().insert(content_id, content));
+    CONTENT_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        if !hashes.contains_key(&key) {
+            hashes.insert(key, content_id);
+        }
+    });
+}
+
+/// Called when a track stops referring to `file`'s content; frees the content
+/// once no track holds it.
+fn free_content(file: &TrackFile) {
+    if let Some(content_id) = file.content_id {
+        let stored = STORED_CONTENTS.with(|c| c.borrow().get(&content_id));
+        match stored {
+            Some(mut stored) if stored.holders > 1 => {
+                stored.holders -= 1;
+                STORED_CONTENTS.with(|c| c.borrow_mut().insert(content_id, stored));
+            }
+            _ => {
+                remove_chunks(content_id);
+                waveforms::remove(content_id);
+                STORED_CONTENTS.with(|c| c.borrow_mut().remove(&content_id));
+                if let Some(key) = stored.as_ref().and_then(|s| content_key(&s.sha256)) {
+                    CONTENT_HASHES.with(|hashes| {
+                        let mut hashes = hashes.borrow_mut();
+                        if hashes.get(&key) == Some(content_id) {
+                            hashes.remove(&key);
+                        }
+                    });
+                }
+            }
+        }
+    }
+    // Inline files are charged to their uploader
+    let charged = file
+        .content_id
+        .and_then(|content_id| CONTENT_CHARGES.with(|c| c.borrow_mut().remove(&(file.track_id, content_id))))
+        .unwrap_or(file.uploaded_by);
+    release(charged, file_size(file));
+}
+
+/// Frees a file taken out of the catalog, unless a version still refers to its content.
+pub fn discard_file(file: &TrackFile) {
+    if file.content_id.is_some_and(|content_id| content_referenced(file.track_id, content_id)) {
+        return;
+    }
+    free_content(file);
+}
+
+/// Frees every file of a deleted track, including content only old versions
+/// refer to. Call before removing the track's versions.
+pub fn remove_track_files(track_id: u64) {
+    let mut contents: BTreeMap<u64, TrackFile> = BTreeMap::new();
+    let mut inline: Vec<TrackFile> = vec![];
+    let current: Vec<TrackFile> = TRACK_FILES.with(|files| {
+        let mut files = files.borrow_mut();
+        let keys: Vec<(u64, u64)> = files.range((track_id, 0)..=(track_id, u64::MAX)).map(|(k, _)| k).collect();
+        keys.iter().filter_map(|key| files.remove(key)).collect()
+    });
+    let versioned: Vec<TrackFile> = TRACK_VERSIONS.with(|tv| {
+        tv.borrow()
+            .range((track_id, 0)..=(track_id, u32::MAX))
+            .flat_map(|(_, v)| v.files.unwrap_or_default())
+            .collect()
+    });
+    for file in current.into_iter().chain(versioned) {
+        match file.content_id {
+            Some(content_id) => {
+                contents.entry(content_id).or_insert(file);
+            }
+            None => inline.push(file),
+        }
+    }
+    for file in contents.values().chain(&inline) {
+        free_content(file);
+    }
+}
+
+/// The track's current files, as recorded in a new version.
+pub fn snapshot(track_id: u64) -> Vec<TrackFile> {
+    TRACK_FILES.with(|files| {
+        files
+            .borrow()
+            .range((track_id, 0)..=(track_id, u64::MAX))
+            .map(|(_, file)| TrackFile { data: vec![], ..file })
+            .collect()
+    })
+}
+
+/// Makes `files` (a version's file set) the track's current files.
+pub fn restore(track_id: u64, files: Vec<TrackFile>) {
+    let removed: Vec<TrackFile> = TRACK_FILES.with(|catalog| {
+        let mut catalog = catalog.borrow_mut();
+        let current: Vec<TrackFile> =
+            catalog.range((track_id, 0)..=(track_id, u64::MAX)).map(|(_, file)| file).collect();
+        for file in &current {
+            catalog.remove(&(track_id, file.file_id));
+        }
+        for file in &files {
+            catalog.insert((track_id, file.file_id), file.clone());
+        }
+        current
+    });
+    for file in &removed {
+        discard_file(file);
+    }
+    refresh_duration(track_id);
+}
+
+/// Files added, removed or changed going from the `old` file set to `new`.
+pub fn diff(old: &[TrackFile], new: &[TrackFile]) -> Vec<FileChange> {
+    let change = |file: &TrackFile, change| FileChange {
+        file_id: file.file_id,
+        kind: file.kind.clone(),
+        filename: file.filename.clone(),
+        change,
+    };
+    let mut changes = vec![];
+    for file in new {
+        match old.iter().find(|f| f.file_id == file.file_id) {
+            None => changes.push(change(file, FileChangeKind::Added)),
+            Some(before) if before.content_id != file.content_id || before.sha256 != file.sha256 => {
+                changes.push(change(file, FileChangeKind::Modified))
+            }
+            Some(_) => {}
+        }
+    }
+    for file in old {
+        if !new.iter().any(|f| f.file_id == file.file_id) {
+            changes.push(change(file, FileChangeKind::Removed));
+        }
+    }
+    changes
+}
+
+/// Stores `data` as chunks under a new content id; returns the id and chunk size.
+fn store_chunks(data: &[u8]) -> (u64, u32) {
+    let content_id = next_id(IdCounter::Upload);
+    let chunk_size = (data.len() as u32).min(MAX_CHUNK_SIZE);
+    FILE_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, chunk) in data.chunks(chunk_size as usize).enumerate() {
+            chunks.insert((content_id, index as u32), chunk.to_vec());
+        }
+    });
+    (content_id, chunk_size)
+}
+
+/// Moves file content still held inline in catalog records into FILE_CHUNKS,
+/// so versions can refer to it. Runs on upgrade; a no-op once nothing is inline.
+pub fn move_inline_data() {
+    let inline: Vec<TrackFile> =
+        TRACK_FILES.with(|files| files.borrow().values().filter(|f| !f.data.is_empty()).collect());
+    for mut file in inline {
+        let (content_id, chunk_size) = store_chunks(&file.data);
+        file.size = Some(file.data.len() as u64);
+        file.content_id = Some(content_id);
+        file.chunk_size = Some(chunk_size);
+        file.data = vec![];
+        TRACK_FILES.with(|files| files.borrow_mut().insert((file.track_id, file.file_id), file));
+    }
+}
+
+fn hash_content(content_id: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    FILE_CHUNKS.with(|chunks| {
+        for (_, chunk) in chunks.borrow().range((content_id, 0)..=(content_id, u32::MAX)) {
+            hasher.update(&chunk);
+        }
+    });
+    hasher.finalize().to_vec()
+}
+
+/// Registers content stored before deduplication, counting the tracks that
+/// hold it. Identical copies stored back then stay separate. Runs on upgrade;
+/// only content missing from STORED_CONTENTS is looked at.
+pub fn index_contents() {
+    let mut found: BTreeMap<u64, (TrackFile, BTreeSet<u64>)> = BTreeMap::new();
+    let versioned: Vec<TrackFile> =
+        TRACK_VERSIONS.with(|tv| tv.borrow().values().flat_map(|v| v.files.unwrap_or_default()).collect());
+    let current: Vec<TrackFile> = TRACK_FILES.with(|files| files.borrow().values().collect());
+    for file in current.into_iter().chain(versioned) {
+        let Some(content_id) = file.content_id else { continue };
+        if STORED_CONTENTS.with(|c| c.borrow().contains_key(&content_id)) {
+            continue;
+        }
+        let track_id = file.track_id;
+        found.entry(content_id).or_insert_with(|| (file, BTreeSet::new())).1.insert(track_id);
+    }
+    for (content_id, (file, tracks)) in &found {
+        let sha256 = file.sha256.clone().unwrap_or_else(|| hash_content(*content_id));
+        if let (Some(key), Some(chunk_size)) = (content_key(&sha256), file.chunk_size) {
+            register_content(*content_id, key, file_size(file), chunk_size, tracks.len() as u64);
+        }
+    }
+    if !found.is_empty() {
+        ic_cdk::println!("Indexed {} stored file contents", found.len());
+    }
+}
+
+/// Records who each track's stored content is charged to where that wasn't
+/// recorded yet: the uploader of the track's earliest file with the content.
+/// Runs on upgrade.
+pub fn index_charges() {
+    let mut earliest: BTreeMap<(u64, u64), (u64, Principal)> = BTreeMap::new();
+    let versioned: Vec<TrackFile> =
+        TRACK_VERSIONS.with(|tv| tv.borrow().values().flat_map(|v| v.files.unwrap_or_default()).collect());
+    let current: Vec<TrackFile> = TRACK_FILES.with(|files| files.borrow().values().collect());
+    for file in current.into_iter().chain(versioned) {
+        let Some(content_id) = file.content_id else { continue };
+        let key = (file.track_id, content_id);
+        if CONTENT_CHARGES.with(|c| c.borrow().contains_key(&key)) {
+            continue;
+        }
+        let candidate = (file.uploaded_at, file.uploaded_by);
+        earliest.entry(key).and_modify(|e| *e = (*e).min(candidate)).or_insert(candidate);
+    }
+    for (&(track_id, content_id), &(_, user)) in &earliest {
+        charge(track_id, content_id, user);
+    }
+    if !earliest.is_empty() {
+        ic_cdk::println!("Recorded storage charges for {} track contents", earliest.len());
+    }
+}
+
+/// Recomputes the SHA-256 of a file's stored chunks and checks it against the
+/// hash recorded when it was uploaded.
+pub fn verify(file: &TrackFile) -> Result<FileVerification, ApiError> {
+    let size = file_size(file);
+    if size > MAX_VERIFY_BYTES {
+        return Err(ApiError::validation(
+            "file_id",
+            &format!("files over {} bytes are too large to verify in a query", MAX_VERIFY_BYTES),
+        ));
+    }
+    let stored = file.content_id.and_then(|content_id| STORED_CONTENTS.with(|c| c.borrow().get(&content_id)));
+    let expected_sha256 = stored.map(|c| c.sha256).or_else(|| file.sha256.clone());
+    let mut hasher = Sha256::new();
+    let mut stored_bytes = 0u64;
+    let mut missing_chunks = vec![];
+    match (file.content_id, file.chunk_size) {
+        (Some(content_id), Some(chunk_size)) => FILE_CHUNKS.with(|chunks| {
+            let chunks = chunks.borrow();
+            for index in 0..chunk_count(size, chunk_size) {
+                match chunks.get(&(content_id, index)) {
+                    Some(chunk) => {
+                        stored_bytes += chunk.len() as u64;
+                        hasher.update(&chunk);
+                    }
+                    None => missing_chunks.push(index),
+                }
+            }
+        }),
+        _ => {
+            stored_bytes = file.data.len() as u64;
+            hasher.update(&file.data);
+        }
+    }
+    let actual_sha256 = hasher.finalize().to_vec();
+    let intact = missing_chunks.is_empty() && stored_bytes == size && expected_sha256.as_ref() == Some(&actual_sha256);
+    Ok(FileVerification {
+        file_id: file.file_id,
+        size,
+        stored_bytes,
+        expected_sha256,
+        actual_sha256,
+        missing_chunks,
+        intact,
+    })
+}
+
+/// Current files whose content was also uploaded by someone else, grouped by hash.
+pub fn duplicates() -> Vec<DuplicateContent> {
+    let mut groups: BTreeMap<Vec<u8>, DuplicateContent> = BTreeMap::new();
+    TRACK_FILES.with(|files| {
+        for file in files.borrow().values() {
+            let stored = file.content_id.and_then(|content_id| STORED_CONTENTS.with(|c| c.borrow().get(&content_id)));
+            let Some(sha256) = stored.map(|c| c.sha256).or_else(|| file.sha256.clone()) else { continue };
+            let group = groups.entry(sha256.clone()).or_insert_with(|| DuplicateContent {
+                sha256,
+                size: file_size(&file),
+                files: vec![],
+            });
+            group.files.push(DuplicateFile {
+                track_id: file.track_id,
+                file_id: file.file_id,
+                filename: file.filename,
+                uploaded_by: file.uploaded_by,
+                uploaded_at: file.uploaded_at,
+            });
+        }
+    });
+    groups
+        .into_values()
+        .filter(|group| group.files.iter().map(|f| f.uploaded_by).collect::<BTreeSet<_>>().len() > 1)
+        .collect()
+}
+
+/// Records a file sent in a single message, charging the uploader for it.
+pub fn store_inline(
+    track_id: u64,
+    target: FileTarget,
+    uploader: Principal,
+    filename: String,
+    content_type: String,
+    data: Vec<u8>,
+) -> Result<TrackFile, ApiError> {
+    let size = data.len() as u64;
+    let audio = audio::inspect(&content_type, size, &|start, end| data[start as usize..end as usize].to_vec())?;
+    reserve(uploader, size)?;
+    let (content_id, chunk_size) = store_chunks(&data);
+    let file = TrackFile {
+        track_id,
+        file_id: 0,
+        kind: FileKind::Other,
+        filename,
+        content_type,
+        data: vec![],
+        uploaded_by: uploader,
+        uploaded_at: now_ms(),
+        content_id: Some(content_id),
+        size: Some(size),
+        chunk_size: Some(chunk_size),
+        sha256: Some(Sha256::digest(&data).to_vec()),
+        audio,
+    };
+    install(file, target).inspect_err(|_| {
+        remove_chunks(content_id);
+        release(uploader, size);
+    })
+}
+
+/// Moves the single file each track had before the catalog into it as the
+/// track's Master. Runs on upgrade; a no-op once the old store is empty.
+pub fn move_legacy_files() {
+    let legacy: Vec<(u64, crate::TrackFileV1)> = LEGACY_TRACK_FILES.with(|files| files.borrow().iter().collect());
+    for (track_id, old) in &legacy {
+        let file = TrackFile {
+            track_id: *track_id,
+            file_id: next_id(IdCounter::TrackFile),
+            kind: FileKind::Master,
+            filename: old.filename.clone(),
+            content_type: old.content_type.clone(),
+            data: old.data.clone(),
+            uploaded_by: old.uploaded_by,
+            uploaded_at: old.uploaded_at,
+            content_id: old.content_id,
+            size: old.size,
+            chunk_size: old.chunk_size,
+            sha256: old.sha256.clone(),
+            audio: None,
+        };
+        TRACK_FILES.with(|files| files.borrow_mut().insert((file.track_id, file.file_id), file));
+        LEGACY_TRACK_FILES.with(|files| files.borrow_mut().remove(track_id));
+    }
+    if !legacy.is_empty() {
+        ic_cdk::println!("Moved {} track files into the file catalog", legacy.len());
+    }
+}
+
+/// Chunk `index` of a stored file. Inline files are a single chunk.
+pub fn read_chunk(file: &TrackFile, index: u32) -> Option<Vec<u8>> {
+    match file.content_id {
+        Some(content_id) => FILE_CHUNKS.with(|chunks| chunks.borrow().get(&(content_id, index))),
+        None if index == 0 => Some(file.data.clone()),
+        None => None,
+    }
+}
+
+pub fn file_size(file: &TrackFile) -> u64 {
+    file.size.unwrap_or(file.data.len() as u64)
+}
+
+/// How many files the catalog holds and their total size.
+pub fn catalog_size() -> (u64, u64) {
+    TRACK_FILES.with(|files| {
+        files.borrow().values().fold((0, 0), |(count, bytes), file| (count + 1, bytes + file_size(&file)))
+    })
+}
+
+/// Bytes of distinct content held in FILE_CHUNKS, including content only older versions refer to.
+pub fn stored_bytes() -> u64 {
+    STORED_CONTENTS.with(|contents| contents.borrow().values().map(|c| c.size).sum())
+}
+
+/// Bytes `start..end` of a stored file, read from whichever chunks hold them.
+pub fn read_range(file: &TrackFile, start: u64, end: u64) -> Vec<u8> {
+    match (file.content_id, file.chunk_size) {
+        (Some(content_id), Some(chunk_size)) => read_content(content_id, chunk_size, start, end),
+        _ => file.data[start as usize..end as usize].to_vec(),
+    }
+}
+
+/// Bytes `start..end` of the content stored in FILE_CHUNKS under `content_id`.
+pub fn read_content(content_id: u64, chunk_size: u32, start: u64, end: u64) -> Vec<u8> {
+    let chunk_size = chunk_size as u64;
+    let mut out = Vec::with_capacity((end - start) as usize);
+    FILE_CHUNKS.with(|chunks| {
+        let chunks = chunks.borrow();
+        for index in start / chunk_size..end.div_ceil(chunk_size) {
+            let Some(chunk) = chunks.get(&(content_id, index as u32)) else { break };
+            let chunk_start = index * chunk_size;
+            let from = start.saturating_sub(chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(chunk.len());
+            out.extend_from_slice(&chunk[from..to]);
+        }
+    });
+    out
+}
+
+/// Drops every session past its expiry. Runs on a timer.
+pub fn prune_expired() {
+    let now = now_ms();
+    let expired: Vec<UploadSession> =
+        UPLOAD_SESSIONS.with(|s| s.borrow().values().filter(|s| s.expires_at <= now).collect());
+    for session in &expired {
+        abort(session);
+    }
+    if !expired.is_empty() {
+        ic_cdk::println!("Pruned {} expired upload sessions", expired.len());
+    }
+}
+
+/// Expires abandoned upload sessions and their chunks every PRUNE_INTERVAL_SECS.
+pub fn start_pruning() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(PRUNE_INTERVAL_SECS), prune_expired);
+}
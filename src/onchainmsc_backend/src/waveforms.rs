@@ -0,0 +1,158 @@
+// Waveform previews for the player, computed from PCM WAV files.
+//
+// When a WAV file is stored, its content id is queued and a timer works through
+// the queue, reading the samples and keeping the min and max of every bucket of
+// frames. Each timer run stops after BATCH_INSTRUCTIONS and schedules the next,
+// so a large file takes several runs instead of one message that would hit the
+// instruction limit. Waveforms are keyed by content id, like the chunks they
+// were computed from, and are dropped along with them.
+//
+// FLAC files would need decoding first and get no waveform for now.
+use crate::{
+    audio::{self, AudioFormat, PcmLayout},
+    uploads, ApiError, TrackFile, Waveform, WaveformPeaks, WaveformStatus, WAVEFORMS, WAVEFORM_QUEUE,
+};
+use std::cell::Cell;
+use std::time::Duration;
+
+/// Peaks kept per file; queries can ask for fewer.
+pub const PEAK_COUNT: u64 = 4096;
+/// Leaves ample room under the per-message instruction limit.
+const BATCH_INSTRUCTIONS: u64 = 4_000_000_000;
+
+thread_local! {
+    // Timers don't survive upgrades, so this is deliberately not in stable memory
+    static BATCH_SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Queues a newly stored file for peak generation if it's a WAV file.
+pub fn queue(file: &TrackFile) {
+    let (Some(content_id), Some(chunk_size)) = (file.content_id, file.chunk_size) else {
+        return;
+    };
+    if file.audio.as_ref().and_then(|audio| audio.format.as_ref()) != Some(&AudioFormat::Wav)
+        || WAVEFORMS.with(|w| w.borrow().contains_key(&content_id))
+    {
+        return;
+    }
+    let size = uploads::file_size(file);
+    let status = match layout(content_id, chunk_size, size) {
+        Some(_) => WaveformStatus::Pending,
+        None => WaveformStatus::Failed { reason: "unsupported WAV encoding".to_string() },
+    };
+    let pending = status == WaveformStatus::Pending;
+    let waveform = Waveform { status, chunk_size, size, frames_per_peak: 0, peaks: vec![] };
+    WAVEFORMS.with(|w| w.borrow_mut().insert(content_id, waveform));
+    if pending {
+        WAVEFORM_QUEUE.with(|q| q.borrow_mut().insert(content_id, ()));
+        schedule();
+    }
+}
+
+pub fn remove(content_id: u64) {
+    WAVEFORMS.with(|w| w.borrow_mut().remove(&content_id));
+    WAVEFORM_QUEUE.with(|q| q.borrow_mut().remove(&content_id));
+}
+
+fn layout(content_id: u64, chunk_size: u32, size: u64) -> Option<PcmLayout> {
+    audio::pcm_layout(&uploads::read_content(content_id, chunk_size, 0, size.min(audio::HEAD_BYTES)))
+}
+
+fn schedule() {
+    if !BATCH_SCHEDULED.with(|s| s.replace(true)) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_batch);
+    }
+}
+
+fn run_batch() {
+    BATCH_SCHEDULED.with(|s| s.set(false));
+    while ic_cdk::api::instruction_counter() < BATCH_INSTRUCTIONS {
+        let Some(content_id) = WAVEFORM_QUEUE.with(|q| q.borrow().first_key_value().map(|(id, _)| id)) else {
+            return;
+        };
+        advance(content_id);
+    }
+    schedule();
+}
+
+/// Computes peaks for `content_id` until it's done or the batch budget runs out.
+fn advance(content_id: u64) {
+    let Some(mut waveform) = WAVEFORMS.with(|w| w.borrow().get(&content_id)) else {
+        WAVEFORM_QUEUE.with(|q| q.borrow_mut().remove(&content_id));
+        return;
+    };
+    let Some(layout) = layout(content_id, waveform.chunk_size, waveform.size) else {
+        waveform.status = WaveformStatus::Failed { reason: "unsupported WAV encoding".to_string() };
+        return finish(content_id, waveform);
+    };
+    let frame_bytes = layout.frame_bytes();
+    let data_len = layout.data_len.min(waveform.size.saturating_sub(layout.data_start));
+    let frames = data_len / frame_bytes;
+    if waveform.frames_per_peak == 0 {
+        waveform.frames_per_peak = frames.div_ceil(PEAK_COUNT).max(1);
+    }
+    loop {
+        let start_frame = waveform.peaks.len() as u64 * waveform.frames_per_peak;
+        if start_frame >= frames {
+            waveform.status = WaveformStatus::Ready;
+            return finish(content_id, waveform);
+        }
+        if ic_cdk::api::instruction_counter() >= BATCH_INSTRUCTIONS {
+            break;
+        }
+        let end_frame = (start_frame + waveform.frames_per_peak).min(frames);
+        let bytes = uploads::read_content(
+            content_id,
+            waveform.chunk_size,
+            layout.data_start + start_frame * frame_bytes,
+            layout.data_start + end_frame * frame_bytes,
+        );
+        let peak = layout.samples(&bytes).fold((i16::MAX, i16::MIN), |(min, max), s| (min.min(s), max.max(s)));
+        waveform.peaks.push(if peak.0 > peak.1 { (0, 0) } else { peak });
+    }
+    WAVEFORMS.with(|w| w.borrow_mut().insert(content_id, waveform));
+}
+
+fn finish(content_id: u64, waveform: Waveform) {
+    WAVEFORMS.with(|w| w.borrow_mut().insert(content_id, waveform));
+    WAVEFORM_QUEUE.with(|q| q.borrow_mut().remove(&content_id));
+}
+
+/// The file's peaks merged down to at most `resolution` buckets. Peaks are only
+/// returned once the whole file has been read.
+pub fn peaks(file: &TrackFile, resolution: u32) -> Result<WaveformPeaks, ApiError> {
+    if resolution == 0 {
+        return Err(ApiError::validation("resolution", "must be at least 1"));
+    }
+    let waveform = file
+        .content_id
+        .and_then(|content_id| WAVEFORMS.with(|w| w.borrow().get(&content_id)))
+        .ok_or_else(|| ApiError::not_found("waveform", file.file_id))?;
+    if waveform.status != WaveformStatus::Ready {
+        return Ok(WaveformPeaks { status: waveform.status, frames_per_peak: 0, peaks: vec![] });
+    }
+    let count = waveform.peaks.len();
+    let buckets = count.min(resolution as usize);
+    let peaks = (0..buckets)
+        .map(|i| {
+            waveform.peaks[i * count / buckets..(i + 1) * count / buckets]
+                .iter()
+                .fold((i16::MAX, i16::MIN), |(min, max), &(lo, hi)| (min.min(lo), max.max(hi)))
+        })
+        .collect();
+    // Buckets merge unevenly when counts don't divide, so this is the average width
+    let frames_per_peak = waveform.frames_per_peak * count as u64 / buckets.max(1) as u64;
+    Ok(WaveformPeaks { status: WaveformStatus::Ready, frames_per_peak, peaks })
+}
+
+/// Queues WAV files stored before waveforms existed, and restarts the batch
+/// timer for anything left queued when the canister was upgraded.
+pub fn resume() {
+    let files: Vec<TrackFile> = crate::TRACK_FILES.with(|files| files.borrow().values().collect());
+    for file in &files {
+        queue(file);
+    }
+    if WAVEFORM_QUEUE.with(|q| !q.borrow().is_empty()) {
+        schedule();
+    }
+}